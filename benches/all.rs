@@ -1,5 +1,6 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
+use hiddb::config::IndexConfig;
 use hiddb::db::dbtypes::*;
 use hiddb::db::RocksDB;
 use hiddb::distance::*;
@@ -34,22 +35,19 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         let db = &RocksDB::init("./build/benchmark.rdb");
         db_options = db.options.clone();
 
-        let index_store = index_store::init(db);
+        let index_store = index_store::init(db).unwrap();
 
         let collection_name = "test_collection".to_owned();
         let field_name = "vector";
 
-        let test_collection = Collection {
-            collection_id: collection_name.clone(),
-            n_documents: 0,
-        };
+        let test_collection = Collection::new(&collection_name);
 
         assert_eq!(
             collection::create(db, &collection_name).unwrap().collection_id,
             test_collection.collection_id
         );
 
-        index::create(db, &index_store, &collection_name, field_name, 200).unwrap();
+        index::create(db, &index_store, &IndexConfig::default(), &collection_name, field_name, 200).unwrap();
 
         let mut rng = rand::thread_rng();
         for _ in 0..500 {}
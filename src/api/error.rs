@@ -0,0 +1,319 @@
+use crate::api::types::{ErrorResponse, RequestId};
+use crate::auth;
+use crate::hiddb::{collection, document, index, job, text_index};
+use crate::workers;
+
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Stable identifier of an error that clients can branch on. Codes are only added, never changed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidBody,
+    InvalidName,
+    InvalidInput,
+    InvalidSchema,
+    SchemaViolation,
+    DimensionMismatch,
+    InvalidVector,
+    MissingDocumentId,
+    BatchTooLarge,
+    Unauthorized,
+    Forbidden,
+    QuotaExceeded,
+    RouteNotFound,
+    CollectionNotFound,
+    IndexNotFound,
+    TextIndexNotFound,
+    DocumentNotFound,
+    JobNotFound,
+    ApiKeyNotFound,
+    CollectionAlreadyExists,
+    IndexAlreadyExists,
+    TextIndexAlreadyExists,
+    DocumentAlreadyExists,
+    RebuildInProgress,
+    JobAlreadyFinished,
+    NotImplemented,
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Every code maps to one status, whichever endpoint returns it
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidBody
+            | ErrorCode::InvalidName
+            | ErrorCode::InvalidInput
+            | ErrorCode::InvalidSchema
+            | ErrorCode::SchemaViolation
+            | ErrorCode::DimensionMismatch
+            | ErrorCode::InvalidVector
+            | ErrorCode::MissingDocumentId
+            | ErrorCode::BatchTooLarge => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::QuotaExceeded => StatusCode::FORBIDDEN,
+            ErrorCode::RouteNotFound
+            | ErrorCode::CollectionNotFound
+            | ErrorCode::IndexNotFound
+            | ErrorCode::TextIndexNotFound
+            | ErrorCode::DocumentNotFound
+            | ErrorCode::JobNotFound
+            | ErrorCode::ApiKeyNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::CollectionAlreadyExists
+            | ErrorCode::IndexAlreadyExists
+            | ErrorCode::TextIndexAlreadyExists
+            | ErrorCode::DocumentAlreadyExists
+            | ErrorCode::RebuildInProgress
+            | ErrorCode::JobAlreadyFinished => StatusCode::CONFLICT,
+            ErrorCode::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error of a request, turned into an `ErrorResponse` with the status of its code.
+/// The errors of the `hiddb` modules don't know the names of a request, so they are passed along when converting.
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Value,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_owned(),
+            details: Value::Null,
+        }
+    }
+
+    pub fn internal() -> Self {
+        Self::new(ErrorCode::Internal, "internal error")
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+
+    pub fn response(&self, request_id: &RequestId) -> HttpResponse {
+        HttpResponse::build(self.code.status()).json(ErrorResponse {
+            code: self.code,
+            message: self.message.clone(),
+            details: self.details.clone(),
+            request_id: request_id.0.clone(),
+        })
+    }
+
+    pub fn collection_not_found(collection_name: &str) -> Self {
+        Self::new(
+            ErrorCode::CollectionNotFound,
+            &format!("collection '{}' does not exist.", collection_name),
+        )
+        .with_details(json!({ "collection": collection_name }))
+    }
+
+    pub fn collection(error: collection::Error, collection_name: &str) -> Self {
+        match error {
+            collection::Error::DoesNotExist => Self::collection_not_found(collection_name),
+            collection::Error::AlreadyExists => Self::new(
+                ErrorCode::CollectionAlreadyExists,
+                &format!("collection '{}' already exists.", collection_name),
+            )
+            .with_details(json!({ "collection": collection_name })),
+            collection::Error::InvalidSchema(reason) => {
+                Self::new(ErrorCode::InvalidSchema, &format!("invalid schema: {}", reason)).with_details(json!({ "reason": reason }))
+            }
+            collection::Error::QuotaExceeded { limit } => quota_exceeded("collections", limit),
+            collection::Error::InternalError => Self::internal(),
+        }
+    }
+
+    pub fn index(error: index::Error, collection_name: &str, field_name: &str) -> Self {
+        let details = json!({ "collection": collection_name, "field": field_name });
+        match error {
+            index::Error::CollectionDoesNotExist => Self::collection_not_found(collection_name),
+            index::Error::IndexDoesNotExist => Self::new(
+                ErrorCode::IndexNotFound,
+                &format!("no index with field {} in {}", field_name, collection_name),
+            )
+            .with_details(details),
+            index::Error::AlreadyExists => Self::new(
+                ErrorCode::IndexAlreadyExists,
+                &format!("index with field {} already exists in {}.", field_name, collection_name),
+            )
+            .with_details(details),
+            index::Error::RebuildInProgress => Self::new(
+                ErrorCode::RebuildInProgress,
+                &format!("index with field {} in {} is already being rebuilt.", field_name, collection_name),
+            )
+            .with_details(details),
+            index::Error::InvalidInput => Self::new(ErrorCode::InvalidInput, "k must be positive and m between 2 and 100."),
+            index::Error::Cancelled => Self::from(workers::Error::Cancelled),
+            index::Error::InternalError => Self::internal(),
+        }
+    }
+
+    pub fn text_index(error: text_index::Error, collection_name: &str, field_name: &str) -> Self {
+        let details = json!({ "collection": collection_name, "field": field_name });
+        match error {
+            text_index::Error::CollectionDoesNotExist => Self::collection_not_found(collection_name),
+            text_index::Error::IndexDoesNotExist => Self::new(
+                ErrorCode::TextIndexNotFound,
+                &format!("no text index with field {} in {}", field_name, collection_name),
+            )
+            .with_details(details),
+            text_index::Error::AlreadyExists => Self::new(
+                ErrorCode::TextIndexAlreadyExists,
+                &format!("text index with field {} already exists in {}.", field_name, collection_name),
+            )
+            .with_details(details),
+            text_index::Error::InvalidInput => Self::new(ErrorCode::InvalidInput, "invalid input"),
+            text_index::Error::InternalError => Self::internal(),
+        }
+    }
+
+    pub fn document(error: document::Error, collection_name: &str) -> Self {
+        match error {
+            document::Error::CollectionDoesNotExist => Self::collection_not_found(collection_name),
+            document::Error::IndexDoesNotExist { field_name } => {
+                Self::new(ErrorCode::IndexNotFound, &format!("index '{}' does not exist", field_name))
+                    .with_details(json!({ "collection": collection_name, "field": field_name }))
+            }
+            document::Error::DocumentDoesNotExist => Self::new(ErrorCode::DocumentNotFound, "document does not exist"),
+            document::Error::AlreadyExists { document_id, .. } => Self::new(
+                ErrorCode::DocumentAlreadyExists,
+                &format!("document '{}' already exists in collection '{}'", document_id, collection_name),
+            )
+            .with_details(json!({ "collection": collection_name, "document_id": document_id })),
+            document::Error::MissingFieldId => Self::new(ErrorCode::MissingDocumentId, "document should have a field named 'id'"),
+            document::Error::DimensionsNotEqual {
+                field,
+                index_dimension,
+                vector_dimension,
+            } => Self::new(
+                ErrorCode::DimensionMismatch,
+                &format!(
+                    "vector in field '{}' has dimension {} but index has dimension {}",
+                    field, vector_dimension, index_dimension
+                ),
+            )
+            .with_details(json!({ "field": field, "expected": index_dimension, "actual": vector_dimension })),
+            document::Error::InvalidVector { field, reason } => {
+                Self::new(ErrorCode::InvalidVector, &format!("vector in field '{}' {}", field, reason))
+                    .with_details(json!({ "field": field, "reason": reason }))
+            }
+            document::Error::SchemaViolation { document_id, field, reason } => Self::new(
+                ErrorCode::SchemaViolation,
+                &format!("document '{}' violates schema: field '{}' {}", document_id, field, reason),
+            )
+            .with_details(json!({ "document_id": document_id, "field": field, "reason": reason })),
+            document::Error::BatchTooLarge { batch_size, max_batch_size } => Self::new(
+                ErrorCode::BatchTooLarge,
+                &format!("request contains {} queries but at most {} are allowed", batch_size, max_batch_size),
+            )
+            .with_details(json!({ "batch_size": batch_size, "max_batch_size": max_batch_size })),
+            document::Error::QuotaExceeded { limit } => quota_exceeded("documents", limit),
+            document::Error::InvalidInput => Self::new(ErrorCode::InvalidInput, "invalid input"),
+            document::Error::NotImplemented => Self::new(ErrorCode::NotImplemented, "not implemented"),
+            document::Error::Cancelled => Self::from(workers::Error::Cancelled),
+            document::Error::InternalError => Self::internal(),
+        }
+    }
+
+    pub fn job(error: job::Error, job_id: &str) -> Self {
+        match error {
+            job::Error::DoesNotExist => {
+                Self::new(ErrorCode::JobNotFound, &format!("job '{}' does not exist.", job_id)).with_details(json!({ "job_id": job_id }))
+            }
+            job::Error::AlreadyFinished => {
+                Self::new(ErrorCode::JobAlreadyFinished, &format!("job '{}' has already finished.", job_id)).with_details(json!({ "job_id": job_id }))
+            }
+            job::Error::Conflict => Self::new(ErrorCode::RebuildInProgress, "a job for the same data is already queued or running"),
+            job::Error::InternalError => Self::internal(),
+        }
+    }
+}
+
+fn quota_exceeded(resource: &str, limit: usize) -> ApiError {
+    ApiError::new(
+        ErrorCode::QuotaExceeded,
+        &format!("quota exceeded: at most {} {} per tenant", limit, resource),
+    )
+    .with_details(json!({ "resource": resource, "limit": limit }))
+}
+
+impl From<workers::Error> for ApiError {
+    fn from(error: workers::Error) -> Self {
+        match error {
+            workers::Error::Cancelled => Self::new(ErrorCode::Unavailable, "request was cancelled"),
+            workers::Error::Panicked => Self::internal(),
+        }
+    }
+}
+
+impl From<auth::Error> for ApiError {
+    fn from(error: auth::Error) -> Self {
+        match error {
+            auth::Error::MissingKey => Self::new(ErrorCode::Unauthorized, "missing API key"),
+            auth::Error::InvalidKey => Self::new(ErrorCode::Unauthorized, "invalid API key"),
+            auth::Error::Forbidden => Self::new(ErrorCode::Forbidden, "API key does not grant access to this resource"),
+            auth::Error::KeyDoesNotExist => Self::new(ErrorCode::ApiKeyNotFound, "API key does not exist."),
+            auth::Error::InvalidInput => Self::new(ErrorCode::InvalidInput, "a key needs at least one scope with a collection name or '*'"),
+            auth::Error::InternalError => Self::internal(),
+        }
+    }
+}
+
+/// Answers bodies that don't match the request type of a handler, see `web::JsonConfig::error_handler`
+pub fn json_error(error: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let response = ApiError::new(ErrorCode::InvalidBody, &error.to_string()).response(&RequestId::of(req));
+    InternalError::from_response(error, response).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error() {
+        let error = ApiError::document(
+            document::Error::DimensionsNotEqual {
+                field: "vector".to_owned(),
+                index_dimension: 3,
+                vector_dimension: 2,
+            },
+            "movies",
+        );
+        assert_eq!(error.code, ErrorCode::DimensionMismatch);
+        assert_eq!(error.details, json!({"field": "vector", "expected": 3, "actual": 2}));
+
+        let response = error.response(&RequestId("0123456789abcdef".to_owned()));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = match response.body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => serde_json::from_slice::<Value>(bytes).unwrap(),
+            _ => panic!("expected a JSON body"),
+        };
+        assert_eq!(body["code"], "dimension_mismatch");
+        assert_eq!(body["request_id"], "0123456789abcdef");
+
+        assert_eq!(
+            ApiError::document(document::Error::DocumentDoesNotExist, "movies").code.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            ApiError::index(index::Error::AlreadyExists, "movies", "vector").code.status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(ApiError::collection(collection::Error::InternalError, "movies").message, "internal error");
+    }
+}
@@ -1,10 +1,11 @@
+use crate::api::error::{ApiError, ErrorCode};
 use crate::api::types::*;
 use crate::db::dbtypes::*;
 use crate::hnsw::key::*;
 use crate::hnsw::{Document, IndexBuilder};
 
 use actix_web::{web, HttpResponse};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::sync::Mutex;
 
 use crate::hnsw::index::get_index_hash;
@@ -14,7 +15,8 @@ use prometheus::{Encoder, TextEncoder};
 
 use crate::metrics;
 
-use crate::hiddb::{collection, document, index};
+use crate::auth;
+use crate::hiddb::{collection, document, gc, index, job, tenant, text_index};
 
 // pub async fn check_health() -> HttpResponse {
 //     HttpResponse::Ok().finish()
@@ -24,8 +26,13 @@ pub async fn dummy() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
-pub async fn not_implemented() -> HttpResponse {
-    HttpResponse::NotImplemented().finish()
+pub async fn not_implemented(request_id: RequestId) -> HttpResponse {
+    ApiError::new(ErrorCode::NotImplemented, "not implemented").response(&request_id)
+}
+
+/// Answers requests that match no route
+pub async fn not_found(request_id: RequestId) -> HttpResponse {
+    ApiError::new(ErrorCode::RouteNotFound, "no such route").response(&request_id)
 }
 
 pub async fn check_health() -> HttpResponse {
@@ -47,213 +54,461 @@ pub async fn get_metrics(state: web::Data<State>) -> HttpResponse {
     return HttpResponse::Ok().body(String::from_utf8(buffer).unwrap());
 }
 
-pub async fn get_collections(state: web::Data<State>) -> HttpResponse {
-    let collections = match collection::get_all(&state.db) {
+/// Runs `work` on the ingest pool instead of the actix worker
+async fn ingest<F, T>(state: &web::Data<State>, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&State) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared = state.clone();
+    state.workers.ingest(move || work(&shared)).await.map_err(ApiError::from)
+}
+
+/// Runs `work` on the query pool instead of the actix worker
+async fn query<F, T>(state: &web::Data<State>, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce(&State) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared = state.clone();
+    state.workers.query(move || work(&shared)).await.map_err(ApiError::from)
+}
+
+pub async fn get_collections(tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let result = match query(&state, move |state| tenant::get_collections(&state.db, &tenant.0)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    let collections = match result {
         Ok(collections) => collections,
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        _ => return ApiError::internal().response(&request_id),
     };
     let collections: Vec<CollectionResponse> = collections.iter().map(|c| CollectionResponse::from(c)).collect();
     HttpResponse::Ok().json(CollectionsResponse { collections })
 }
 
-pub async fn create_collection(item: web::Json<CollectionRequest>, state: web::Data<State>) -> HttpResponse {
-    match collection::create(&state.db, &item.collection_name) {
-        Ok(collection) => {
-            return HttpResponse::Ok().json(CollectionResponse::from(&collection));
-        }
-        Err(collection::Error::AlreadyExists) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!("collection '{}' already exists.", &item.collection_name)));
-        }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+pub async fn create_collection(item: web::Json<CreateCollectionRequest>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let item = item.into_inner();
+    if !tenant::is_valid_name(&item.collection_name) {
+        return ApiError::new(ErrorCode::InvalidName, &format!("invalid collection name '{}'", &item.collection_name))
+            .with_details(json!({ "collection": &item.collection_name }))
+            .response(&request_id);
+    }
+    let collection_name = tenant.qualify(&item.collection_name);
+    let schema = item.schema.clone();
+    let result = match ingest(&state, move |state| {
+        tenant::check_collection_quota(&state.db, &state.quota, &tenant.0)?;
+        collection::create_with_schema(&state.db, &state.index_store, &state.index_defaults, &collection_name, schema)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(collection) => HttpResponse::Ok().json(CollectionResponse::from(&collection)),
+        Err(error) => ApiError::collection(error, &item.collection_name).response(&request_id),
     }
 }
 
-pub async fn get_collection(path: web::Path<CollectionRequest>, state: web::Data<State>) -> HttpResponse {
-    match collection::get(&state.db, &path.collection_name) {
-        Ok(collection) => {
-            return HttpResponse::Ok().json(CollectionResponse::from(&collection));
-        }
-        Err(collection::Error::DoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+pub async fn get_collection(path: web::Path<CollectionRequest>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let result = match query(&state, move |state| collection::get_statistics(&state.db, &collection_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(statistics) => HttpResponse::Ok().json(CollectionResponse::from_statistics(&statistics)),
+        Err(error) => ApiError::collection(error, &path.collection_name).response(&request_id),
     }
 }
 
-pub async fn delete_collection(path: web::Path<CollectionRequest>, state: web::Data<State>) -> HttpResponse {
-    match collection::delete(&state.db, &path.collection_name, &state.index_store) {
+pub async fn delete_collection(path: web::Path<CollectionRequest>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let deleted_name = collection_name.clone();
+    let result = match ingest(&state, move |state| collection::delete(&state.db, &deleted_name, &state.index_store)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
         Ok(collection) => {
-            return HttpResponse::Ok().json(CollectionResponse::from(&collection));
-        }
-        Err(collection::Error::DoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
+            State::schedule_compaction(&state.clone().into_inner(), move |db| collection::compact(db, &collection_name));
+            HttpResponse::Ok().json(CollectionResponse::from(&collection))
         }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        Err(error) => ApiError::collection(error, &path.collection_name).response(&request_id),
     }
 }
 
-pub async fn create_index(path: web::Path<CollectionRequest>, item: web::Json<CreateIndexRequest>, state: web::Data<State>) -> HttpResponse {
-    match index::create(&state.db, &state.index_store, &path.collection_name, &item.field_name, item.dimension) {
-        Ok(index) => {
-            return HttpResponse::Ok().json(IndexResponse::from_db_type(&index));
-        }
-        Err(index::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
-        Err(index::Error::AlreadyExists) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!("index '{}' already exists.", &path.collection_name)));
+pub async fn create_index(
+    path: web::Path<CollectionRequest>,
+    item: web::Json<CreateIndexRequest>,
+    tenant: Tenant,
+    request_id: RequestId,
+    state: web::Data<State>,
+) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = item.field_name.clone();
+    let dimension = item.dimension;
+    let result = match ingest(&state, move |state| job::create_index(state, &tenant.0, &collection_name, &field_name, dimension)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok((index, job)) => {
+            let mut response = IndexResponse::from_db_type(&index);
+            if let Some(job) = job {
+                job::schedule(&state.clone().into_inner(), &job.job_id);
+                response.backfill_job_id = Some(job.job_id);
+            }
+            HttpResponse::Ok().json(response)
         }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        Err(error) => ApiError::index(error, &path.collection_name, &item.field_name).response(&request_id),
     }
 }
 
-pub async fn get_indices(path: web::Path<CollectionRequest>, state: web::Data<State>) -> HttpResponse {
-    let indices = match index::get_all(&state.db, &path.collection_name) {
+pub async fn get_indices(path: web::Path<CollectionRequest>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let result = match query(&state, move |state| index::get_all(&state.db, &collection_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    let indices = match result {
         Ok(collections) => collections,
-        Err(index::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        Err(error) => return ApiError::index(error, &path.collection_name, "").response(&request_id),
     };
     let indices: Vec<IndexResponse> = indices.iter().map(|i| IndexResponse::from_db_type(i)).collect();
     HttpResponse::Ok().json(IndicesInfo { indices })
 }
 
-pub async fn get_index(path: web::Path<IndexRequestPath>, state: web::Data<State>) -> HttpResponse {
-    match index::get(&state.db, &path.collection_name, &path.field_name) {
-        Ok(index) => {
-            return HttpResponse::Ok().json(IndexResponse::from_db_type(&index));
-        }
-        Err(index::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
-        Err(index::Error::IndexDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!(
-                "no index with field {} in {}",
-                path.field_name, path.collection_name
-            )));
+pub async fn get_index(path: web::Path<IndexRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = path.field_name.clone();
+    let result = match query(&state, move |state| index::get(&state.db, &collection_name, &field_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(index) => HttpResponse::Ok().json(IndexResponse::from_db_type(&index)),
+        Err(error) => ApiError::index(error, &path.collection_name, &path.field_name).response(&request_id),
+    }
+}
+
+pub async fn verify_index(path: web::Path<IndexRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = path.field_name.clone();
+    let result = match query(&state, move |state| index::verify(&state.db, &state.index_store, &collection_name, &field_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(verification) => HttpResponse::Ok().json(IndexVerificationResponse::new(&path.collection_name, &path.field_name, verification)),
+        Err(error) => ApiError::index(error, &path.collection_name, &path.field_name).response(&request_id),
+    }
+}
+
+/// Queues a job that rebuilds the graph of an index while the current one keeps serving. The body is optional.
+pub async fn rebuild_index(path: web::Path<IndexRequestPath>, body: web::Bytes, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let item: RebuildIndexRequest = match body.is_empty() {
+        true => RebuildIndexRequest::default(),
+        false => match serde_json::from_slice(&body) {
+            Ok(item) => item,
+            _ => return ApiError::new(ErrorCode::InvalidBody, "Invalid body").response(&request_id),
+        },
+    };
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = path.field_name.clone();
+    let result = match ingest(&state, move |state| {
+        index::check_rebuild(&state.db, &collection_name, &field_name, item.k, item.m).map(|_| {
+            let kind = JobKind::RebuildIndex {
+                collection_id: collection_name,
+                field_id: field_name,
+                k: item.k,
+                m: item.m,
+            };
+            job::create(&state.db, &state.jobs, &tenant.0, kind)
+        })
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(Ok(job)) => {
+            job::schedule(&state.clone().into_inner(), &job.job_id);
+            HttpResponse::Accepted().json(JobResponse::from_db_type(&job))
         }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        Ok(Err(job::Error::Conflict)) => ApiError::index(index::Error::RebuildInProgress, &path.collection_name, &path.field_name).response(&request_id),
+        Ok(Err(error)) => ApiError::job(error, "").response(&request_id),
+        Err(error) => ApiError::index(error, &path.collection_name, &path.field_name).response(&request_id),
     }
 }
 
-pub async fn delete_index(path: web::Path<IndexRequestPath>, state: web::Data<State>) -> HttpResponse {
-    match index::delete(&state.db, &state.index_store, &path.collection_name, &path.field_name) {
+pub async fn delete_index(path: web::Path<IndexRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = path.field_name.clone();
+    let result = match ingest(&state, move |state| index::delete(&state.db, &state.index_store, &collection_name, &field_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
         Ok(index) => {
-            return HttpResponse::Ok().json(IndexResponse::from_db_type(&index));
-        }
-        Err(index::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
+            let deleted = index.clone();
+            State::schedule_compaction(&state.clone().into_inner(), move |db| index::compact(db, &deleted));
+            HttpResponse::Ok().json(IndexResponse::from_db_type(&index))
         }
-        Err(index::Error::IndexDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!(
-                "no index with field {} in {}",
-                path.field_name, path.collection_name
-            )));
-        }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        Err(error) => ApiError::index(error, &path.collection_name, &path.field_name).response(&request_id),
+    }
+}
+
+pub async fn create_text_index(
+    path: web::Path<CollectionRequest>,
+    item: web::Json<CreateTextIndexRequest>,
+    tenant: Tenant,
+    request_id: RequestId,
+    state: web::Data<State>,
+) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = item.field_name.clone();
+    let result = match ingest(&state, move |state| text_index::create(&state.db, &collection_name, &field_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(text_index) => HttpResponse::Ok().json(TextIndexResponse::from_db_type(&text_index)),
+        Err(error) => ApiError::text_index(error, &path.collection_name, &item.field_name).response(&request_id),
+    }
+}
+
+pub async fn get_text_indices(path: web::Path<CollectionRequest>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let result = match query(&state, move |state| text_index::get_all(&state.db, &collection_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    let text_indices = match result {
+        Ok(text_indices) => text_indices,
+        Err(error) => return ApiError::text_index(error, &path.collection_name, "").response(&request_id),
+    };
+    let text_indices: Vec<TextIndexResponse> = text_indices.iter().map(TextIndexResponse::from_db_type).collect();
+    HttpResponse::Ok().json(TextIndicesInfo { text_indices })
+}
+
+pub async fn get_text_index(path: web::Path<IndexRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = path.field_name.clone();
+    let result = match query(&state, move |state| text_index::get(&state.db, &collection_name, &field_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(text_index) => HttpResponse::Ok().json(TextIndexResponse::from_db_type(&text_index)),
+        Err(error) => ApiError::text_index(error, &path.collection_name, &path.field_name).response(&request_id),
+    }
+}
+
+pub async fn delete_text_index(path: web::Path<IndexRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let field_name = path.field_name.clone();
+    let result = match ingest(&state, move |state| text_index::delete(&state.db, &collection_name, &field_name)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(text_index) => HttpResponse::Ok().json(TextIndexResponse::from_db_type(&text_index)),
+        Err(error) => ApiError::text_index(error, &path.collection_name, &path.field_name).response(&request_id),
     }
 }
 
-pub async fn insert_documents(path: web::Path<CollectionRequest>, body: web::Bytes, state: web::Data<State>) -> HttpResponse {
+pub async fn insert_documents(path: web::Path<CollectionRequest>, body: web::Bytes, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
     let item: Value = match serde_json::from_slice(&body) {
         Ok(body) => body,
-        _ => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new("Invalid body"));
-        }
+        _ => return ApiError::new(ErrorCode::InvalidBody, "Invalid body").response(&request_id),
     };
 
     let items = match item.get("documents") {
         Some(items) => items,
-        _ => return HttpResponse::BadRequest().json(ErrorResponse::new("expected field \"documents\".")),
+        _ => return ApiError::new(ErrorCode::InvalidBody, "expected field \"documents\".").response(&request_id),
     };
 
     let documents = match items.as_array() {
         Some(items) => items,
-        _ => return HttpResponse::BadRequest().json(ErrorResponse::new("field \"documents\" should be an array of objects.")),
+        _ => return ApiError::new(ErrorCode::InvalidBody, "field \"documents\" should be an array of objects.").response(&request_id),
     };
 
-    match document::insert(&state.db, &state.index_store, &path.collection_name, &documents) {
-        Ok(_) => {
-            return HttpResponse::Ok().finish();
-        }
-        Err(document::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
-        Err(document::Error::MissingFieldId) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new("document should have a field named 'id'"));
-        }
-        Err(document::Error::DimensionsNotEqual {
-            field,
-            index_dimension,
-            vector_dimension,
-        }) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!(
-                "vector in field '{}' has dimension {} but index has dimension {}",
-                &field, vector_dimension, index_dimension,
-            )));
-        }
-        Err(document::Error::InvalidInput) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new("invalid input"));
-        }
-        Err(document::Error::NotImplemented) => {
-            return HttpResponse::NotImplemented().json(ErrorResponse::new(""));
-        }
-        Err(document::Error::AlreadyExists {
-            collection_name,
-            document_id,
-        }) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!(
-                "document '{}' already exists in collection '{}'",
-                document_id, collection_name,
-            )));
-        }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+    let collection_name = tenant.qualify(&path.collection_name);
+    let documents = documents.clone();
+    let result = match ingest(&state, move |state| {
+        tenant::check_document_quota(&state.db, &state.quota, &tenant.0, documents.len())?;
+        document::insert(&state.db, &state.index_store, &collection_name, &documents)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(error) => ApiError::document(error, &path.collection_name).response(&request_id),
     }
 }
 
-pub async fn search_documents(path: web::Path<CollectionRequest>, body: web::Bytes, state: web::Data<State>) -> HttpResponse {
+pub async fn search_documents(path: web::Path<CollectionRequest>, body: web::Bytes, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
     let item: Value = match serde_json::from_slice(&body) {
         Ok(body) => body,
-        _ => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new("Invalid body"));
-        }
+        _ => return ApiError::new(ErrorCode::InvalidBody, "Invalid body").response(&request_id),
     };
 
-    match document::search_ann(&state.db, &state.index_store, &path.collection_name, &item) {
-        Ok(data) => HttpResponse::Ok().json(SearchResponse { data }),
-        Err(document::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
-        Err(document::Error::DimensionsNotEqual {
-            field,
-            index_dimension,
-            vector_dimension,
-        }) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!(
-                "vector in field '{}' has dimension {} but index has dimension {}",
-                &field, vector_dimension, index_dimension,
-            )));
-        }
-        Err(document::Error::InvalidInput) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new("invalid input"));
-        }
-        Err(document::Error::IndexDoesNotExist { field_name }) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!("index '{}' does not exist", &field_name)));
-        }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+    let collection_name = tenant.qualify(&path.collection_name);
+    let result = match query(&state, move |state| match item.get("group_by") {
+        Some(_) => document::search_grouped(&state.db, &state.index_store, &collection_name, &item).map(SearchResponse::from_groups),
+        _ => document::search_ann(&state.db, &state.index_store, &collection_name, &item).map(SearchResponse::new),
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(error) => ApiError::document(error, &path.collection_name).response(&request_id),
+    }
+}
+
+pub async fn recommend_documents(path: web::Path<CollectionRequest>, body: web::Bytes, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let item: Value = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        _ => return ApiError::new(ErrorCode::InvalidBody, "Invalid body").response(&request_id),
+    };
+
+    let collection_name = tenant.qualify(&path.collection_name);
+    let result = match query(&state, move |state| document::recommend(&state.db, &state.index_store, &collection_name, &item)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(data) => HttpResponse::Ok().json(SearchResponse::new(vec![data])),
+        Err(document::Error::DocumentDoesNotExist) => ApiError::new(ErrorCode::DocumentNotFound, "example document does not exist").response(&request_id),
+        Err(error) => ApiError::document(error, &path.collection_name).response(&request_id),
     }
 }
 
-pub async fn get_document_by_id(path: web::Path<DocumentRequestPath>, state: web::Data<State>) -> HttpResponse {
-    match document::get_by_id(&state.db, &path.collection_name, &path.document_id) {
+pub async fn get_document_by_id(path: web::Path<DocumentRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let collection_name = tenant.qualify(&path.collection_name);
+    let document_id = path.document_id.clone();
+    let result = match query(&state, move |state| document::get_by_id(&state.db, &collection_name, &document_id)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
         Ok(document) => HttpResponse::Ok().json(document.data),
-        Err(document::Error::CollectionDoesNotExist) => {
-            return HttpResponse::NotFound().json(ErrorResponse::new(&format!("collection '{}' does not exist.", &path.collection_name)));
-        }
         Err(document::Error::DocumentDoesNotExist) => {
-            return HttpResponse::BadRequest().json(ErrorResponse::new(&format!("document '{}' does not exist.", &path.document_id)));
+            ApiError::new(ErrorCode::DocumentNotFound, &format!("document '{}' does not exist.", &path.document_id))
+                .with_details(json!({ "collection": &path.collection_name, "document_id": &path.document_id }))
+                .response(&request_id)
         }
-        _ => return HttpResponse::InternalServerError().json(ErrorResponse::new("")),
+        Err(error) => ApiError::document(error, &path.collection_name).response(&request_id),
+    }
+}
+
+pub async fn create_api_key(item: web::Json<CreateApiKeyRequest>, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let item = item.into_inner();
+    let result = match ingest(&state, move |state| auth::create(&state.db, &item.name, item.scopes)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok((api_key, token)) => {
+            let mut response = ApiKeyResponse::from_db_type(&api_key);
+            response.key = Some(token);
+            HttpResponse::Ok().json(response)
+        }
+        Err(error) => ApiError::from(error).response(&request_id),
+    }
+}
+
+pub async fn get_api_keys(request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let result = match query(&state, move |state| auth::get_all(&state.db)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    let api_keys = match result {
+        Ok(api_keys) => api_keys,
+        Err(error) => return ApiError::from(error).response(&request_id),
+    };
+    let keys: Vec<ApiKeyResponse> = api_keys.iter().map(ApiKeyResponse::from_db_type).collect();
+    HttpResponse::Ok().json(ApiKeysResponse { keys })
+}
+
+/// Deletes keys of collections and indices that no longer exist, see `gc::collect`
+pub async fn collect_garbage(body: web::Bytes, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let item: CollectGarbageRequest = match body.is_empty() {
+        true => CollectGarbageRequest::default(),
+        false => match serde_json::from_slice(&body) {
+            Ok(item) => item,
+            _ => return ApiError::new(ErrorCode::InvalidBody, "Invalid body").response(&request_id),
+        },
+    };
+    let dry_run = item.dry_run;
+    let result = match ingest(&state, move |state| gc::collect(&state.db, &state.index_store, dry_run)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(report) => HttpResponse::Ok().json(CollectGarbageResponse::from_report(&report, dry_run)),
+        Err(gc::Error::CorruptedMetadata) => ApiError::new(ErrorCode::Internal, "metadata is corrupted, no keys were deleted").response(&request_id),
+        Err(gc::Error::InternalError) => ApiError::internal().response(&request_id),
+    }
+}
+
+pub async fn revoke_api_key(path: web::Path<ApiKeyRequestPath>, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let key_id = path.key_id.clone();
+    let result = match ingest(&state, move |state| auth::revoke(&state.db, &key_id)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(api_key) => HttpResponse::Ok().json(ApiKeyResponse::from_db_type(&api_key)),
+        Err(auth::Error::KeyDoesNotExist) => ApiError::new(ErrorCode::ApiKeyNotFound, &format!("API key '{}' does not exist.", &path.key_id))
+            .with_details(json!({ "key_id": &path.key_id }))
+            .response(&request_id),
+        Err(error) => ApiError::from(error).response(&request_id),
+    }
+}
+
+pub async fn get_jobs(tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let result = match query(&state, move |state| job::get_all(&state.db, &tenant.0)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(jobs) => HttpResponse::Ok().json(JobsResponse {
+            jobs: jobs.iter().map(JobResponse::from_db_type).collect(),
+        }),
+        Err(error) => ApiError::job(error, "").response(&request_id),
+    }
+}
+
+pub async fn get_job(path: web::Path<JobRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let job_id = path.job_id.clone();
+    let result = match query(&state, move |state| job::get(&state.db, &tenant.0, &job_id)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(job) => HttpResponse::Ok().json(JobResponse::from_db_type(&job)),
+        Err(error) => ApiError::job(error, &path.job_id).response(&request_id),
+    }
+}
+
+pub async fn cancel_job(path: web::Path<JobRequestPath>, tenant: Tenant, request_id: RequestId, state: web::Data<State>) -> HttpResponse {
+    let job_id = path.job_id.clone();
+    let result = match ingest(&state, move |state| job::cancel(&state.db, &state.jobs, &tenant.0, &job_id)).await {
+        Ok(result) => result,
+        Err(error) => return error.response(&request_id),
+    };
+    match result {
+        Ok(job) => HttpResponse::Ok().json(JobResponse::from_db_type(&job)),
+        Err(error) => ApiError::job(error, &path.job_id).response(&request_id),
     }
 }
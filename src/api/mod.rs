@@ -1,2 +1,4 @@
+pub mod error;
 pub mod handlers;
+pub mod middleware;
 pub mod types;
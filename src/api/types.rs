@@ -1,17 +1,143 @@
+use crate::hnsw::verify::{Verification, Violation};
 use crate::hnsw::Index;
 
 use serde::{Deserialize, Serialize};
 
-use crate::db::dbtypes::{Collection, IndexDB};
-use crate::db::RocksDB;
+use crate::api::error::{ApiError, ErrorCode};
+
+use crate::auth::Authenticator;
+use crate::config::{Config, IndexConfig};
+use crate::db::dbtypes::{ApiKeyDB, Collection, IndexDB, JobDB, JobKind, JobStatus, Schema, Scope, TextIndexDB};
+use crate::db::{self, RocksDB};
+use crate::index_store;
+use crate::utils;
+use crate::workers::{Cancellation, Workers};
+use crate::hiddb::collection::CollectionStatistics;
+use crate::hiddb::document::Group;
+use crate::hiddb::gc;
+use crate::hiddb::job::Jobs;
+use crate::hiddb::tenant::{self, Quota};
+use actix_web::{dev, error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{err, ok, Ready};
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 pub type IndexStore = RwLock<HashMap<[u8; 16], Mutex<Index>>>;
 
 pub struct State {
     pub index_store: IndexStore,
     pub db: RocksDB,
+    pub workers: Workers,
+    pub auth: Authenticator,
+    pub quota: Quota,
+    pub jobs: Jobs,
+    pub compact_after_delete: bool,
+    /// Parameters of new indices that don't set their own
+    pub index_defaults: IndexConfig,
+}
+
+impl State {
+    pub fn new(db: RocksDB) -> Result<Self, db::Error> {
+        Self::from_config(db, &Config::default())
+    }
+
+    pub fn from_config(db: RocksDB, config: &Config) -> Result<Self, db::Error> {
+        Ok(Self {
+            index_store: index_store::init(&db)?,
+            db,
+            workers: Workers::with_defaults(config.server.ingest_threads, config.server.query_threads),
+            auth: Authenticator::from_env(),
+            quota: Quota::from_config(&config.tenant),
+            jobs: Jobs::new(),
+            compact_after_delete: config.storage.compact_after_delete,
+            index_defaults: config.index.clone(),
+        })
+    }
+
+    /// Runs `compact` on the background pool if compaction after deletes is enabled
+    pub fn schedule_compaction<F>(state: &Arc<State>, compact: F)
+    where
+        F: FnOnce(&RocksDB) + Send + 'static,
+    {
+        if !state.compact_after_delete {
+            return;
+        }
+        let shared = state.clone();
+        state.workers.spawn(Cancellation::new(), move || compact(&shared.db));
+    }
+}
+
+/// Tenant of a request, taken from the `/ns/{tenant}` prefix of its route.
+/// Also rejects requests whose `{collection_name}` can't be qualified with the tenant.
+pub struct Tenant(pub String);
+
+impl Tenant {
+    pub fn qualify(&self, collection_name: &str) -> String {
+        tenant::qualify(&self.0, collection_name)
+    }
+}
+
+impl FromRequest for Tenant {
+    type Error = error::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let name = req.match_info().get("tenant").unwrap_or(tenant::DEFAULT_TENANT);
+        if !tenant::is_valid_name(name) {
+            let response = ApiError::new(ErrorCode::InvalidName, &format!("invalid tenant '{}'", name))
+                .with_details(json!({ "tenant": name }))
+                .response(&RequestId::of(req));
+            return err(error::InternalError::from_response("", response).into());
+        }
+        // Collection names with the separator would reach collections of other tenants once qualified
+        if let Some(collection_name) = req.match_info().get("collection_name") {
+            if !tenant::is_valid_name(collection_name) {
+                let response = ApiError::new(ErrorCode::InvalidName, &format!("invalid collection name '{}'", collection_name))
+                    .with_details(json!({ "collection": collection_name }))
+                    .response(&RequestId::of(req));
+                return err(error::InternalError::from_response("", response).into());
+            }
+        }
+        ok(Tenant(name.to_owned()))
+    }
+}
+
+/// Id of a request, returned in the `x-request-id` header and in error responses.
+/// Taken from the `x-request-id` header of the request if it is a valid id, random otherwise.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub const HEADER: &'static str = "x-request-id";
+
+    /// Id of `req`, assigned on first use
+    pub fn of<R: HttpMessage>(req: &R) -> Self {
+        if let Some(request_id) = req.extensions().get::<RequestId>() {
+            return request_id.clone();
+        }
+        let id = req
+            .headers()
+            .get(Self::HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .map(|id| id.to_owned())
+            .unwrap_or_else(|| utils::to_hex(&rand::random::<[u8; 8]>()));
+        let request_id = RequestId(id);
+        req.extensions_mut().insert(request_id.clone());
+        request_id
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = error::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ok(RequestId::of(req))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,6 +146,13 @@ pub struct CreateIndexRequest {
     pub dimension: usize,
 }
 
+/// Parameters of the rebuilt graph, the current ones are kept if unset
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RebuildIndexRequest {
+    pub k: Option<usize>,
+    pub m: Option<f64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IndexRequestPath {
     pub field_name: String,
@@ -47,18 +180,86 @@ pub struct IndexResponse {
     pub dimension: usize,
     // pub buffer_size: usize,
     // pub k: usize,
+    /// Job adding the documents stored before the index was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backfill_job_id: Option<String>,
 }
 
 impl IndexResponse {
     pub fn from_db_type(index_db: &IndexDB) -> Self {
         Self {
-            collection_name: index_db.collection_id.clone(),
+            collection_name: tenant::split(&index_db.collection_id).1.to_owned(),
             field_name: index_db.field_id.clone(),
             n_documents: index_db.n_elements,
-            distance_metric: "euclidean".to_owned(),
+            distance_metric: match index_db.metric.as_str() {
+                "" => "euclidean".to_owned(),
+                metric => metric.to_owned(),
+            },
             dimension: index_db.dimension,
             // buffer_size: index_db.buffer_size,
             // k: index_db.k,
+            backfill_job_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexVerificationResponse {
+    pub collection_name: String,
+    pub field_name: String,
+
+    pub healthy: bool,
+    pub n_nodes: u64,
+    pub n_reachable: u64,
+    pub n_violations: usize,
+    /// At most `hnsw::verify::MAX_VIOLATIONS`
+    pub violations: Vec<Violation>,
+}
+
+impl IndexVerificationResponse {
+    pub fn new(collection_name: &str, field_name: &str, verification: Verification) -> Self {
+        Self {
+            collection_name: collection_name.to_owned(),
+            field_name: field_name.to_owned(),
+            healthy: verification.is_healthy(),
+            n_nodes: verification.n_nodes,
+            n_reachable: verification.n_reachable,
+            n_violations: verification.n_violations,
+            violations: verification.violations,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateTextIndexRequest {
+    pub field_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextIndicesInfo {
+    pub text_indices: Vec<TextIndexResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextIndexResponse {
+    pub collection_name: String,
+    pub field_name: String,
+
+    pub n_documents: u64,
+    pub average_length: f64,
+    pub created_at: u64,
+    pub last_write: u64,
+}
+
+impl TextIndexResponse {
+    pub fn from_db_type(text_index: &TextIndexDB) -> Self {
+        Self {
+            collection_name: tenant::split(&text_index.collection_id).1.to_owned(),
+            field_name: text_index.field_id.clone(),
+            n_documents: text_index.n_documents,
+            average_length: text_index.average_length(),
+            created_at: text_index.created_at,
+            last_write: text_index.updated_at,
         }
     }
 }
@@ -68,10 +269,39 @@ pub struct CollectionRequest {
     pub collection_name: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateCollectionRequest {
+    pub collection_name: String,
+    #[serde(default)]
+    pub schema: Option<Schema>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CollectionResponse {
     pub collection_name: String,
     pub n_documents: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Schema>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n_indices: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approximate_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indices: Option<Vec<IndexStatisticsResponse>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexStatisticsResponse {
+    pub field_name: String,
+    pub n_documents: u64,
+    pub approximate_bytes: u64,
+    pub created_at: u64,
+    pub last_write: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -82,13 +312,69 @@ pub struct CollectionsResponse {
 #[derive(Serialize, Deserialize)]
 pub struct SearchResponse {
     pub data: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<Vec<GroupResponse>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GroupResponse {
+    pub key: Value,
+    pub ids: Vec<String>,
+}
+
+impl SearchResponse {
+    pub fn new(data: Vec<Vec<String>>) -> Self {
+        Self { data, groups: None }
+    }
+
+    /// `data` holds the hits of all groups in group order
+    pub fn from_groups(groups: Vec<Vec<Group>>) -> Self {
+        Self {
+            data: groups
+                .iter()
+                .map(|groups| groups.iter().flat_map(|group| group.ids.clone()).collect())
+                .collect(),
+            groups: Some(
+                groups
+                    .into_iter()
+                    .map(|groups| groups.into_iter().map(|group| GroupResponse { key: group.key, ids: group.ids }).collect())
+                    .collect(),
+            ),
+        }
+    }
 }
 
 impl CollectionResponse {
     pub fn from(collection: &Collection) -> Self {
         Self {
-            collection_name: collection.collection_id.clone(),
+            collection_name: tenant::split(&collection.collection_id).1.to_owned(),
             n_documents: collection.n_documents,
+            schema: collection.schema.clone(),
+            created_at: collection.created_at,
+            updated_at: collection.updated_at,
+            n_indices: None,
+            approximate_bytes: None,
+            indices: None,
+        }
+    }
+
+    pub fn from_statistics(statistics: &CollectionStatistics) -> Self {
+        let indices: Vec<IndexStatisticsResponse> = statistics
+            .indices
+            .iter()
+            .map(|i| IndexStatisticsResponse {
+                field_name: i.index.field_id.clone(),
+                n_documents: i.index.n_elements,
+                approximate_bytes: i.approximate_bytes,
+                created_at: i.index.created_at,
+                last_write: i.index.updated_at,
+            })
+            .collect();
+        Self {
+            n_indices: Some(indices.len()),
+            approximate_bytes: Some(statistics.approximate_bytes),
+            indices: Some(indices),
+            ..Self::from(&statistics.collection)
         }
     }
 }
@@ -133,13 +419,125 @@ pub struct QueryByVec {
     pub max_neighbors: usize,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ErrorResponse {
-    message: String,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyRequestPath {
+    pub key_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeysResponse {
+    pub keys: Vec<ApiKeyResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyResponse {
+    pub key_id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+    // only returned when the key is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
 }
 
-impl ErrorResponse {
-    pub fn new(message: &str) -> Self {
-        Self { message: message.to_owned() }
+impl ApiKeyResponse {
+    pub fn from_db_type(api_key: &ApiKeyDB) -> Self {
+        Self {
+            key_id: api_key.key_id.clone(),
+            name: api_key.name.clone(),
+            scopes: api_key.scopes.clone(),
+            created_at: api_key.created_at,
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CollectGarbageRequest {
+    /// Only report what would be deleted
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CollectGarbageResponse {
+    pub checked_keys: u64,
+    pub orphaned_keys: u64,
+    pub reclaimed_bytes: u64,
+    pub dry_run: bool,
+}
+
+impl CollectGarbageResponse {
+    pub fn from_report(report: &gc::Report, dry_run: bool) -> Self {
+        Self {
+            checked_keys: report.checked_keys,
+            orphaned_keys: report.orphaned_keys,
+            reclaimed_bytes: report.reclaimed_bytes,
+            dry_run,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobRequestPath {
+    pub job_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobsResponse {
+    pub jobs: Vec<JobResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobResponse {
+    pub job_id: String,
+    pub kind: String,
+    pub collection_name: String,
+    pub field_name: String,
+    pub status: JobStatus,
+    pub progress: u64,
+    pub total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl JobResponse {
+    pub fn from_db_type(job: &JobDB) -> Self {
+        let (kind, collection_id, field_id) = match &job.kind {
+            JobKind::RebuildIndex {
+                collection_id, field_id, ..
+            } => ("rebuild_index", collection_id, field_id),
+            JobKind::BackfillIndex { collection_id, field_id } => ("backfill_index", collection_id, field_id),
+        };
+        Self {
+            job_id: job.job_id.clone(),
+            kind: kind.to_owned(),
+            collection_name: tenant::split(collection_id).1.to_owned(),
+            field_name: field_id.clone(),
+            status: job.status,
+            progress: job.progress,
+            total: job.total,
+            error: job.error.clone(),
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+        }
     }
 }
+
+/// Body of every error response, see `ApiError`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub details: Value,
+    pub request_id: String,
+}
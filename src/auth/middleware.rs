@@ -0,0 +1,189 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::{header, Method};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, web, Error};
+use futures::future::{ok, Ready};
+use percent_encoding::percent_decode_str;
+
+use crate::api::error::ApiError;
+use crate::api::types::{RequestId, State};
+use crate::auth::{bearer_token, ALL_COLLECTIONS, ALL_TENANTS};
+use crate::db::dbtypes::Permission;
+use crate::hiddb::tenant::DEFAULT_TENANT;
+
+/// Rejects requests without an API key granting the permission the route needs
+pub struct Auth;
+
+impl Auth {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl<S, B> Transform<S> for Auth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct AuthMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for AuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let state = req.app_data::<web::Data<State>>().filter(|state| state.auth.is_enabled()).cloned();
+        let (state, (tenant, collection_name, permission)) = match (state, required_permission(req.method(), req.path())) {
+            (Some(state), Some(required)) => (state, required),
+            _ => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(bearer_token)
+            .map(|token| token.to_owned());
+        let request_id = RequestId::of(&req);
+
+        Box::pin(async move {
+            let result = state
+                .workers
+                .query({
+                    let state = state.clone();
+                    move || state.auth.authorize(&state.db, token.as_deref(), &tenant, &collection_name, permission)
+                })
+                .await;
+            match result {
+                Ok(Ok(())) => {
+                    let fut = service.borrow_mut().call(req);
+                    fut.await
+                }
+                Ok(Err(error)) => Err(InternalError::from_response("", ApiError::from(error).response(&request_id)).into()),
+                Err(error) => Err(InternalError::from_response("", ApiError::from(error).response(&request_id)).into()),
+            }
+        })
+    }
+}
+
+/// Tenant, collection and permission a request needs, or `None` for public routes
+fn required_permission(method: &Method, path: &str) -> Option<(String, String, Permission)> {
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|segment| segment.as_str()).collect();
+    let read_only = method == Method::GET || method == Method::HEAD;
+
+    let (tenant, segments) = match segments.as_slice() {
+        ["health"] | ["metrics"] => return None,
+        ["admin", ..] => return Some((ALL_TENANTS.to_owned(), ALL_COLLECTIONS.to_owned(), Permission::Admin)),
+        ["ns", tenant, rest @ ..] => (tenant.to_string(), rest),
+        rest => (DEFAULT_TENANT.to_owned(), rest),
+    };
+    let (collection_name, permission) = match segments {
+        ["collection"] if read_only => (ALL_COLLECTIONS.to_owned(), Permission::Read),
+        ["collection"] => (ALL_COLLECTIONS.to_owned(), Permission::Admin),
+        ["collection", collection_name, rest @ ..] => {
+            let permission = match rest {
+                _ if read_only => Permission::Read,
+                ["document", "search"] | ["document", "recommend"] => Permission::Read,
+                ["document", ..] => Permission::Write,
+                _ => Permission::Admin,
+            };
+            (collection_name.to_string(), permission)
+        }
+        // Jobs can touch any collection of the tenant
+        ["jobs", ..] if read_only => (ALL_COLLECTIONS.to_owned(), Permission::Read),
+        ["jobs", ..] => (ALL_COLLECTIONS.to_owned(), Permission::Admin),
+        _ => (ALL_COLLECTIONS.to_owned(), Permission::Read),
+    };
+    Some((tenant, collection_name, permission))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_permission() {
+        let required = |tenant: &str, collection_name: &str, permission| Some((tenant.to_owned(), collection_name.to_owned(), permission));
+        assert_eq!(required_permission(&Method::GET, "/health"), None);
+        assert_eq!(required_permission(&Method::POST, "/admin/keys"), required("*", "*", Permission::Admin));
+        assert_eq!(
+            required_permission(&Method::GET, "/collection"),
+            required("default", "*", Permission::Read)
+        );
+        assert_eq!(
+            required_permission(&Method::POST, "/collection"),
+            required("default", "*", Permission::Admin)
+        );
+        assert_eq!(
+            required_permission(&Method::DELETE, "/collection/movies"),
+            required("default", "movies", Permission::Admin)
+        );
+        assert_eq!(
+            required_permission(&Method::POST, "/collection/movies/document/search"),
+            required("default", "movies", Permission::Read)
+        );
+        assert_eq!(
+            required_permission(&Method::POST, "/collection/movies/document"),
+            required("default", "movies", Permission::Write)
+        );
+        assert_eq!(
+            required_permission(&Method::POST, "/collection/movies/index"),
+            required("default", "movies", Permission::Admin)
+        );
+        assert_eq!(
+            required_permission(&Method::GET, "/collection/m%6Fvies/index"),
+            required("default", "movies", Permission::Read)
+        );
+        assert_eq!(
+            required_permission(&Method::POST, "/ns/team_a/collection/movies/document"),
+            required("team_a", "movies", Permission::Write)
+        );
+        assert_eq!(
+            required_permission(&Method::POST, "/ns/team_a/collection"),
+            required("team_a", "*", Permission::Admin)
+        );
+        assert_eq!(
+            required_permission(&Method::DELETE, "/ns/team_a/jobs/0123456789abcdef"),
+            required("team_a", "*", Permission::Admin)
+        );
+    }
+}
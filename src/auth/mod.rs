@@ -0,0 +1,221 @@
+pub mod middleware;
+
+use crate::db::dbtypes::*;
+use crate::db::RocksDB;
+use crate::hiddb::tenant;
+use crate::utils;
+
+use rand::Rng;
+use seahash::hash;
+use sha2::{Digest, Sha256};
+
+use std::env;
+
+/// Collection name of scopes that apply to every collection of their tenant
+pub const ALL_COLLECTIONS: &str = "*";
+/// Tenant of scopes that apply to every tenant
+pub const ALL_TENANTS: &str = "*";
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    MissingKey,
+    InvalidKey,
+    Forbidden,
+    KeyDoesNotExist,
+    InvalidInput,
+    InternalError,
+}
+
+/// Validates bearer tokens against the API keys in RocksDB.
+/// Authentication is disabled unless an admin key is configured, which is also the only way to create the first keys.
+pub struct Authenticator {
+    admin_secret_hash: Option<Vec<u8>>,
+}
+
+impl Authenticator {
+    pub fn new(admin_key: Option<&str>) -> Self {
+        Self {
+            admin_secret_hash: admin_key.map(sha256),
+        }
+    }
+
+    /// Admin key from `HIDDB_ADMIN_KEY`
+    pub fn from_env() -> Self {
+        let admin_key = env::var("HIDDB_ADMIN_KEY").ok().filter(|admin_key| !admin_key.is_empty());
+        Self::new(admin_key.as_deref())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.admin_secret_hash.is_some()
+    }
+
+    /// Checks that `token` grants at least `permission` on `collection_name` of `tenant`.
+    /// Use `ALL_COLLECTIONS` for requests which are not about a single collection and `ALL_TENANTS` for server-wide requests.
+    pub fn authorize(&self, db: &RocksDB, token: Option<&str>, tenant: &str, collection_name: &str, permission: Permission) -> Result<(), Error> {
+        let admin_secret_hash = match &self.admin_secret_hash {
+            Some(admin_secret_hash) => admin_secret_hash,
+            None => return Ok(()),
+        };
+        let token = token.ok_or(Error::MissingKey)?;
+        if constant_time_eq(&sha256(token), admin_secret_hash) {
+            return Ok(());
+        }
+
+        let (key_id, secret) = split_token(token).ok_or(Error::InvalidKey)?;
+        let api_key = db
+            .get_api_key(&hash(key_id.as_bytes()).to_be_bytes())
+            .or(Err(Error::InternalError))?
+            .ok_or(Error::InvalidKey)?;
+        if api_key.key_id != key_id || !constant_time_eq(&sha256(secret), &api_key.secret_hash) {
+            return Err(Error::InvalidKey);
+        }
+
+        let granted = api_key.scopes.iter().any(|scope| {
+            (scope.tenant == tenant || scope.tenant == ALL_TENANTS)
+                && (scope.collection == collection_name || scope.collection == ALL_COLLECTIONS)
+                && scope.permission >= permission
+        });
+        if !granted {
+            return Err(Error::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+/// Token from an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+/// Creates a key and returns it together with its token.
+/// The token contains the secret and can't be recovered later.
+pub fn create(db: &RocksDB, name: &str, scopes: Vec<Scope>) -> Result<(ApiKeyDB, String), Error> {
+    let is_valid = |name: &str| name == "*" || tenant::is_valid_name(name);
+    if scopes.is_empty() || scopes.iter().any(|scope| !is_valid(&scope.tenant) || !is_valid(&scope.collection)) {
+        return Err(Error::InvalidInput);
+    }
+
+    let mut rng = rand::thread_rng();
+    let key_id = utils::to_hex(&rng.gen::<[u8; 8]>());
+    let secret = utils::to_hex(&rng.gen::<[u8; 32]>());
+
+    let api_key = ApiKeyDB {
+        key_id: key_id.clone(),
+        name: name.to_owned(),
+        secret_hash: sha256(&secret),
+        scopes,
+        created_at: utils::timestamp(),
+    };
+    db.insert_api_key(&hash(key_id.as_bytes()).to_be_bytes(), &api_key)
+        .or(Err(Error::InternalError))?;
+
+    Ok((api_key, format!("{}.{}", key_id, secret)))
+}
+
+pub fn get_all(db: &RocksDB) -> Result<Vec<ApiKeyDB>, Error> {
+    db.get_api_keys().or(Err(Error::InternalError))
+}
+
+/// Deletes a key. Requests using it are rejected immediately.
+pub fn revoke(db: &RocksDB, key_id: &str) -> Result<ApiKeyDB, Error> {
+    let key_hash = hash(key_id.as_bytes()).to_be_bytes();
+    let api_key = match db.get_api_key(&key_hash).or(Err(Error::InternalError))? {
+        Some(api_key) if api_key.key_id == key_id => api_key,
+        _ => return Err(Error::KeyDoesNotExist),
+    };
+    db.delete_api_key(&key_hash).or(Err(Error::InternalError))?;
+    Ok(api_key)
+}
+
+fn split_token(token: &str) -> Option<(&str, &str)> {
+    let mut parts = token.splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(key_id), Some(secret)) if !key_id.is_empty() && !secret.is_empty() => Some((key_id, secret)),
+        _ => None,
+    }
+}
+
+fn sha256(value: &str) -> Vec<u8> {
+    Sha256::digest(value.as_bytes()).to_vec()
+}
+
+/// Compares hashes without leaking the position of the first difference through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let db_options;
+        {
+            let db = RocksDB::init("./build/auth_authorize.rdb");
+            db_options = db.options.clone();
+
+            let disabled = Authenticator::new(None);
+            assert_eq!(disabled.authorize(&db, None, "default", "movies", Permission::Admin), Ok(()));
+
+            let auth = Authenticator::new(Some("admin-secret"));
+            assert_eq!(auth.authorize(&db, None, "default", "movies", Permission::Read), Err(Error::MissingKey));
+            assert_eq!(
+                auth.authorize(&db, Some("admin-secret"), ALL_TENANTS, ALL_COLLECTIONS, Permission::Admin),
+                Ok(())
+            );
+            assert_eq!(
+                auth.authorize(&db, Some("no-key"), "default", "movies", Permission::Read),
+                Err(Error::InvalidKey)
+            );
+
+            let scopes = vec![Scope {
+                tenant: "team_a".to_owned(),
+                collection: "movies".to_owned(),
+                permission: Permission::Write,
+            }];
+            let (api_key, token) = create(&db, "ingest", scopes).unwrap();
+            assert_eq!(auth.authorize(&db, Some(&token), "team_a", "movies", Permission::Read), Ok(()));
+            assert_eq!(auth.authorize(&db, Some(&token), "team_a", "movies", Permission::Write), Ok(()));
+            assert_eq!(
+                auth.authorize(&db, Some(&token), "team_a", "movies", Permission::Admin),
+                Err(Error::Forbidden)
+            );
+            assert_eq!(
+                auth.authorize(&db, Some(&token), "team_a", "books", Permission::Read),
+                Err(Error::Forbidden)
+            );
+            assert_eq!(
+                auth.authorize(&db, Some(&token), "team_b", "movies", Permission::Read),
+                Err(Error::Forbidden)
+            );
+
+            let forged = format!("{}.{}", api_key.key_id, "0".repeat(64));
+            assert_eq!(
+                auth.authorize(&db, Some(&forged), "team_a", "movies", Permission::Read),
+                Err(Error::InvalidKey)
+            );
+
+            assert_eq!(get_all(&db).unwrap().len(), 1);
+            revoke(&db, &api_key.key_id).unwrap();
+            assert_eq!(
+                auth.authorize(&db, Some(&token), "team_a", "movies", Permission::Read),
+                Err(Error::InvalidKey)
+            );
+            assert_eq!(revoke(&db, &api_key.key_id), Err(Error::KeyDoesNotExist));
+        }
+        RocksDB::destroy(&db_options, "./build/auth_authorize.rdb");
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc.def"), Some("abc.def"));
+        assert_eq!(bearer_token("bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("Basic abc"), None);
+        assert_eq!(bearer_token("abc"), None);
+    }
+}
@@ -0,0 +1,308 @@
+//! Offline tooling for a hiddb data directory.
+//! RocksDB allows a single process per directory, so the server has to be stopped first.
+
+use hiddb::config::StorageConfig;
+use hiddb::db::dbtypes::*;
+use hiddb::db::{column_family, RocksDB, COLUMN_FAMILIES};
+use hiddb::hiddb::{collection, gc, index, recovery, text_index};
+use hiddb::hnsw::key::*;
+use hiddb::index_store;
+use hiddb::sorted_list::SortedList;
+use hiddb::utils;
+
+use clap::{Parser, Subcommand};
+use rocksdb::IteratorMode;
+use seahash::hash;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[derive(Parser)]
+#[clap(
+    name = "hiddb-admin",
+    version,
+    about = "Inspects and maintains a hiddb data directory while the server is stopped"
+)]
+struct Args {
+    /// Directory of the RocksDB database, defaults to HIDDB_PATH
+    #[clap(long)]
+    data_path: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List collections and their indices
+    List,
+    /// Print the documents of a collection as JSON lines
+    Dump {
+        /// Collection name, prefixed with "<tenant>/" outside of the default tenant
+        collection: String,
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Decode a hex-encoded key
+    DecodeKey { key: String },
+    /// Print the number of nodes and their degrees per layer of an index
+    GraphStats { collection: String, field: String },
+    /// Check document counts and index metadata against the stored data
+    Verify {
+        /// Write the recomputed metadata
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Delete keys of collections and indices that no longer exist
+    Gc {
+        /// Only report what would be deleted
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Compact all column families
+    Compact,
+    /// Write all entries to a file as JSON lines
+    Export { file: PathBuf },
+    /// Load a file written by export into an empty data directory
+    Import { file: PathBuf },
+}
+
+/// Line of an export file, keys and values are hex-encoded
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    cf: String,
+    key: String,
+    value: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    let path = args
+        .data_path
+        .or_else(|| env::var("HIDDB_PATH").ok())
+        .unwrap_or_else(|| StorageConfig::default().path);
+    if let Err(error) = run(&path, args.command) {
+        eprintln!("error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str, command: Command) -> Result<(), String> {
+    if !matches!(command, Command::Import { .. }) && !Path::new(path).exists() {
+        return Err(format!("data directory '{}' does not exist", path));
+    }
+    let db =
+        RocksDB::try_open(path, &StorageConfig::default()).map_err(|e| format!("could not open '{}', is the server still running? {}", path, e))?;
+
+    match command {
+        Command::List => list(&db),
+        Command::Dump { collection, limit } => dump(&db, &collection, limit),
+        Command::DecodeKey { key } => decode_key(&db, &key),
+        Command::GraphStats { collection, field } => graph_stats(&db, &collection, &field),
+        Command::Verify { repair } => verify(&db, repair),
+        Command::Gc { dry_run } => collect_garbage(&db, dry_run),
+        Command::Compact => db.compact().map_err(|e| e.to_string()),
+        Command::Export { file } => export(&db, &file),
+        Command::Import { file } => import(&db, &file),
+    }
+}
+
+fn list(db: &RocksDB) -> Result<(), String> {
+    for collection in collection::get_all(db).map_err(|e| format!("{:?}", e))? {
+        println!("{}\t{} documents", collection.collection_id, collection.n_documents);
+        for index in index::get_all(db, &collection.collection_id).map_err(|e| format!("{:?}", e))? {
+            println!(
+                "  index {}\t{} dimensions, {}, {} elements in {} layers",
+                index.field_id, index.dimension, index.metric, index.n_elements, index.n_layers
+            );
+        }
+        for text_index in text_index::get_all(db, &collection.collection_id).map_err(|e| format!("{:?}", e))? {
+            println!("  text_index {}\t{} documents", text_index.field_id, text_index.n_documents);
+        }
+    }
+    Ok(())
+}
+
+fn dump(db: &RocksDB, collection_name: &str, limit: Option<usize>) -> Result<(), String> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    if db.get_collection(&collection_hash).map_err(|e| e.to_string())?.is_none() {
+        return Err(format!("collection '{}' does not exist", collection_name));
+    }
+    // Corrupted documents are skipped and logged
+    let documents = db.get_documents(&collection_hash).map_err(|e| e.to_string())?;
+    for document in documents.into_iter().take(limit.unwrap_or(usize::MAX)) {
+        println!("{}", document.data);
+    }
+    Ok(())
+}
+
+/// Prints the parts of a key, with names where the hashes can be resolved
+fn decode_key(db: &RocksDB, hex: &str) -> Result<(), String> {
+    let bytes = utils::from_hex(hex).filter(|bytes| bytes.len() == 26).ok_or("keys are 26 bytes in hex")?;
+    let key = Key::from_vec(bytes);
+
+    let collection = collection::get_all(db)
+        .map_err(|e| format!("{:?}", e))?
+        .into_iter()
+        .find(|collection| hash(collection.collection_id.as_bytes()).to_be_bytes() == key.get_collection_id());
+    let mut fields: Vec<String> = Vec::new();
+    if let Some(collection) = &collection {
+        let indices = index::get_all(db, &collection.collection_id).map_err(|e| format!("{:?}", e))?;
+        let text_indices = text_index::get_all(db, &collection.collection_id).map_err(|e| format!("{:?}", e))?;
+        fields.extend(indices.into_iter().map(|index| index.field_id));
+        fields.extend(text_indices.into_iter().map(|text_index| text_index.field_id));
+    }
+    let field = fields
+        .into_iter()
+        .find(|field| hash(field.as_bytes()).to_be_bytes() == key.get_field_id());
+    let document = match (&collection, key.get_type()) {
+        (Some(_), DOCUMENT) | (Some(_), VALUE) | (Some(_), NEIGHBORS) | (Some(_), REVERSE_NEIGHBORS) | (Some(_), FIELD_LENGTH) => db
+            .get_document(&key.get_collection_id(), &key.get_document_id())
+            .map_err(|e| e.to_string())?
+            .map(|document| document.id_user),
+        _ => None,
+    };
+
+    let name = |name: Option<String>| name.map(|name| format!(" ({})", name)).unwrap_or_default();
+    println!("type\t{}{}", key.get_type() as char, name(type_name(key.get_type()).map(str::to_owned)));
+    println!(
+        "collection\t{}{}",
+        utils::to_hex(&key.get_collection_id()),
+        name(collection.map(|c| c.collection_id))
+    );
+    println!("field\t{}{}", utils::to_hex(&key.get_field_id()), name(field));
+    println!("layer\t{}", key.get_layer());
+    println!("document\t{}{}", utils::to_hex(&key.get_document_id()), name(document));
+    Ok(())
+}
+
+fn graph_stats(db: &RocksDB, collection_name: &str, field_name: &str) -> Result<(), String> {
+    let index = index::get(db, collection_name, field_name).map_err(|e| format!("{:?}", e))?;
+    println!(
+        "{} elements in {} layers, k = {}, entry point {}",
+        index.n_elements,
+        index.n_layers,
+        index.k,
+        index.entry_point.map(|id| utils::to_hex(&id)).unwrap_or_else(|| "none".to_owned())
+    );
+
+    let prefix = Prefix::new()
+        .prefix_type(NEIGHBORS)
+        .collection(&hash(collection_name.as_bytes()).to_be_bytes())
+        .field(&index.graph_hash)
+        .finish();
+    // Layer to number of nodes, sum, minimum and maximum of their degrees
+    let mut layers: BTreeMap<u8, (usize, usize, usize, usize)> = BTreeMap::new();
+    for (key, value) in db.get_by_prefix_key_value("neighbors", &prefix).map_err(|e| e.to_string())? {
+        let degree = SortedList::<f64, [u8; 8]>::from_binary(&value)
+            .map_err(|_| format!("corrupted neighbors at key {}", utils::to_hex(&key.to_vec())))?
+            .len();
+        let layer = layers.entry(key.get_layer()).or_insert((0, 0, usize::MAX, 0));
+        layer.0 += 1;
+        layer.1 += degree;
+        layer.2 = layer.2.min(degree);
+        layer.3 = layer.3.max(degree);
+    }
+
+    println!("layer\tnodes\tavg degree\tmin degree\tmax degree");
+    for (layer, (nodes, degrees, min_degree, max_degree)) in layers.iter().rev() {
+        println!(
+            "{}\t{}\t{:.2}\t{}\t{}",
+            layer,
+            nodes,
+            *degrees as f64 / *nodes as f64,
+            min_degree,
+            max_degree
+        );
+    }
+    Ok(())
+}
+
+fn verify(db: &RocksDB, repair: bool) -> Result<(), String> {
+    let index_store = index_store::init(db).map_err(|e| e.to_string())?;
+    let report = recovery::check(db, &index_store, repair).map_err(|e| format!("{:?}", e))?;
+    println!(
+        "Checked {} collections and {} indices",
+        report.checked_collections, report.checked_indices
+    );
+    for collection_name in &report.stale_collections {
+        println!("collection {}: document count is stale", collection_name);
+    }
+    for (collection_name, field_name) in &report.stale_indices {
+        println!("index {}/{}: metadata is stale", collection_name, field_name);
+    }
+    match (report.is_consistent(), repair) {
+        (false, false) => Err("inconsistencies found, run with --repair to fix them".to_owned()),
+        (false, true) => {
+            println!("Repaired");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn collect_garbage(db: &RocksDB, dry_run: bool) -> Result<(), String> {
+    let index_store = index_store::init(db).map_err(|e| e.to_string())?;
+    let report = gc::collect(db, &index_store, dry_run).map_err(|e| format!("{:?}", e))?;
+    println!("Checked {} keys", report.checked_keys);
+    match dry_run {
+        true => println!("{} orphaned keys, {} bytes", report.orphaned_keys, report.reclaimed_bytes),
+        false => println!("Deleted {} orphaned keys, reclaimed {} bytes", report.orphaned_keys, report.reclaimed_bytes),
+    }
+    Ok(())
+}
+
+fn export(db: &RocksDB, file: &Path) -> Result<(), String> {
+    let mut writer = BufWriter::new(File::create(file).map_err(|e| e.to_string())?);
+    let mut n_entries = 0;
+    for name in COLUMN_FAMILIES.iter() {
+        let cf = db.db.cf_handle(name).unwrap();
+        for (key, value) in db.db.iterator_cf(&cf, IteratorMode::Start) {
+            // The run state belongs to the data directory, not to the data
+            if key.first() == Some(&RUN_STATE) {
+                continue;
+            }
+            let entry = Entry {
+                cf: name.to_string(),
+                key: utils::to_hex(&key),
+                value: utils::to_hex(&value),
+            };
+            writeln!(writer, "{}", serde_json::to_string(&entry).unwrap()).map_err(|e| e.to_string())?;
+            n_entries += 1;
+        }
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+    println!("Exported {} entries", n_entries);
+    Ok(())
+}
+
+fn import(db: &RocksDB, file: &Path) -> Result<(), String> {
+    if !collection::get_all(db).map_err(|e| format!("{:?}", e))?.is_empty() {
+        return Err("the data directory already contains collections".to_owned());
+    }
+    let reader = BufReader::new(File::open(file).map_err(|e| e.to_string())?);
+    let mut n_entries = 0;
+    for (line_idx, line) in reader.lines().enumerate() {
+        let invalid = |reason: &str| format!("line {}: {}", line_idx + 1, reason);
+        let line = line.map_err(|e| e.to_string())?;
+        let entry: Entry = serde_json::from_str(&line).map_err(|e| invalid(&e.to_string()))?;
+        if !COLUMN_FAMILIES.contains(&entry.cf.as_str()) {
+            return Err(invalid("unknown column family"));
+        }
+        let key = utils::from_hex(&entry.key)
+            .filter(|key| key.len() == 26)
+            .ok_or_else(|| invalid("invalid key"))?;
+        let value = utils::from_hex(&entry.value).ok_or_else(|| invalid("invalid value"))?;
+        // Exports of earlier versions have all but the neighbors in "default"
+        let key = Key::from_vec(key);
+        db.put(column_family(key.get_type()), &key, &value).map_err(|e| e.to_string())?;
+        n_entries += 1;
+    }
+    db.flush().map_err(|e| e.to_string())?;
+    println!("Imported {} entries", n_entries);
+    Ok(())
+}
@@ -0,0 +1,428 @@
+use crate::distance;
+
+use clap::Parser;
+use rocksdb::DBCompressionType;
+use serde::{Deserialize, Serialize};
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Command-line flags. They take precedence over environment variables, which take precedence over the config file.
+#[derive(Debug, Default, Parser)]
+#[clap(name = "hiddb", version, about = "Vector database with HNSW indices")]
+pub struct Args {
+    /// TOML config file, also read from HIDDB_CONFIG
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address of the REST API
+    #[clap(long)]
+    pub listen: Option<String>,
+    /// Address of the gRPC API
+    #[clap(long)]
+    pub grpc_listen: Option<String>,
+    /// Directory of the RocksDB database
+    #[clap(long)]
+    pub data_path: Option<String>,
+    /// Number of HTTP workers
+    #[clap(long)]
+    pub workers: Option<usize>,
+    /// Log filter in RUST_LOG syntax
+    #[clap(long)]
+    pub log_level: Option<String>,
+    /// Log format, either "text" or "json"
+    #[clap(long)]
+    pub log_format: Option<String>,
+    /// Print the effective configuration and exit
+    #[clap(long)]
+    pub print_config: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read { path: PathBuf, reason: String },
+    Parse(String),
+    InvalidValue { key: &'static str, value: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Read { path, reason } => write!(f, "could not read config file '{}': {}", path.display(), reason),
+            Error::Parse(reason) => write!(f, "invalid config file: {}", reason),
+            Error::InvalidValue { key, value } => write!(f, "invalid value '{}' for {}", value, key),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+    pub index: IndexConfig,
+    pub tenant: TenantConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub grpc_listen: String,
+    /// HTTP workers, defaults to the number of cores
+    pub workers: Option<usize>,
+    /// Threads for inserts, defaults to half of the cores
+    pub ingest_threads: Option<usize>,
+    /// Threads for searches, defaults to the number of cores
+    pub query_threads: Option<usize>,
+    /// Maximum size of JSON bodies in bytes
+    pub json_limit: usize,
+    /// Maximum size of raw bodies (documents, search requests) in bytes
+    pub payload_limit: usize,
+    /// Allowed CORS origins, `*` allows all
+    pub cors_origins: Vec<String>,
+    /// Seconds to wait for in-flight requests and inserts on shutdown
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".to_owned(),
+            grpc_listen: "127.0.0.1:50051".to_owned(),
+            workers: None,
+            ingest_threads: None,
+            query_threads: None,
+            json_limit: 131072,
+            payload_limit: 262144,
+            cors_origins: vec!["*".to_owned()],
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub path: String,
+    /// Size of the LRU block cache in MiB, RocksDB's default if unset
+    pub block_cache_mb: Option<usize>,
+    /// Size of a memtable in MiB, RocksDB's default if unset
+    pub write_buffer_mb: Option<usize>,
+    /// One of none, snappy, zlib, bz2, lz4, lz4hc or zstd. Vectors are always stored uncompressed.
+    pub compression: String,
+    pub max_background_jobs: Option<i32>,
+    /// Compacts the key ranges of deleted collections and indices in the background to reclaim disk space right away
+    pub compact_after_delete: bool,
+    /// Deletes keys of collections and indices that no longer exist on start, which scans the whole database
+    pub gc_on_start: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: "HIDDBrocksdb".to_owned(),
+            block_cache_mb: None,
+            write_buffer_mb: None,
+            compression: "snappy".to_owned(),
+            max_background_jobs: None,
+            compact_after_delete: false,
+            gc_on_start: false,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn compression_type(&self) -> Option<DBCompressionType> {
+        match self.compression.as_str() {
+            "none" => Some(DBCompressionType::None),
+            "snappy" => Some(DBCompressionType::Snappy),
+            "zlib" => Some(DBCompressionType::Zlib),
+            "bz2" => Some(DBCompressionType::Bz2),
+            "lz4" => Some(DBCompressionType::Lz4),
+            "lz4hc" => Some(DBCompressionType::Lz4hc),
+            "zstd" => Some(DBCompressionType::Zstd),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in RUST_LOG syntax. RUST_LOG takes precedence over the config file.
+    pub level: String,
+    pub format: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "actix_web=info".to_owned(),
+            format: "text".to_owned(),
+        }
+    }
+}
+
+/// Parameters of indices that are created without setting them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+    pub k: usize,
+    pub m: f64,
+    pub distance_metric: String,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            k: 16,
+            m: 2.0,
+            distance_metric: distance::EUCLIDEAN.to_owned(),
+        }
+    }
+}
+
+/// Limits applied to every tenant, unlimited if unset
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub max_collections: Option<usize>,
+    pub max_documents: Option<usize>,
+}
+
+/// Value of the variable `key` returned by `var`, parsed as `T`
+fn parse<T, F>(var: &F, key: &'static str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    F: Fn(&str) -> Option<String>,
+{
+    match var(key) {
+        Some(value) => value.parse().map(Some).map_err(|_| Error::InvalidValue { key, value }),
+        None => Ok(None),
+    }
+}
+
+/// Builds the configuration from defaults, the config file, environment variables and `args`, in increasing precedence
+pub fn load(args: &Args) -> Result<Config, Error> {
+    let path = args.config.clone().or_else(|| env::var("HIDDB_CONFIG").ok().map(PathBuf::from));
+    let mut config = match path {
+        Some(path) => {
+            let content = fs::read_to_string(&path).map_err(|e| Error::Read {
+                path: path.clone(),
+                reason: e.to_string(),
+            })?;
+            Config::from_toml(&content)?
+        }
+        None => Config::default(),
+    };
+    config.apply_env(|key| env::var(key).ok())?;
+    config.apply_args(args);
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        toml::from_str(content).map_err(|e| Error::Parse(e.to_string()))
+    }
+
+    /// Overrides settings with the `HIDDB_*` variables and `RUST_LOG` returned by `var`.
+    /// Every setting has a variable, e.g. `HIDDB_BLOCK_CACHE_MB` for `storage.block_cache_mb` or `HIDDB_INDEX_K` for `index.k`.
+    /// `HIDDB_CORS_ORIGINS` is a comma-separated list.
+    pub fn apply_env<F>(&mut self, var: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(listen) = var("HIDDB_LISTEN") {
+            self.server.listen = listen;
+        }
+        if let Some(grpc_listen) = var("HIDDB_GRPC_LISTEN") {
+            self.server.grpc_listen = grpc_listen;
+        }
+        if let Some(path) = var("HIDDB_PATH") {
+            self.storage.path = path;
+        }
+        if let Some(level) = var("RUST_LOG") {
+            self.log.level = level;
+        }
+        if let Some(format) = var("HIDDB_LOG_FORMAT") {
+            self.log.format = format;
+        }
+        if let Some(workers) = parse(&var, "HIDDB_WORKERS")? {
+            self.server.workers = Some(workers);
+        }
+        if let Some(threads) = parse(&var, "HIDDB_INGEST_THREADS")? {
+            self.server.ingest_threads = Some(threads);
+        }
+        if let Some(threads) = parse(&var, "HIDDB_QUERY_THREADS")? {
+            self.server.query_threads = Some(threads);
+        }
+        if let Some(limit) = parse(&var, "HIDDB_JSON_LIMIT")? {
+            self.server.json_limit = limit;
+        }
+        if let Some(limit) = parse(&var, "HIDDB_PAYLOAD_LIMIT")? {
+            self.server.payload_limit = limit;
+        }
+        if let Some(origins) = var("HIDDB_CORS_ORIGINS") {
+            self.server.cors_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_owned())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        if let Some(timeout) = parse(&var, "HIDDB_SHUTDOWN_TIMEOUT")? {
+            self.server.shutdown_timeout = timeout;
+        }
+        if let Some(size) = parse(&var, "HIDDB_BLOCK_CACHE_MB")? {
+            self.storage.block_cache_mb = Some(size);
+        }
+        if let Some(size) = parse(&var, "HIDDB_WRITE_BUFFER_MB")? {
+            self.storage.write_buffer_mb = Some(size);
+        }
+        if let Some(compression) = var("HIDDB_COMPRESSION") {
+            self.storage.compression = compression;
+        }
+        if let Some(jobs) = parse(&var, "HIDDB_MAX_BACKGROUND_JOBS")? {
+            self.storage.max_background_jobs = Some(jobs);
+        }
+        if let Some(compact) = parse(&var, "HIDDB_COMPACT_AFTER_DELETE")? {
+            self.storage.compact_after_delete = compact;
+        }
+        if let Some(gc) = parse(&var, "HIDDB_GC_ON_START")? {
+            self.storage.gc_on_start = gc;
+        }
+        if let Some(k) = parse(&var, "HIDDB_INDEX_K")? {
+            self.index.k = k;
+        }
+        if let Some(m) = parse(&var, "HIDDB_INDEX_M")? {
+            self.index.m = m;
+        }
+        if let Some(metric) = var("HIDDB_INDEX_DISTANCE_METRIC") {
+            self.index.distance_metric = metric;
+        }
+        if let Some(limit) = parse(&var, "HIDDB_TENANT_MAX_COLLECTIONS")? {
+            self.tenant.max_collections = Some(limit);
+        }
+        if let Some(limit) = parse(&var, "HIDDB_TENANT_MAX_DOCUMENTS")? {
+            self.tenant.max_documents = Some(limit);
+        }
+        Ok(())
+    }
+
+    pub fn apply_args(&mut self, args: &Args) {
+        if let Some(listen) = &args.listen {
+            self.server.listen = listen.clone();
+        }
+        if let Some(grpc_listen) = &args.grpc_listen {
+            self.server.grpc_listen = grpc_listen.clone();
+        }
+        if let Some(path) = &args.data_path {
+            self.storage.path = path.clone();
+        }
+        if let Some(workers) = args.workers {
+            self.server.workers = Some(workers);
+        }
+        if let Some(level) = &args.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(format) = &args.log_format {
+            self.log.format = format.clone();
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |key: &'static str, value: &dyn fmt::Display| Error::InvalidValue {
+            key,
+            value: value.to_string(),
+        };
+        if self.server.workers == Some(0) {
+            return Err(invalid("server.workers", &0));
+        }
+        if self.server.ingest_threads == Some(0) {
+            return Err(invalid("server.ingest_threads", &0));
+        }
+        if self.server.query_threads == Some(0) {
+            return Err(invalid("server.query_threads", &0));
+        }
+        if self.storage.compression_type().is_none() {
+            return Err(invalid("storage.compression", &self.storage.compression));
+        }
+        if self.log.format != "text" && self.log.format != "json" {
+            return Err(invalid("log.format", &self.log.format));
+        }
+        if self.index.k == 0 {
+            return Err(invalid("index.k", &self.index.k));
+        }
+        if !(2.0..=100.0).contains(&self.index.m) {
+            return Err(invalid("index.m", &self.index.m));
+        }
+        if !distance::is_supported(&self.index.distance_metric) {
+            return Err(invalid("index.distance_metric", &self.index.distance_metric));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            listen = "0.0.0.0:8080"
+            cors_origins = ["https://example.com"]
+
+            [storage]
+            compression = "zstd"
+            block_cache_mb = 512
+
+            [index]
+            k = 32
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.listen, "0.0.0.0:8080");
+        assert_eq!(config.server.grpc_listen, "127.0.0.1:50051");
+        assert_eq!(config.storage.block_cache_mb, Some(512));
+        assert_eq!(config.index.k, 32);
+        assert_eq!(config.index.m, 2.0);
+
+        let env = |key: &str| match key {
+            "HIDDB_LISTEN" => Some("0.0.0.0:9090".to_owned()),
+            "RUST_LOG" => Some("debug".to_owned()),
+            "HIDDB_QUERY_THREADS" => Some("4".to_owned()),
+            "HIDDB_CORS_ORIGINS" => Some("https://a.example.com, https://b.example.com".to_owned()),
+            "HIDDB_WRITE_BUFFER_MB" => Some("64".to_owned()),
+            "HIDDB_GC_ON_START" => Some("true".to_owned()),
+            "HIDDB_INDEX_M" => Some("4.5".to_owned()),
+            _ => None,
+        };
+        config.apply_env(env).unwrap();
+        assert_eq!(config.server.listen, "0.0.0.0:9090");
+        assert_eq!(config.log.level, "debug");
+        assert_eq!(config.server.query_threads, Some(4));
+        assert_eq!(config.server.cors_origins, vec!["https://a.example.com", "https://b.example.com"]);
+        assert_eq!(config.storage.write_buffer_mb, Some(64));
+        assert!(config.storage.gc_on_start);
+        assert_eq!((config.index.k, config.index.m), (32, 4.5));
+
+        config.apply_args(&Args {
+            listen: Some("127.0.0.1:7070".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(config.server.listen, "127.0.0.1:7070");
+        assert!(config.validate().is_ok());
+
+        config.storage.compression = "gzip".to_owned();
+        assert!(config.validate().is_err());
+        assert!(Config::from_toml("[server]\nport = 8080").is_err());
+        assert!(Config::default().apply_env(|_| Some("many".to_owned())).is_err());
+    }
+}
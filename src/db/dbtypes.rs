@@ -1,26 +1,90 @@
 use bincode::{deserialize, serialize};
+use seahash::hash;
 use serde::{Deserialize, Serialize};
 
 use crate::sorted_list::SortedList;
 
+use crate::hiddb::tenant;
 use crate::hnsw;
+use crate::utils;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Collection {
     pub collection_id: String,
     pub n_documents: usize,
+    pub schema: Option<Schema>,
+    pub created_at: u64, // unix timestamp in milliseconds
+    pub updated_at: u64,
 }
 
 impl Collection {
     pub fn new(collection_id: &str) -> Self {
+        let now = utils::timestamp();
         Self {
             collection_id: collection_id.to_owned(),
             n_documents: 0,
+            schema: None,
+            created_at: now,
+            updated_at: now,
         }
     }
+
+    pub fn touch(&mut self) {
+        self.updated_at = utils::timestamp();
+    }
+
+    pub fn with_schema(mut self, schema: Schema) -> Self {
+        self.schema = Some(schema);
+        self
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// Fields of a collection declared at creation time.
+/// Documents may carry additional fields which are not validated.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Schema {
+    #[serde(default)]
+    pub vectors: Vec<VectorField>,
+    #[serde(default)]
+    pub fields: Vec<PayloadField>,
+}
+
+/// Vector field with an HNSW index created together with the collection
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VectorField {
+    pub name: String,
+    pub dimension: usize,
+    #[serde(default = "default_metric")]
+    pub metric: String,
+    pub k: Option<usize>, // number of nearest neighbors to save
+    pub m: Option<f64>,
+}
+
+fn default_metric() -> String {
+    "euclidean".to_owned()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PayloadField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Array,
+    Object,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct IndexDB {
     pub field_id: String,      // here vector
     pub collection_id: String, // here vector
@@ -32,6 +96,9 @@ pub struct IndexDB {
     pub reverse_size: f64,
     pub n_layers: u8, // number of layers
     pub n_elements: u64,
+    pub created_at: u64,
+    pub updated_at: u64, // time of last write
+    pub graph_hash: [u8; 8], // replaces the field hash in NEIGHBORS keys
 }
 
 impl IndexDB {
@@ -48,6 +115,9 @@ impl IndexDB {
         index.reverse_size = self.reverse_size;
         index.n_layers = self.n_layers;
         index.n_elements = self.n_elements;
+        index.created_at = self.created_at;
+        index.updated_at = self.updated_at;
+        index.graph_hash = self.graph_hash;
 
         index
     }
@@ -63,13 +133,188 @@ impl IndexDB {
             reverse_size: index.reverse_size,
             n_layers: index.n_layers,
             n_elements: index.n_elements,
+            created_at: index.created_at,
+            updated_at: index.updated_at,
+            graph_hash: index.graph_hash,
+        }
+    }
+}
+
+/// Layout of `Collection` written by versions without schemas and timestamps
+#[derive(Serialize, Deserialize)]
+pub struct LegacyCollection {
+    pub collection_id: String,
+    pub n_documents: usize,
+}
+
+impl From<LegacyCollection> for Collection {
+    /// Timestamps of legacy collections are unknown and set to 0
+    fn from(legacy: LegacyCollection) -> Self {
+        Self {
+            collection_id: legacy.collection_id,
+            n_documents: legacy.n_documents,
+            schema: None,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+}
+
+/// Layout of `IndexDB` written by versions without timestamps and rebuilds
+#[derive(Serialize, Deserialize)]
+pub struct LegacyIndexDB {
+    pub field_id: String,
+    pub collection_id: String,
+    pub metric: String,
+    pub buffer_size: usize,
+    pub dimension: usize,
+    pub k: usize,
+    pub entry_point: Option<[u8; 8]>,
+    pub reverse_size: f64,
+    pub n_layers: u8,
+    pub n_elements: u64,
+}
+
+impl From<LegacyIndexDB> for IndexDB {
+    /// Graphs of legacy indices are stored under the field hash, as before rebuilds existed
+    fn from(legacy: LegacyIndexDB) -> Self {
+        let graph_hash = hash(legacy.field_id.as_bytes()).to_be_bytes();
+        Self {
+            field_id: legacy.field_id,
+            collection_id: legacy.collection_id,
+            metric: legacy.metric,
+            buffer_size: legacy.buffer_size,
+            dimension: legacy.dimension,
+            k: legacy.k,
+            entry_point: legacy.entry_point,
+            reverse_size: legacy.reverse_size,
+            n_layers: legacy.n_layers,
+            n_elements: legacy.n_elements,
+            created_at: 0,
+            updated_at: 0,
+            graph_hash,
+        }
+    }
+}
+
+/// Full-text index on a string field scored with BM25
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TextIndexDB {
+    pub field_id: String,
+    pub collection_id: String,
+    pub k1: f64,
+    pub b: f64,
+    pub n_documents: u64,
+    pub total_length: u64, // number of tokens in all indexed documents
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl TextIndexDB {
+    pub fn new(collection_id: &str, field_id: &str) -> Self {
+        let now = utils::timestamp();
+        Self {
+            field_id: field_id.to_owned(),
+            collection_id: collection_id.to_owned(),
+            k1: 1.2,
+            b: 0.75,
+            n_documents: 0,
+            total_length: 0,
+            created_at: now,
+            updated_at: now,
         }
     }
+
+    pub fn average_length(&self) -> f64 {
+        if self.n_documents == 0 {
+            return 0.0;
+        }
+        self.total_length as f64 / self.n_documents as f64
+    }
+}
+
+/// Documents containing a term together with the term frequency
+pub type Postings = Vec<([u8; 8], u32)>;
+
+/// Access levels of an API key. Each level includes the ones before it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+/// Permission on a single collection of a tenant, or on all of them if `collection` is `*`.
+/// Scopes with tenant `*` apply to every tenant and are required for the admin endpoints.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Scope {
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub collection: String,
+    pub permission: Permission,
+}
+
+fn default_tenant() -> String {
+    tenant::DEFAULT_TENANT.to_owned()
+}
+
+/// API key without its secret. Only the SHA-256 hash of the secret is stored.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ApiKeyDB {
+    pub key_id: String,
+    pub name: String,
+    pub secret_hash: Vec<u8>,
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+}
+
+/// Work of a background job. Collection names are qualified with the tenant.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum JobKind {
+    RebuildIndex {
+        collection_id: String,
+        field_id: String,
+        k: Option<usize>,
+        m: Option<f64>,
+    },
+    /// Adds the documents stored before the index was created
+    BackfillIndex { collection_id: String, field_id: String },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled)
+    }
 }
 
-pub trait BinaryConverison {
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct JobDB {
+    pub job_id: String,
+    pub tenant: String,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub progress: u64, // units of work done out of total
+    pub total: u64,
+    pub error: Option<String>,
+    pub cancel_requested: bool,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+pub trait BinaryConverison: Sized {
     fn to_binary(&self) -> Vec<u8>;
-    fn from_binary(data: &Vec<u8>) -> Self;
+    fn from_binary(data: &Vec<u8>) -> bincode::Result<Self>;
 }
 
 // TODO: implement and benchmark own implementation
@@ -81,14 +326,28 @@ macro_rules! implement_BinaryConversion {
                 serialize(self).unwrap()
             }
 
-            fn from_binary(data: &Vec<u8>) -> Self {
-                deserialize(data).unwrap()
+            fn from_binary(data: &Vec<u8>) -> bincode::Result<Self> {
+                deserialize(data)
             }
         })*
-    }
+    };
+    // Values of earlier versions are shorter than the current layout, so they fail to decode and are read with the legacy one
+    (for $t:ty, falling back to $legacy:ty) => {
+        impl BinaryConverison for $t {
+            fn to_binary(&self) -> Vec<u8> {
+                serialize(self).unwrap()
+            }
+
+            fn from_binary(data: &Vec<u8>) -> bincode::Result<Self> {
+                deserialize(data).or_else(|error| deserialize::<$legacy>(data).map(Self::from).or(Err(error)))
+            }
+        }
+    };
 }
 
-implement_BinaryConversion!(for Collection, IndexDB, SortedList<f64, [u8; 8]>, Vec<f64>);
+implement_BinaryConversion!(for Collection, falling back to LegacyCollection);
+implement_BinaryConversion!(for IndexDB, falling back to LegacyIndexDB);
+implement_BinaryConversion!(for TextIndexDB, Postings, ApiKeyDB, JobDB, SortedList<f64, [u8; 8]>, Vec<f64>, u32);
 
 #[cfg(test)]
 mod tests {
@@ -98,10 +357,66 @@ mod tests {
         let collection = Collection {
             collection_id: "test_collection".to_owned(),
             n_documents: 0,
+            schema: None,
+            created_at: 0,
+            updated_at: 0,
         };
 
         let encoded: Vec<u8> = bincode::serialize(&collection).unwrap();
         let decoded: Collection = bincode::deserialize(&encoded[..]).unwrap();
         assert_eq!(collection.collection_id, decoded.collection_id);
+
+        let collection = Collection::new("test_collection").with_schema(Schema {
+            vectors: vec![VectorField {
+                name: "vector".to_owned(),
+                dimension: 3,
+                metric: "cosine".to_owned(),
+                k: Some(8),
+                m: None,
+            }],
+            fields: vec![PayloadField {
+                name: "title".to_owned(),
+                field_type: FieldType::String,
+                required: true,
+            }],
+        });
+        assert_eq!(Collection::from_binary(&collection.to_binary()).unwrap(), collection);
+    }
+
+    #[test]
+    fn test_legacy_conversion() {
+        let legacy = LegacyCollection {
+            collection_id: "test_collection".to_owned(),
+            n_documents: 3,
+        };
+        let collection = Collection::from_binary(&serialize(&legacy).unwrap()).unwrap();
+        assert_eq!((collection.collection_id.as_str(), collection.n_documents), ("test_collection", 3));
+        assert_eq!((collection.schema, collection.created_at), (None, 0));
+
+        let legacy = LegacyIndexDB {
+            field_id: "vector".to_owned(),
+            collection_id: "test_collection".to_owned(),
+            metric: "euclidean".to_owned(),
+            buffer_size: 1,
+            dimension: 2,
+            k: 8,
+            entry_point: Some([1; 8]),
+            reverse_size: 1.0,
+            n_layers: 2,
+            n_elements: 3,
+        };
+        let index = IndexDB::from_binary(&serialize(&legacy).unwrap()).unwrap();
+        assert_eq!((index.entry_point, index.n_elements), (Some([1; 8]), 3));
+        assert_eq!(index.graph_hash, hash(b"vector").to_be_bytes());
+
+        // Current values are not mistaken for legacy ones
+        let index = IndexDB {
+            created_at: 1,
+            updated_at: 2,
+            graph_hash: [2; 8],
+            ..index
+        };
+        assert_eq!(IndexDB::from_binary(&index.to_binary()).unwrap(), index);
+        assert!(Collection::from_binary(&vec![0xFF]).is_err());
     }
 }
@@ -7,53 +7,357 @@ use crate::hnsw::key::*;
 use crate::hnsw::*;
 use crate::sorted_list::SortedList;
 
+use std::fmt;
 use std::path::Path;
+use std::sync::Arc;
 
 use std::collections::HashMap;
 
-use rocksdb::{ColumnFamily, DBWithThreadMode, Error, MultiThreaded, Options, SingleThreaded};
+use crate::config::StorageConfig;
+use crate::utils;
+
+use rocksdb::{
+    BlockBasedOptions, BoundColumnFamily, Cache, ColumnFamilyDescriptor, DBCompressionType, Direction, IteratorMode, ReadOptions, SliceTransform,
+};
+use rocksdb::{ColumnFamily, DBWithThreadMode, MultiThreaded, Options, SingleThreaded, WriteBatch, WriteOptions};
 
 pub type DB = DBWithThreadMode<MultiThreaded>;
 
+pub const COLUMN_FAMILIES: [&str; 5] = ["default", "metadata", "documents", "vectors", "neighbors"];
+
+/// Keys moved by a single batch when migrating an existing database
+const MIGRATION_BATCH_SIZE: usize = 10000;
+
+#[derive(Debug)]
+pub enum Error {
+    RocksDB(rocksdb::Error),
+    MissingColumnFamily(String),
+    /// Value which could not be decoded, with the column family and key it was read from
+    Corrupted {
+        cf: String,
+        key: Vec<u8>,
+    },
+}
+
+impl From<rocksdb::Error> for Error {
+    fn from(error: rocksdb::Error) -> Self {
+        Error::RocksDB(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::RocksDB(error) => write!(f, "{}", error),
+            Error::MissingColumnFamily(name) => write!(f, "missing column family '{}'", name),
+            Error::Corrupted { cf, key } => write!(f, "corrupted value in column family '{}' at key {}", cf, utils::to_hex(key)),
+        }
+    }
+}
+
+/// Column family of the keys of `key_type`. Text index data stays in "default".
+pub fn column_family(key_type: u8) -> &'static str {
+    match key_type {
+        COLLECTION | INDEX | TEXT_INDEX | API_KEY | RUN_STATE | JOB => "metadata",
+        DOCUMENT => "documents",
+        VALUE => "vectors",
+        NEIGHBORS | REVERSE_NEIGHBORS => "neighbors",
+        _ => "default",
+    }
+}
+
+/// Length of the fixed prefix extractor of a column family, covering the type and collection of a key, or also its field.
+/// Metadata is small and scanned by type only, so it has none.
+fn prefix_length(cf: &str) -> Option<usize> {
+    match cf {
+        "documents" => Some(9),
+        "default" | "vectors" | "neighbors" => Some(17),
+        _ => None,
+    }
+}
+
+/// Read options for a scan over `prefix`. Prefixes shorter than the prefix extractor have to seek in total order.
+fn scan_options(cf: &str, prefix: &[u8]) -> ReadOptions {
+    let mut read_options = ReadOptions::default();
+    match prefix_length(cf) {
+        Some(length) if prefix.len() >= length => read_options.set_prefix_same_as_start(true),
+        _ => read_options.set_total_order_seek(true),
+    }
+    read_options
+}
+
+/// First key after all keys starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Longer than any key
+    vec![u8::MAX; 27]
+}
+
+/// Share of an SST file with keys from `first` to `last` that lies between `start` and `end` (exclusive).
+/// Partially covered files are interpolated on the key bytes after the prefix common to `first` and `last`,
+/// assuming their keys are spread evenly, which holds for the hashed ids in keys.
+fn share_of_file(first: &[u8], last: &[u8], start: &[u8], end: &[u8]) -> f64 {
+    if last < start || first >= end {
+        return 0.0;
+    }
+    if first >= start && last < end {
+        return 1.0;
+    }
+    let common = first.iter().zip(last.iter()).take_while(|(a, b)| a == b).count();
+    let position = |key: &[u8]| {
+        let mut bytes = [0; 16];
+        let key = key.get(common..).unwrap_or(&[]);
+        let n = key.len().min(16);
+        bytes[..n].copy_from_slice(&key[..n]);
+        u128::from_be_bytes(bytes) as f64
+    };
+    let (lower, upper) = (first.max(start), last.min(end));
+    let width = position(last) - position(first);
+    if width <= 0.0 {
+        return 1.0;
+    }
+    ((position(upper) - position(lower)) / width).clamp(0.0, 1.0)
+}
+
+/// Column families and prefixes of all keys of a collection, including the collection itself
+pub fn collection_prefixes(collection_id: &[u8; 8]) -> Vec<(&'static str, Vec<u8>)> {
+    [COLLECTION, INDEX, DOCUMENT, VALUE, NEIGHBORS, REVERSE_NEIGHBORS, TEXT_INDEX, POSTINGS, FIELD_LENGTH]
+        .iter()
+        .map(|key_type| (column_family(*key_type), Prefix::new().prefix_type(*key_type).collection(collection_id).finish()))
+        .collect()
+}
+
+/// Id of the postings of a term in the text index of `field_id`, which POSTINGS keys hold in the field slot.
+/// Derived from the hash of the term, so the postings of earlier versions can be split up, see `split_postings`.
+pub fn term_id(field_id: &[u8; 8], term_hash: &[u8; 8]) -> [u8; 8] {
+    seahash::hash(&[&field_id[..], &term_hash[..]].concat()).to_be_bytes()
+}
+
+/// Column families and prefixes of all keys of an index, including the index itself
+pub fn index_prefixes(collection_id: &[u8; 8], field_id: &[u8; 8], graph_id: &[u8; 8]) -> Vec<(&'static str, Vec<u8>)> {
+    [(INDEX, field_id), (VALUE, field_id), (NEIGHBORS, graph_id), (REVERSE_NEIGHBORS, graph_id)]
+        .iter()
+        .map(|(key_type, id)| (column_family(*key_type), Prefix::new().prefix_type(*key_type).collection(collection_id).field(id).finish()))
+        .collect()
+}
+
+/// Options of a column family, derived from the database options
+fn cf_options(cf: &str, options: &Options, cache: Option<&Cache>) -> Options {
+    let mut cf_options = options.clone();
+    let mut block_options = BlockBasedOptions::default();
+    if let Some(cache) = cache {
+        block_options.set_block_cache(cache);
+    }
+    block_options.set_bloom_filter(10, false);
+    match cf {
+        // Floats hardly compress, and vectors are read one at a time
+        "vectors" => cf_options.set_compression_type(DBCompressionType::None),
+        // JSON compresses better in larger blocks
+        "documents" => block_options.set_block_size(16 << 10),
+        // Small neighbor lists are read one at a time during searches
+        "neighbors" => block_options.set_block_size(4 << 10),
+        _ => {}
+    }
+    if let Some(length) = prefix_length(cf) {
+        cf_options.set_prefix_extractor(SliceTransform::create_fixed_prefix(length));
+        cf_options.set_memtable_prefix_bloom_ratio(0.1);
+    }
+    cf_options.set_block_based_table_factory(&block_options);
+    cf_options
+}
+
+/// Logs a value which could not be decoded and returns the error reporting it
+fn corrupted(cf: &str, key: &Key) -> Error {
+    let error = Error::Corrupted {
+        cf: cf.to_owned(),
+        key: key.to_vec(),
+    };
+    log::error!("{}", error);
+    error
+}
+
+/// Decodes the values of a scan. Corrupted values are logged and skipped, so a single one doesn't hide all others.
+fn decode_all<T: BinaryConverison>(cf: &str, values: Vec<(Key, Vec<u8>)>) -> Vec<T> {
+    values
+        .into_iter()
+        .filter_map(|(key, value)| T::from_binary(&value).map_err(|_| corrupted(cf, &key)).ok())
+        .collect()
+}
+
+fn cf_handle<'a>(db: &'a DB, name: &str) -> Result<Arc<BoundColumnFamily<'a>>, Error> {
+    db.cf_handle(name).ok_or_else(|| Error::MissingColumnFamily(name.to_owned()))
+}
+
+/// Value of `key_type` in the current layout. Metadata of earlier versions is re-encoded, values which can't be decoded are kept as they are.
+fn upgrade(key_type: u8, value: &[u8]) -> Vec<u8> {
+    let value = value.to_vec();
+    let upgraded = match key_type {
+        COLLECTION => Collection::from_binary(&value).map(|collection| collection.to_binary()),
+        INDEX => IndexDB::from_binary(&value).map(|index| index.to_binary()),
+        _ => return value,
+    };
+    upgraded.unwrap_or(value)
+}
+
+/// Moves keys that earlier versions kept in "default" to their own column family and returns their number.
+/// Metadata is rewritten in the current layout on the way, see `upgrade`.
+/// Every batch moves its keys atomically, so an interrupted migration continues on the next open.
+fn migrate(db: &DB) -> Result<usize, Error> {
+    let default = cf_handle(db, "default")?;
+    let mut n_moved = 0;
+    for key_type in (0..=u8::MAX).filter(|key_type| column_family(*key_type) != "default") {
+        let target = cf_handle(db, column_family(key_type))?;
+        loop {
+            let mut batch = WriteBatch::default();
+            let mut iterator = db.raw_iterator_cf_opt(&default, scan_options("default", &[key_type]));
+            iterator.seek([key_type]);
+            while iterator.valid() && batch.len() < MIGRATION_BATCH_SIZE {
+                match (iterator.key(), iterator.value()) {
+                    (Some(k), Some(v)) if k.first() == Some(&key_type) => {
+                        batch.put_cf(&target, k, upgrade(key_type, v));
+                        batch.delete_cf(&default, k);
+                    }
+                    _ => break,
+                }
+                iterator.next();
+            }
+            iterator.status()?;
+            if batch.is_empty() {
+                break;
+            }
+            n_moved += batch.len() / 2;
+            db.write(batch)?;
+        }
+    }
+    Ok(n_moved)
+}
+
+/// Splits the postings of earlier versions, one value per term, into one POSTINGS key per term and document.
+/// Returns the number of terms. Corrupted values are logged and dropped, as searches could not read them either.
+fn split_postings(db: &DB) -> Result<usize, Error> {
+    let default = cf_handle(db, "default")?;
+    let mut n_split = 0;
+    loop {
+        let mut batch = WriteBatch::default();
+        let mut n_terms = 0;
+        let mut iterator = db.raw_iterator_cf_opt(&default, scan_options("default", &[LEGACY_POSTINGS]));
+        iterator.seek([LEGACY_POSTINGS]);
+        while iterator.valid() && n_terms < MIGRATION_BATCH_SIZE {
+            match (iterator.key(), iterator.value()) {
+                (Some(k), Some(v)) if k.first() == Some(&LEGACY_POSTINGS) => {
+                    let legacy = Key::from_slice(k);
+                    match Postings::from_binary(&v.to_vec()) {
+                        Ok(postings) => {
+                            let mut key = Key::new();
+                            key.set_type(POSTINGS);
+                            key.set_collection_id(&legacy.get_collection_id());
+                            key.set_field_id(&term_id(&legacy.get_field_id(), &legacy.get_document_id()));
+                            for (document_id, frequency) in postings.iter() {
+                                key.set_document_id(document_id);
+                                batch.put_cf(&default, key.to_vec(), frequency.to_binary());
+                            }
+                        }
+                        Err(_) => {
+                            corrupted("default", &legacy);
+                        }
+                    }
+                    batch.delete_cf(&default, k);
+                    n_terms += 1;
+                }
+                _ => break,
+            }
+            iterator.next();
+        }
+        iterator.status()?;
+        if n_terms == 0 {
+            break;
+        }
+        n_split += n_terms;
+        db.write(batch)?;
+    }
+    Ok(n_split)
+}
+
 pub struct RocksDB {
     pub db: DB,
     pub options: Options,
 }
 
-fn init_db(db: &mut DB, options: &Options) {
-    // println!("{:?}", DB::list_cf(options, "./HIDDBrocksdb"));
-}
-
 impl RocksDB {
     pub fn init(path: &str) -> Self {
-        let new_db = !Path::new(path).exists();
+        Self::open(path, &StorageConfig::default())
+    }
+
+    pub fn open(path: &str, config: &StorageConfig) -> Self {
+        Self::try_open(path, config).unwrap()
+    }
 
+    /// Fails instead of panicking, e.g. when another process holds the lock on `path`.
+    /// Databases of earlier versions are migrated to the current column families.
+    pub fn try_open(path: &str, config: &StorageConfig) -> Result<Self, Error> {
         let mut options = Options::default();
         options.increase_parallelism(num_cpus::get() as i32);
         options.create_if_missing(true);
         options.create_missing_column_families(true);
         options.enable_statistics();
 
-        let cf_names = ["default", "neighbors"];
-        let mut db = DB::open_cf(&options, path, &cf_names).unwrap();
+        if let Some(compression) = config.compression_type() {
+            options.set_compression_type(compression);
+        }
+        if let Some(write_buffer_mb) = config.write_buffer_mb {
+            options.set_write_buffer_size(write_buffer_mb << 20);
+        }
+        if let Some(jobs) = config.max_background_jobs {
+            options.set_max_background_jobs(jobs);
+        }
+        // Shared by all column families
+        let cache = match config.block_cache_mb {
+            Some(block_cache_mb) => Some(Cache::new_lru_cache(block_cache_mb << 20)?),
+            None => None,
+        };
+
+        let cfs = COLUMN_FAMILIES
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, cf_options(name, &options, cache.as_ref())));
+        let db = DB::open_cf_descriptors(&options, path, cfs)?;
 
-        if new_db {
-            init_db(&mut db, &options);
+        let n_moved = migrate(&db)?;
+        if n_moved > 0 {
+            log::info!("Moved {} keys to their column families", n_moved);
         }
-        Self { db, options }
+        let n_split = split_postings(&db)?;
+        if n_split > 0 {
+            log::info!("Split the postings of {} terms into one key per document", n_split);
+        }
+        Ok(Self { db, options })
     }
 }
 
 impl RocksDB {
     pub fn get_by_key(&self, cf: &str, key: &Key) -> Result<Option<Vec<u8>>, Error> {
-        let cf = self.db.cf_handle(cf).unwrap();
-        self.db.get_cf(&cf, key.to_vec())
+        let cf = cf_handle(&self.db, cf)?;
+        Ok(self.db.get_cf(&cf, key.to_vec())?)
+    }
+
+    /// Reads and decodes the value of `key`. Corrupted values are logged and returned as `Error::Corrupted`.
+    pub fn get_decoded<T: BinaryConverison>(&self, cf: &str, key: &Key) -> Result<Option<T>, Error> {
+        match self.get_by_key(cf, key)? {
+            Some(value) => T::from_binary(&value).map(Some).map_err(|_| corrupted(cf, key)),
+            None => Ok(None),
+        }
     }
 
     pub fn get_by_prefix(&self, cf: &str, prefix: &Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
-        let cf = self.db.cf_handle(cf).unwrap();
+        let read_options = scan_options(cf, prefix);
+        let cf = cf_handle(&self.db, cf)?;
         let mut results: Vec<Vec<u8>> = Vec::new();
-        for (k, v) in self.db.prefix_iterator_cf(&cf, prefix) {
+        for (k, v) in self.db.iterator_cf_opt(&cf, read_options, IteratorMode::From(prefix, Direction::Forward)) {
             if k[..prefix.len()] != prefix[..] {
                 break;
             }
@@ -63,9 +367,10 @@ impl RocksDB {
     }
 
     pub fn get_by_prefix_key_value(&self, cf: &str, prefix: &Vec<u8>) -> Result<Vec<(Key, Vec<u8>)>, Error> {
-        let cf = self.db.cf_handle(cf).unwrap();
+        let read_options = scan_options(cf, prefix);
+        let cf = cf_handle(&self.db, cf)?;
         let mut results: Vec<(Key, Vec<u8>)> = Vec::new();
-        for (k, v) in self.db.prefix_iterator_cf(&cf, prefix) {
+        for (k, v) in self.db.iterator_cf_opt(&cf, read_options, IteratorMode::From(prefix, Direction::Forward)) {
             if k[..prefix.len()] != prefix[..] {
                 break;
             }
@@ -74,23 +379,108 @@ impl RocksDB {
         Ok(results)
     }
 
+    /// Approximate bytes on disk of the keys starting with each of `prefixes`, like RocksDB's `GetApproximateSizes`.
+    /// Computed from the key ranges and sizes of the SST files without reading any data, as the rocksdb crate
+    /// doesn't give access to the database handle `rocksdb_approximate_sizes_cf` needs. Column families are
+    /// told apart by the key type in the first byte. Writes still in memtables are counted once they are flushed.
+    pub fn get_approximate_sizes(&self, prefixes: &[Vec<u8>]) -> Result<Vec<u64>, Error> {
+        let files = self.db.live_files()?;
+        Ok(prefixes
+            .iter()
+            .map(|prefix| {
+                let end = prefix_end(prefix);
+                let size: f64 = files
+                    .iter()
+                    .filter_map(|file| match (&file.start_key, &file.end_key) {
+                        (Some(first), Some(last)) => Some(file.size as f64 * share_of_file(first, last, prefix, &end)),
+                        _ => None,
+                    })
+                    .sum();
+                size as u64
+            })
+            .collect())
+    }
+
+    /// Groups the keys starting with `prefix` by their first `length` bytes.
+    /// Returns each group with its number of keys and bytes of keys and values, in key order.
+    pub fn get_sizes_by_group(&self, cf: &str, prefix: &Vec<u8>, length: usize) -> Result<Vec<(Vec<u8>, u64, u64)>, Error> {
+        let read_options = scan_options(cf, prefix);
+        let cf = cf_handle(&self.db, cf)?;
+        let mut iterator = self.db.raw_iterator_cf_opt(&cf, read_options);
+        iterator.seek(prefix);
+
+        let mut groups: Vec<(Vec<u8>, u64, u64)> = Vec::new();
+        while iterator.valid() {
+            match (iterator.key(), iterator.value()) {
+                (Some(k), Some(v)) if k.starts_with(prefix) => {
+                    let group = &k[..length.min(k.len())];
+                    match groups.last_mut() {
+                        Some((last, keys, size)) if last[..] == group[..] => {
+                            *keys += 1;
+                            *size += (k.len() + v.len()) as u64;
+                        }
+                        _ => groups.push((group.to_vec(), 1, (k.len() + v.len()) as u64)),
+                    }
+                }
+                _ => break,
+            }
+            iterator.next();
+        }
+        iterator.status()?;
+        Ok(groups)
+    }
+
+    /// Returns the keys starting with `prefix` without copying their values
+    pub fn get_keys_by_prefix(&self, cf: &str, prefix: &Vec<u8>) -> Result<Vec<Key>, Error> {
+        let read_options = scan_options(cf, prefix);
+        let cf = cf_handle(&self.db, cf)?;
+        let mut iterator = self.db.raw_iterator_cf_opt(&cf, read_options);
+        iterator.seek(prefix);
+
+        let mut keys: Vec<Key> = Vec::new();
+        while iterator.valid() {
+            match iterator.key() {
+                Some(k) if k.starts_with(prefix) => keys.push(Key::from_slice(k)),
+                _ => break,
+            }
+            iterator.next();
+        }
+        iterator.status()?;
+        Ok(keys)
+    }
+
     pub fn put(&self, cf: &str, key: &Key, value: &Vec<u8>) -> Result<(), Error> {
-        let cf = self.db.cf_handle(cf).unwrap();
-        self.db.put_cf(&cf, key.to_vec(), value)
+        let cf = cf_handle(&self.db, cf)?;
+        Ok(self.db.put_cf(&cf, key.to_vec(), value)?)
     }
 
     pub fn delete(&self, cf: &str, key: &Key) -> Result<(), Error> {
-        let cf = self.db.cf_handle(cf).unwrap();
-        self.db.delete_cf(&cf, key.to_vec())
+        let cf = cf_handle(&self.db, cf)?;
+        Ok(self.db.delete_cf(&cf, key.to_vec())?)
     }
 
+    /// Deletes all keys starting with `prefix`, see `delete_by_prefixes`
     pub fn delete_by_prefix(&self, cf: &str, prefix: &Vec<u8>) -> Result<(), Error> {
-        let cf = self.db.cf_handle(cf).unwrap();
-        for (k, _) in self.db.prefix_iterator_cf(&cf, prefix) {
-            if k[..prefix.len()] != prefix[..] {
-                break;
-            }
-            self.db.delete_cf(&cf, k).unwrap();
+        self.delete_by_prefixes(&[(cf, prefix.clone())])
+    }
+
+    /// Deletes all keys starting with one of the prefixes in a single batch, so either all of them are deleted or none.
+    /// Each prefix becomes one range tombstone, independent of the number of keys. Their space is reclaimed by compaction.
+    pub fn delete_by_prefixes(&self, prefixes: &[(&str, Vec<u8>)]) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        for (cf, prefix) in prefixes.iter() {
+            let cf = cf_handle(&self.db, cf)?;
+            batch.delete_range_cf(&cf, prefix.as_slice(), prefix_end(prefix).as_slice());
+        }
+        Ok(self.db.write(batch)?)
+    }
+
+    /// Compacts the keys starting with each of the prefixes, which drops deleted ones from disk
+    pub fn compact_prefixes(&self, prefixes: &[(&str, Vec<u8>)]) -> Result<(), Error> {
+        for (cf, prefix) in prefixes.iter() {
+            let cf = cf_handle(&self.db, cf)?;
+            self.db
+                .compact_range_cf(&cf, Some(prefix.as_slice()), Some(prefix_end(prefix).as_slice()));
         }
         Ok(())
     }
@@ -103,7 +493,29 @@ impl RocksDB {
         key.set_collection_id(collection_id);
 
         // TODO: create collections
-        self.put("default", &key, &collection.to_binary())
+        self.put("metadata", &key, &collection.to_binary())
+    }
+
+    /// Writes a collection together with its indices in a single batch.
+    /// Either all keys are written or none.
+    pub fn insert_collection_with_indices(&self, collection_id: &[u8; 8], collection: &Collection, indices: &[Index]) -> Result<(), Error> {
+        let cf = cf_handle(&self.db, "metadata")?;
+        let mut batch = WriteBatch::default();
+
+        let mut key = Key::new();
+        key.set_type(COLLECTION);
+        key.set_collection_id(collection_id);
+        batch.put_cf(&cf, key.to_vec(), collection.to_binary());
+
+        for index in indices.iter() {
+            let mut key = Key::new();
+            key.set_type(INDEX);
+            key.set_collection_id(collection_id);
+            key.set_field_id(&index.field_hash);
+            batch.put_cf(&cf, key.to_vec(), IndexDB::from_hnsw_type(index).to_binary());
+        }
+
+        Ok(self.db.write(batch)?)
     }
 
     pub fn insert_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8], index: &Index) -> Result<(), Error> {
@@ -112,7 +524,7 @@ impl RocksDB {
         key.set_collection_id(collection_id);
         key.set_field_id(field_id);
         // TODO: create index on field
-        self.put("default", &key, &IndexDB::from_hnsw_type(index).to_binary())
+        self.put("metadata", &key, &IndexDB::from_hnsw_type(index).to_binary())
     }
 
     pub fn insert_document(&self, collection_id: &[u8; 8], document_id: &[u8; 8], document: &Document) -> Result<(), Error> {
@@ -122,7 +534,7 @@ impl RocksDB {
         key.set_document_id(&document_id);
 
         // TODO: create index on field
-        self.put("default", &key, &document.clone().to_binary())
+        self.put("documents", &key, &document.clone().to_binary())
     }
 
     pub fn insert_value(&self, collection_id: &[u8; 8], field_id: &[u8; 8], document_id: &[u8; 8], value: &Vec<f64>) -> Result<(), Error> {
@@ -133,7 +545,42 @@ impl RocksDB {
         key.set_document_id(document_id);
 
         // TODO: create index on field
-        self.put("default", &key, &value.clone().to_binary())
+        self.put("vectors", &key, &value.clone().to_binary())
+    }
+
+    pub fn insert_text_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8], text_index: &TextIndexDB) -> Result<(), Error> {
+        let mut key = Key::new();
+        key.set_type(TEXT_INDEX);
+        key.set_collection_id(collection_id);
+        key.set_field_id(field_id);
+        self.put("metadata", &key, &text_index.to_binary())
+    }
+
+    /// Writes the postings of several terms, by `term_id`, in a single batch.
+    /// Every document of a term gets its own key with the term frequency, so adding documents doesn't rewrite the others.
+    pub fn insert_postings(&self, collection_id: &[u8; 8], postings: &[([u8; 8], Postings)]) -> Result<(), Error> {
+        let cf = cf_handle(&self.db, "default")?;
+        let mut batch = WriteBatch::default();
+        let mut key = Key::new();
+        key.set_type(POSTINGS);
+        key.set_collection_id(collection_id);
+        for (term_id, postings) in postings.iter() {
+            key.set_field_id(term_id);
+            for (document_id, frequency) in postings.iter() {
+                key.set_document_id(document_id);
+                batch.put_cf(&cf, key.to_vec(), frequency.to_binary());
+            }
+        }
+        Ok(self.db.write(batch)?)
+    }
+
+    pub fn insert_field_length(&self, collection_id: &[u8; 8], field_id: &[u8; 8], document_id: &[u8; 8], length: u32) -> Result<(), Error> {
+        let mut key = Key::new();
+        key.set_type(FIELD_LENGTH);
+        key.set_collection_id(collection_id);
+        key.set_field_id(field_id);
+        key.set_document_id(document_id);
+        self.put("default", &key, &length.to_binary())
     }
 
     pub fn insert_neighbors(
@@ -157,55 +604,112 @@ impl RocksDB {
 }
 
 impl RocksDB {
-    pub fn get_collection(&self, collection_id: &[u8; 8]) -> Option<Collection> {
+    pub fn get_collection(&self, collection_id: &[u8; 8]) -> Result<Option<Collection>, Error> {
         let mut key = Key::new();
         key.set_type(COLLECTION);
         key.set_collection_id(collection_id);
-        return match self.get_by_key("default", &key).unwrap() {
-            Some(c) => Some(Collection::from_binary(&c)),
-            _ => None,
-        };
+        self.get_decoded("metadata", &key)
     }
 
-    pub fn get_indices_in_collection(&self, collection_id: &[u8; 8]) -> Result<Vec<Vec<u8>>, Error> {
+    /// All collections, skipping corrupted ones
+    pub fn get_collections(&self) -> Result<Vec<Collection>, Error> {
+        let prefix = Prefix::new().prefix_type(COLLECTION).finish();
+        Ok(decode_all("metadata", self.get_by_prefix_key_value("metadata", &prefix)?))
+    }
+
+    /// Indices of a collection, skipping corrupted ones
+    pub fn get_indices_in_collection(&self, collection_id: &[u8; 8]) -> Result<Vec<IndexDB>, Error> {
         let prefix = Prefix::new().prefix_type(INDEX).collection(collection_id).finish();
-        self.get_by_prefix("default", &prefix)
+        Ok(decode_all("metadata", self.get_by_prefix_key_value("metadata", &prefix)?))
+    }
+
+    /// Indices of all collections, skipping corrupted ones
+    pub fn get_indices(&self) -> Result<Vec<IndexDB>, Error> {
+        let prefix = Prefix::new().prefix_type(INDEX).finish();
+        Ok(decode_all("metadata", self.get_by_prefix_key_value("metadata", &prefix)?))
     }
 
-    pub fn get_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8]) -> Option<Index> {
+    pub fn get_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8]) -> Result<Option<Index>, Error> {
         let mut key = Key::new();
         key.set_type(INDEX);
         key.set_collection_id(collection_id);
         key.set_field_id(field_id);
-        let index = match self.get_by_key("default", &key).unwrap() {
-            Some(i) => IndexDB::from_binary(&i).to_hnsw_type(),
-            _ => return None,
-        };
-        Some(index)
+        Ok(self.get_decoded::<IndexDB>("metadata", &key)?.map(|index| index.to_hnsw_type()))
     }
 
-    pub fn get_document(&self, collection_id: &[u8; 8], document_id: &[u8; 8]) -> Option<Document> {
+    pub fn get_document(&self, collection_id: &[u8; 8], document_id: &[u8; 8]) -> Result<Option<Document>, Error> {
         let mut key = Key::new();
         key.set_type(DOCUMENT);
         key.set_collection_id(collection_id);
         key.set_field_id(&[0u8; 8]);
         key.set_document_id(document_id);
-        match self.get_by_key("default", &key).unwrap() {
-            Some(document) => Some(Document::from_binary(document)),
-            None => None,
+        match self.get_by_key("documents", &key)? {
+            Some(document) => Document::from_binary(document).map(Some).ok_or_else(|| corrupted("documents", &key)),
+            None => Ok(None),
         }
     }
 
-    pub fn get_value(&self, collection_id: &[u8; 8], field_id: &[u8; 8], document_id: &[u8; 8]) -> Option<Vec<f64>> {
+    /// Documents of a collection, skipping corrupted ones
+    pub fn get_documents(&self, collection_id: &[u8; 8]) -> Result<Vec<Document>, Error> {
+        let prefix = Prefix::new().prefix_type(DOCUMENT).collection(collection_id).finish();
+        Ok(self
+            .get_by_prefix_key_value("documents", &prefix)?
+            .into_iter()
+            .filter_map(|(key, document)| {
+                let document = Document::from_binary(document);
+                if document.is_none() {
+                    corrupted("documents", &key);
+                }
+                document
+            })
+            .collect())
+    }
+
+    pub fn get_value(&self, collection_id: &[u8; 8], field_id: &[u8; 8], document_id: &[u8; 8]) -> Result<Option<Vec<f64>>, Error> {
         let mut key = Key::new();
         key.set_type(VALUE);
         key.set_collection_id(collection_id);
         key.set_field_id(field_id);
         key.set_document_id(document_id);
-        match self.get_by_key("default", &key).unwrap() {
-            Some(value) => Some(Vec::<f64>::from_binary(&value)),
-            None => None,
-        }
+        self.get_decoded("vectors", &key)
+    }
+
+    pub fn get_text_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8]) -> Result<Option<TextIndexDB>, Error> {
+        let mut key = Key::new();
+        key.set_type(TEXT_INDEX);
+        key.set_collection_id(collection_id);
+        key.set_field_id(field_id);
+        self.get_decoded("metadata", &key)
+    }
+
+    /// Text indices of a collection, skipping corrupted ones
+    pub fn get_text_indices_in_collection(&self, collection_id: &[u8; 8]) -> Result<Vec<TextIndexDB>, Error> {
+        let prefix = Prefix::new().prefix_type(TEXT_INDEX).collection(collection_id).finish();
+        Ok(decode_all("metadata", self.get_by_prefix_key_value("metadata", &prefix)?))
+    }
+
+    /// Documents containing the term of `term_id`, see `term_id`. Corrupted frequencies are logged and skipped.
+    pub fn get_postings(&self, collection_id: &[u8; 8], term_id: &[u8; 8]) -> Result<Postings, Error> {
+        let prefix = Prefix::new().prefix_type(POSTINGS).collection(collection_id).field(term_id).finish();
+        Ok(self
+            .get_by_prefix_key_value("default", &prefix)?
+            .into_iter()
+            .filter_map(|(key, value)| {
+                u32::from_binary(&value)
+                    .map(|frequency| (key.get_document_id(), frequency))
+                    .map_err(|_| corrupted("default", &key))
+                    .ok()
+            })
+            .collect())
+    }
+
+    pub fn get_field_length(&self, collection_id: &[u8; 8], field_id: &[u8; 8], document_id: &[u8; 8]) -> Result<Option<u32>, Error> {
+        let mut key = Key::new();
+        key.set_type(FIELD_LENGTH);
+        key.set_collection_id(collection_id);
+        key.set_field_id(field_id);
+        key.set_document_id(document_id);
+        self.get_decoded("default", &key)
     }
 
     pub fn get_neighbors(
@@ -214,18 +718,14 @@ impl RocksDB {
         field_id: &[u8; 8],
         layer_id: u8,
         document_id: &[u8; 8],
-    ) -> Option<SortedList<f64, [u8; 8]>> {
+    ) -> Result<Option<SortedList<f64, [u8; 8]>>, Error> {
         let mut key = Key::new();
         key.set_type(NEIGHBORS);
         key.set_collection_id(collection_id);
         key.set_field_id(&field_id);
         key.set_layer(layer_id);
         key.set_document_id(document_id);
-
-        match self.get_by_key("neighbors", &key).unwrap() {
-            Some(neighbors) => Some(SortedList::<f64, [u8; 8]>::from_binary(&neighbors)),
-            None => None,
-        }
+        self.get_decoded("neighbors", &key)
     }
 
     pub fn get_reverse_neighbors(
@@ -248,45 +748,143 @@ impl RocksDB {
 }
 
 impl RocksDB {
+    /// Deletes a collection with its documents, indices and text indices
     pub fn delete_collection(&self, collection_id: &[u8; 8]) -> Result<(), Error> {
-        // Delete all associated indicies, documents, neighbors and reverse neighbors
-        self.delete_by_prefix("default", &Prefix::new().prefix_type(INDEX).collection(collection_id).finish())
-            .unwrap();
-        self.delete_by_prefix("default", &Prefix::new().prefix_type(DOCUMENT).collection(collection_id).finish())
-            .unwrap();
-        self.delete_by_prefix("neighbors", &Prefix::new().prefix_type(NEIGHBORS).collection(collection_id).finish())
-            .unwrap();
-        self.delete_by_prefix(
-            "neighbors",
-            &Prefix::new().prefix_type(REVERSE_NEIGHBORS).collection(collection_id).finish(),
-        )
-        .unwrap();
-
-        // Delete collection itself
+        self.delete_by_prefixes(&collection_prefixes(collection_id))
+    }
+
+    /// Deletes an index with its vectors and graph. The documents are kept.
+    pub fn delete_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8], graph_id: &[u8; 8]) -> Result<(), Error> {
+        self.delete_by_prefixes(&index_prefixes(collection_id, field_id, graph_id))
+    }
+}
+
+impl RocksDB {
+    /// Deletes the neighbor lists of a graph, see `Index::graph_hash`
+    pub fn delete_graph(&self, collection_id: &[u8; 8], graph_id: &[u8; 8]) -> Result<(), Error> {
+        let prefixes: Vec<(&str, Vec<u8>)> = [NEIGHBORS, REVERSE_NEIGHBORS]
+            .iter()
+            .map(|key_type| ("neighbors", Prefix::new().prefix_type(*key_type).collection(collection_id).field(graph_id).finish()))
+            .collect();
+        self.delete_by_prefixes(&prefixes)
+    }
+}
+
+impl RocksDB {
+    /// Deletes a text index with its field lengths and `postings`, given as term id and document.
+    /// POSTINGS keys are named by the term instead of the field, so they can't be deleted by prefix.
+    pub fn delete_text_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8], postings: &[([u8; 8], [u8; 8])]) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        for key_type in [TEXT_INDEX, FIELD_LENGTH].iter() {
+            let cf = cf_handle(&self.db, column_family(*key_type))?;
+            let prefix = Prefix::new().prefix_type(*key_type).collection(collection_id).field(field_id).finish();
+            batch.delete_range_cf(&cf, prefix.as_slice(), prefix_end(&prefix).as_slice());
+        }
+
+        let cf = cf_handle(&self.db, "default")?;
         let mut key = Key::new();
-        key.set_type(COLLECTION);
+        key.set_type(POSTINGS);
         key.set_collection_id(collection_id);
-        self.delete("default", &key)
-    }
-
-    pub fn delete_index(&self, collection_id: &[u8; 8], field_id: &[u8; 8]) -> Result<(), Error> {
-        // Delete all associated indicies, documents, neighbors and reverse neighbors
-        self.delete_by_prefix("default", &Prefix::new().prefix_type(DOCUMENT).collection(collection_id).finish())
-            .unwrap();
-        self.delete_by_prefix("neighbors", &Prefix::new().prefix_type(NEIGHBORS).collection(collection_id).finish())
-            .unwrap();
-        self.delete_by_prefix(
-            "neighbors",
-            &Prefix::new().prefix_type(REVERSE_NEIGHBORS).collection(collection_id).finish(),
-        )
-        .unwrap();
-
-        // Delete index itself
+        for (term_id, document_id) in postings.iter() {
+            key.set_field_id(term_id);
+            key.set_document_id(document_id);
+            batch.delete_cf(&cf, key.to_vec());
+        }
+        Ok(self.db.write(batch)?)
+    }
+}
+
+impl RocksDB {
+    pub fn insert_api_key(&self, key_id: &[u8; 8], api_key: &ApiKeyDB) -> Result<(), Error> {
         let mut key = Key::new();
-        key.set_type(INDEX);
-        key.set_collection_id(collection_id);
-        key.set_field_id(field_id);
-        self.delete("default", &key)
+        key.set_type(API_KEY);
+        key.set_document_id(key_id);
+        self.put("metadata", &key, &api_key.to_binary())
+    }
+
+    pub fn get_api_key(&self, key_id: &[u8; 8]) -> Result<Option<ApiKeyDB>, Error> {
+        let mut key = Key::new();
+        key.set_type(API_KEY);
+        key.set_document_id(key_id);
+        self.get_decoded("metadata", &key)
+    }
+
+    /// All API keys, skipping corrupted ones
+    pub fn get_api_keys(&self) -> Result<Vec<ApiKeyDB>, Error> {
+        let prefix = Prefix::new().prefix_type(API_KEY).finish();
+        Ok(decode_all("metadata", self.get_by_prefix_key_value("metadata", &prefix)?))
+    }
+
+    pub fn delete_api_key(&self, key_id: &[u8; 8]) -> Result<(), Error> {
+        let mut key = Key::new();
+        key.set_type(API_KEY);
+        key.set_document_id(key_id);
+        self.delete("metadata", &key)
+    }
+}
+
+impl RocksDB {
+    pub fn insert_job(&self, job_id: &[u8; 8], job: &JobDB) -> Result<(), Error> {
+        let mut key = Key::new();
+        key.set_type(JOB);
+        key.set_document_id(job_id);
+        self.put("metadata", &key, &job.to_binary())
+    }
+
+    pub fn get_job(&self, job_id: &[u8; 8]) -> Result<Option<JobDB>, Error> {
+        let mut key = Key::new();
+        key.set_type(JOB);
+        key.set_document_id(job_id);
+        self.get_decoded("metadata", &key)
+    }
+
+    /// All jobs, skipping corrupted ones
+    pub fn get_jobs(&self) -> Result<Vec<JobDB>, Error> {
+        let prefix = Prefix::new().prefix_type(JOB).finish();
+        Ok(decode_all("metadata", self.get_by_prefix_key_value("metadata", &prefix)?))
+    }
+
+    pub fn delete_job(&self, job_id: &[u8; 8]) -> Result<(), Error> {
+        let mut key = Key::new();
+        key.set_type(JOB);
+        key.set_document_id(job_id);
+        self.delete("metadata", &key)
+    }
+}
+
+impl RocksDB {
+    pub fn get_run_state(&self) -> Result<Option<u8>, Error> {
+        let mut key = Key::new();
+        key.set_type(RUN_STATE);
+        Ok(self.get_by_key("metadata", &key)?.and_then(|state| state.first().copied()))
+    }
+
+    /// Written synchronously, which also syncs all earlier writes in the WAL to disk
+    pub fn set_run_state(&self, state: u8) -> Result<(), Error> {
+        let mut key = Key::new();
+        key.set_type(RUN_STATE);
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(true);
+        let cf = cf_handle(&self.db, "metadata")?;
+        Ok(self.db.put_cf_opt(&cf, key.to_vec(), [state], &write_options)?)
+    }
+
+    /// Writes the memtables of all column families to SST files, so the next start doesn't replay the WAL
+    pub fn flush(&self) -> Result<(), Error> {
+        for name in COLUMN_FAMILIES.iter() {
+            let cf = cf_handle(&self.db, name)?;
+            self.db.flush_cf(&cf)?;
+        }
+        Ok(())
+    }
+
+    /// Compacts all column families, which drops deleted entries from disk
+    pub fn compact(&self) -> Result<(), Error> {
+        for name in COLUMN_FAMILIES.iter() {
+            let cf = cf_handle(&self.db, name)?;
+            self.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        }
+        Ok(())
     }
 }
 
@@ -312,75 +910,314 @@ mod tests {
             db_options = db.get_options();
             let mut key_document = Key::new();
             key_document.set_type(DOCUMENT);
-            db.put("default", &key_document, &vec![0, 0, 0]).unwrap();
+            db.put("documents", &key_document, &vec![0, 0, 0]).unwrap();
 
             let mut key_index = Key::new();
             key_index.set_type(INDEX);
-            db.put("default", &key_index, &vec![0, 0, 1]).unwrap();
+            db.put("metadata", &key_index, &vec![0, 0, 1]).unwrap();
 
             let mut key_collection = Key::new();
             key_collection.set_type(COLLECTION);
-            db.put("default", &key_collection, &vec![0, 0, 2]).unwrap();
+            db.put("metadata", &key_collection, &vec![0, 0, 2]).unwrap();
 
-            assert_eq!(db.get_by_key("default", &key_document).unwrap(), Some(vec![0, 0, 0]));
-            assert_eq!(db.get_by_key("default", &key_index).unwrap(), Some(vec![0, 0, 1]));
-            assert_eq!(db.get_by_key("default", &key_collection).unwrap(), Some(vec![0, 0, 2]));
+            assert_eq!(db.get_by_key("documents", &key_document).unwrap(), Some(vec![0, 0, 0]));
+            assert_eq!(db.get_by_key("metadata", &key_index).unwrap(), Some(vec![0, 0, 1]));
+            assert_eq!(db.get_by_key("metadata", &key_collection).unwrap(), Some(vec![0, 0, 2]));
 
             // Test collection deletion
-            db.delete("default", &key_collection).unwrap();
-            assert_eq!(db.get_by_key("default", &key_collection).unwrap(), None);
+            db.delete("metadata", &key_collection).unwrap();
+            assert_eq!(db.get_by_key("metadata", &key_collection).unwrap(), None);
 
             let mut key_collection_1 = Key::new();
             key_collection_1.set_type(COLLECTION);
             let collection_id = &[0, 0, 0, 0, 1, 1, 1, 2];
             key_collection_1.set_collection_id(collection_id);
-            db.put("default", &key_collection_1, &vec![1, 2, 3]).unwrap();
+            db.put("metadata", &key_collection_1, &vec![1, 2, 3]).unwrap();
             let mut key_collection_2 = Key::new();
             key_collection_2.set_type(COLLECTION);
             let collection_id = &[1, 5, 2, 0, 1, 1, 1, 2];
             key_collection_2.set_collection_id(collection_id);
-            db.put("default", &key_collection_2, &vec![2, 2, 3]).unwrap();
-            assert_eq!(db.get_by_key("default", &key_collection_1).unwrap(), Some(vec![1, 2, 3]));
-            assert_eq!(db.get_by_key("default", &key_collection_2).unwrap(), Some(vec![2, 2, 3]));
-            db.delete_by_prefix("default", &Prefix::new().prefix_type(COLLECTION).finish()).unwrap();
-            assert_eq!(db.get_by_key("default", &key_collection_1).unwrap(), None);
-            assert_eq!(db.get_by_key("default", &key_collection_2).unwrap(), None);
+            db.put("metadata", &key_collection_2, &vec![2, 2, 3]).unwrap();
+            assert_eq!(db.get_by_key("metadata", &key_collection_1).unwrap(), Some(vec![1, 2, 3]));
+            assert_eq!(db.get_by_key("metadata", &key_collection_2).unwrap(), Some(vec![2, 2, 3]));
+            db.delete_by_prefix("metadata", &Prefix::new().prefix_type(COLLECTION).finish()).unwrap();
+            assert_eq!(db.get_by_key("metadata", &key_collection_1).unwrap(), None);
+            assert_eq!(db.get_by_key("metadata", &key_collection_2).unwrap(), None);
 
             let mut key_collection_1 = Key::new();
             key_collection_1.set_type(COLLECTION);
             let collection_id_1 = &[0, 0, 0, 0, 1, 1, 1, 2];
             key_collection_1.set_collection_id(collection_id_1);
-            db.put("default", &key_collection_1, &vec![1, 2, 3]).unwrap();
+            db.put("metadata", &key_collection_1, &vec![1, 2, 3]).unwrap();
             let mut key_collection_2 = Key::new();
             key_collection_2.set_type(COLLECTION);
             let collection_id_2 = &[1, 5, 2, 0, 1, 1, 1, 2];
             key_collection_2.set_collection_id(collection_id_2);
-            db.put("default", &key_collection_2, &vec![2, 2, 3]).unwrap();
+            db.put("metadata", &key_collection_2, &vec![2, 2, 3]).unwrap();
             let mut key_index_2 = key_collection_2.clone();
             key_index_2.set_type(INDEX);
-            db.put("default", &key_index_2, &vec![2, 2, 8]).unwrap();
-            assert_eq!(db.get_by_key("default", &key_collection_1).unwrap(), Some(vec![1, 2, 3]));
-            assert_eq!(db.get_by_key("default", &key_collection_2).unwrap(), Some(vec![2, 2, 3]));
-            assert_eq!(db.get_by_key("default", &key_index_2).unwrap(), Some(vec![2, 2, 8]));
+            db.put("metadata", &key_index_2, &vec![2, 2, 8]).unwrap();
+            assert_eq!(db.get_by_key("metadata", &key_collection_1).unwrap(), Some(vec![1, 2, 3]));
+            assert_eq!(db.get_by_key("metadata", &key_collection_2).unwrap(), Some(vec![2, 2, 3]));
+            assert_eq!(db.get_by_key("metadata", &key_index_2).unwrap(), Some(vec![2, 2, 8]));
             db.delete_collection(collection_id_1).unwrap();
-            assert_eq!(db.get_by_key("default", &key_collection_1).unwrap(), None);
-            assert_eq!(db.get_by_key("default", &key_collection_2).unwrap(), Some(vec![2, 2, 3]));
-            assert_eq!(db.get_by_key("default", &key_index_2).unwrap(), Some(vec![2, 2, 8]));
+            assert_eq!(db.get_by_key("metadata", &key_collection_1).unwrap(), None);
+            assert_eq!(db.get_by_key("metadata", &key_collection_2).unwrap(), Some(vec![2, 2, 3]));
+            assert_eq!(db.get_by_key("metadata", &key_index_2).unwrap(), Some(vec![2, 2, 8]));
             db.delete_collection(collection_id_2).unwrap();
-            assert_eq!(db.get_by_key("default", &key_collection_1).unwrap(), None);
-            assert_eq!(db.get_by_key("default", &key_collection_2).unwrap(), None);
-            assert_eq!(db.get_by_key("default", &key_index_2).unwrap(), None);
+            assert_eq!(db.get_by_key("metadata", &key_collection_1).unwrap(), None);
+            assert_eq!(db.get_by_key("metadata", &key_collection_2).unwrap(), None);
+            assert_eq!(db.get_by_key("metadata", &key_index_2).unwrap(), None);
 
             key_document.set_layer(1);
-            db.put("default", &key_document, &vec![0, 1, 0]).unwrap();
+            db.put("documents", &key_document, &vec![0, 1, 0]).unwrap();
             key_document.set_layer(2);
-            db.put("default", &key_document, &vec![0, 2, 0]).unwrap();
+            db.put("documents", &key_document, &vec![0, 2, 0]).unwrap();
 
             assert_eq!(
-                db.get_by_prefix("default", &vec![DOCUMENT]).unwrap(),
+                db.get_by_prefix("documents", &vec![DOCUMENT]).unwrap(),
                 vec![vec![0, 0, 0], vec![0, 1, 0], vec![0, 2, 0]]
             );
         }
         RocksDB::destroy(&db_options, "test_rocksdb.rdb");
     }
+
+    #[test]
+    fn test_migration() {
+        let db_name = "./build/migration.rdb";
+        let db_options;
+        {
+            // Earlier versions kept everything but the neighbors in "default"
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            for key_type in [DOCUMENT, VALUE, POSTINGS].iter() {
+                db.put("default", &key_for(*key_type), &vec![*key_type]).unwrap();
+            }
+        }
+        {
+            let db = RocksDB::init(db_name);
+            assert_eq!(db.get_by_key("documents", &key_for(DOCUMENT)).unwrap(), Some(vec![DOCUMENT]));
+            assert_eq!(db.get_by_key("vectors", &key_for(VALUE)).unwrap(), Some(vec![VALUE]));
+            assert_eq!(db.get_by_key("default", &key_for(POSTINGS)).unwrap(), Some(vec![POSTINGS]));
+            assert_eq!(db.get_by_key("default", &key_for(DOCUMENT)).unwrap(), None);
+            assert_eq!(db.get_by_key("default", &key_for(VALUE)).unwrap(), None);
+        }
+        RocksDB::destroy(&db_options, db_name);
+
+        fn key_for(key_type: u8) -> Key {
+            let mut key = Key::new();
+            key.set_type(key_type);
+            key.set_collection_id(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            key
+        }
+    }
+
+    #[test]
+    fn test_legacy_records() {
+        let db_name = "./build/legacy_records.rdb";
+        let db_options;
+        let collection_id = seahash::hash(b"legacy").to_be_bytes();
+        let field_id = seahash::hash(b"vector").to_be_bytes();
+        {
+            // Metadata as written before schemas, timestamps and rebuilds existed
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            let mut key = Key::new();
+            key.set_type(COLLECTION);
+            key.set_collection_id(&collection_id);
+            let collection = LegacyCollection {
+                collection_id: "legacy".to_owned(),
+                n_documents: 1,
+            };
+            db.put("default", &key, &bincode::serialize(&collection).unwrap()).unwrap();
+            key.set_type(INDEX);
+            key.set_field_id(&field_id);
+            let index = LegacyIndexDB {
+                field_id: "vector".to_owned(),
+                collection_id: "legacy".to_owned(),
+                metric: "euclidean".to_owned(),
+                buffer_size: 1,
+                dimension: 2,
+                k: 8,
+                entry_point: Some([1; 8]),
+                reverse_size: 1.0,
+                n_layers: 1,
+                n_elements: 1,
+            };
+            db.put("default", &key, &bincode::serialize(&index).unwrap()).unwrap();
+        }
+        {
+            let db = RocksDB::init(db_name);
+            let collections = db.get_collections().unwrap();
+            assert_eq!(collections.len(), 1);
+            assert_eq!((collections[0].collection_id.as_str(), collections[0].n_documents), ("legacy", 1));
+
+            let index = db.get_index(&collection_id, &field_id).unwrap().unwrap();
+            assert_eq!((index.entry_point, index.n_elements), (Some([1; 8]), 1));
+            assert_eq!(index.graph_hash, field_id);
+            let index_store = crate::index_store::init(&db).unwrap();
+            assert_eq!(index_store.read().unwrap().len(), 1);
+
+            // Migrating rewrote the metadata in the current layout
+            let mut key = Key::new();
+            key.set_type(COLLECTION);
+            key.set_collection_id(&collection_id);
+            let value = db.get_by_key("metadata", &key).unwrap().unwrap();
+            assert_eq!(bincode::deserialize::<Collection>(&value).unwrap(), collections[0]);
+            key.set_type(INDEX);
+            key.set_field_id(&field_id);
+            let value = db.get_by_key("metadata", &key).unwrap().unwrap();
+            assert_eq!(bincode::deserialize::<IndexDB>(&value).unwrap().graph_hash, field_id);
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+
+    #[test]
+    fn test_split_postings() {
+        let db_name = "./build/split_postings.rdb";
+        let db_options;
+        let collection_id = seahash::hash(b"legacy").to_be_bytes();
+        let field_id = seahash::hash(b"title").to_be_bytes();
+        let term_hash = seahash::hash(b"apple").to_be_bytes();
+        {
+            // All postings of a term in one value, as written by earlier versions
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            let mut key = Key::new();
+            key.set_type(LEGACY_POSTINGS);
+            key.set_collection_id(&collection_id);
+            key.set_field_id(&field_id);
+            key.set_document_id(&term_hash);
+            let postings: Postings = vec![([1; 8], 2), ([2; 8], 1)];
+            db.put("default", &key, &postings.to_binary()).unwrap();
+        }
+        {
+            let db = RocksDB::init(db_name);
+            assert_eq!(
+                db.get_postings(&collection_id, &term_id(&field_id, &term_hash)).unwrap(),
+                vec![([1; 8], 2), ([2; 8], 1)]
+            );
+            assert!(db.get_by_prefix("default", &vec![LEGACY_POSTINGS]).unwrap().is_empty());
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+
+    #[test]
+    fn test_approximate_sizes() {
+        assert_eq!(share_of_file(&[1, 0], &[1, 9], &[2], &[3]), 0.0);
+        assert_eq!(share_of_file(&[1, 0], &[1, 9], &[1], &[2]), 1.0);
+        assert_eq!(share_of_file(&[1, 0], &[1, 0x80], &[1, 0x40], &[2]), 0.5);
+        assert_eq!(share_of_file(&[1, 5], &[1, 5], &[1], &[2]), 1.0);
+
+        let db_name = "./build/approximate_sizes.rdb";
+        let db_options;
+        {
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            let key_for = |collection_id: &[u8; 8], document_id: u64| {
+                let mut key = Key::new();
+                key.set_type(DOCUMENT);
+                key.set_collection_id(collection_id);
+                key.set_document_id(&document_id.to_be_bytes());
+                key
+            };
+            for collection_id in [[1; 8], [2; 8]].iter() {
+                for document_id in 0..1000 {
+                    db.put("documents", &key_for(collection_id, document_id), &vec![0; 1000]).unwrap();
+                }
+            }
+            let prefixes: Vec<Vec<u8>> = [[1; 8], [2; 8], [3; 8]]
+                .iter()
+                .map(|collection_id| Prefix::new().prefix_type(DOCUMENT).collection(collection_id).finish())
+                .collect();
+            // Nothing is counted before the memtables are flushed
+            assert_eq!(db.get_approximate_sizes(&prefixes).unwrap(), vec![0, 0, 0]);
+
+            db.flush().unwrap();
+            let sizes = db.get_approximate_sizes(&prefixes).unwrap();
+            assert!(sizes[0] > 0 && sizes[1] > 0);
+            assert_eq!(sizes[2], 0);
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+
+    #[test]
+    fn test_delete_index() {
+        assert_eq!(prefix_end(&[1, 2, 3]), vec![1, 2, 4]);
+        assert_eq!(prefix_end(&[1, 0xFF, 0xFF]), vec![2]);
+        assert_eq!(prefix_end(&[0xFF]), vec![0xFF; 27]);
+
+        let db_name = "./build/delete_index.rdb";
+        let db_options;
+        {
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            let collection_id = &[1, 2, 3, 4, 5, 6, 7, 8];
+            let key_for = |key_type: u8, field_id: &[u8; 8]| {
+                let mut key = Key::new();
+                key.set_type(key_type);
+                key.set_collection_id(collection_id);
+                key.set_field_id(field_id);
+                key
+            };
+            let (field_id, other_field_id, graph_id) = (&[1; 8], &[0xFF; 8], &[2; 8]);
+            let keys = [
+                key_for(INDEX, field_id),
+                key_for(VALUE, field_id),
+                key_for(NEIGHBORS, graph_id),
+                key_for(REVERSE_NEIGHBORS, graph_id),
+                key_for(DOCUMENT, &[0; 8]),
+                key_for(INDEX, other_field_id),
+                key_for(VALUE, other_field_id),
+            ];
+            for key in keys.iter() {
+                db.put(column_family(key.get_type()), key, &vec![key.get_type()]).unwrap();
+            }
+
+            db.delete_index(collection_id, field_id, graph_id).unwrap();
+            db.compact_prefixes(&index_prefixes(collection_id, field_id, graph_id)).unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                let value = db.get_by_key(column_family(key.get_type()), key).unwrap();
+                assert_eq!(value.is_some(), i >= 4);
+            }
+
+            db.delete_collection(collection_id).unwrap();
+            for key in keys.iter() {
+                assert_eq!(db.get_by_key(column_family(key.get_type()), key).unwrap(), None);
+            }
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+
+    #[test]
+    fn test_corrupted_value() {
+        let db_name = "./build/corrupted_value.rdb";
+        let db_options;
+        {
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            let (intact_id, corrupted_id) = (&[1; 8], &[2; 8]);
+            db.insert_collection(intact_id, &Collection::new("intact")).unwrap();
+            let mut key = Key::new();
+            key.set_type(COLLECTION);
+            key.set_collection_id(corrupted_id);
+            db.put("metadata", &key, &vec![0xFF]).unwrap();
+
+            // Reading the corrupted entry fails, listing skips it
+            assert_eq!(db.get_collection(intact_id).unwrap().unwrap().collection_id, "intact");
+            match db.get_collection(corrupted_id) {
+                Err(Error::Corrupted { cf, key: corrupted_key }) => assert_eq!((cf.as_str(), corrupted_key), ("metadata", key.to_vec())),
+                result => panic!("expected a corrupted value, got {:?}", result),
+            }
+            let collections = db.get_collections().unwrap();
+            assert_eq!(collections.len(), 1);
+            assert_eq!(collections[0].collection_id, "intact");
+
+            key.set_type(DOCUMENT);
+            db.put("documents", &key, &bincode::serialize("{\"no\": \"id\"}").unwrap()).unwrap();
+            assert!(matches!(db.get_document(corrupted_id, &[0; 8]), Err(Error::Corrupted { .. })));
+            assert!(db.get_documents(corrupted_id).unwrap().is_empty());
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
 }
@@ -27,6 +27,50 @@ pub fn euclidean<T: Float>(a: &[T], b: &[T]) -> T {
     Float::sqrt(squared_euclidean(a, b))
 }
 
+/// Returns the cosine distance `1 - cos(a, b)`.
+/// The distance of a zero vector to any other vector is defined as 1.
+#[inline]
+pub fn cosine<T: Float>(a: &[T], b: &[T]) -> T {
+    debug_assert_eq!(a.len(), b.len());
+    let (dot, norm_a, norm_b) = a
+        .iter()
+        .zip(b.iter())
+        .fold((T::zero(), T::zero(), T::zero()), |(dot, norm_a, norm_b), (x, y)| {
+            (dot + (*x) * (*y), norm_a + (*x) * (*x), norm_b + (*y) * (*y))
+        });
+    if norm_a == T::zero() || norm_b == T::zero() {
+        return T::one();
+    }
+    T::one() - dot / (Float::sqrt(norm_a) * Float::sqrt(norm_b))
+}
+
+pub const EUCLIDEAN: &str = "euclidean";
+pub const COSINE: &str = "cosine";
+
+/// Largest squared norm of a vector. Below it the squared euclidean distance of two vectors can't overflow.
+pub const MAX_SQUARED_NORM: f64 = f64::MAX / 4.0;
+
+/// Checks that the distances of `vector` in `metric` are finite numbers, otherwise returns why not.
+/// Zero vectors are rejected for the cosine metric, as they have no direction.
+pub fn validate(vector: &[f64], metric: &str) -> Result<(), &'static str> {
+    if vector.iter().any(|x| !x.is_finite()) {
+        return Err("has components which are not finite numbers");
+    }
+    let squared_norm = vector.iter().fold(0.0, |norm, x| norm + x * x);
+    if squared_norm > MAX_SQUARED_NORM {
+        return Err("has components too large to compute distances");
+    }
+    if metric == COSINE && squared_norm == 0.0 {
+        return Err("is a zero vector, which has no cosine distance");
+    }
+    Ok(())
+}
+
+/// Returns true if `metric` names a supported distance metric.
+pub fn is_supported(metric: &str) -> bool {
+    metric == EUCLIDEAN || metric == COSINE
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +101,27 @@ mod tests {
         let distance: f64 = euclidean(&vector_a, &vector_b);
         assert!(approx_eq!(f64, 2.0, distance, ulps = 2));
     }
+
+    #[test]
+    fn test_cosine() {
+        let vector_a: [f64; 2] = [1.0, 0.0];
+        let vector_b: [f64; 2] = [0.0, 2.0];
+        assert!(approx_eq!(f64, 1.0, cosine(&vector_a, &vector_b), ulps = 2));
+        assert!(approx_eq!(f64, 0.0, cosine(&vector_a, &[3.0, 0.0]), ulps = 2));
+        assert!(approx_eq!(f64, 2.0, cosine(&vector_a, &[-1.0, 0.0]), ulps = 2));
+        assert!(approx_eq!(f64, 1.0, cosine(&vector_a, &[0.0, 0.0]), ulps = 2));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(&[1.0, -2.0], EUCLIDEAN), Ok(()));
+        assert_eq!(validate(&[0.0, 0.0], EUCLIDEAN), Ok(()));
+        assert!(validate(&[0.0, 0.0], COSINE).is_err());
+        assert!(validate(&[f64::NAN, 0.0], EUCLIDEAN).is_err());
+        assert!(validate(&[f64::INFINITY, 0.0], COSINE).is_err());
+
+        // Finite components whose distances overflow
+        assert!(validate(&[1e200, 0.0], EUCLIDEAN).is_err());
+        assert!(squared_euclidean(&[1e200, 0.0], &[-1e200, 0.0]).is_infinite());
+    }
 }
@@ -0,0 +1,52 @@
+pub mod service;
+
+use crate::api::types::State;
+use service::HiddbService;
+
+use futures::channel::oneshot;
+use futures::stream;
+use proto::hiddb_server::HiddbServer;
+use tokio::net::TcpListener;
+use tonic::transport::Server;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+pub mod proto {
+    tonic::include_proto!("hiddb");
+}
+
+/// Serves the gRPC API on `addr` from a separate thread.
+/// actix runs on tokio 0.2 while tonic needs tokio 1, so the server gets a runtime of its own.
+/// The address is bound before the thread starts, so failing to bind it is returned to the caller.
+/// Once `shutdown` fires the server stops accepting requests and the thread ends after in-flight requests finished.
+pub fn spawn(state: Arc<State>, addr: SocketAddr, shutdown: oneshot::Receiver<()>) -> io::Result<thread::JoinHandle<()>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+
+    thread::Builder::new().name("hiddb-grpc".to_owned()).spawn(move || {
+        runtime.block_on(async move {
+            let listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(error) => {
+                    log::error!("gRPC server on {} could not start: {}", addr, error);
+                    return;
+                }
+            };
+            let incoming = stream::unfold(listener, |listener| async move {
+                let connection = listener.accept().await.map(|(connection, _)| connection);
+                Some((connection, listener))
+            });
+            let server = Server::builder().add_service(HiddbServer::new(HiddbService::new(state)));
+            let signal = async {
+                let _ = shutdown.await;
+            };
+            if let Err(error) = server.serve_with_incoming_shutdown(incoming, signal).await {
+                log::error!("gRPC server on {} stopped: {}", addr, error);
+            }
+        })
+    })
+}
@@ -0,0 +1,582 @@
+use crate::api::types::State;
+use crate::auth::{self, ALL_COLLECTIONS};
+use crate::db::dbtypes::*;
+use crate::grpc::proto;
+use crate::grpc::proto::hiddb_server::Hiddb;
+use crate::hiddb::{collection, document, index, job, tenant};
+use crate::hnsw::Document;
+use crate::workers;
+
+use serde_json::{json, Map, Value};
+use tonic::{Request, Response, Status};
+
+use std::sync::Arc;
+
+/// gRPC frontend sharing `State` and the `hiddb` modules with the REST handlers
+pub struct HiddbService {
+    state: Arc<State>,
+}
+
+impl HiddbService {
+    pub fn new(state: Arc<State>) -> Self {
+        Self { state }
+    }
+
+    async fn ingest<F, T>(&self, work: F) -> Result<T, Status>
+    where
+        F: FnOnce(&State) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = self.state.clone();
+        self.state.workers.ingest(move || work(&state)).await.map_err(worker_status)
+    }
+
+    async fn query<F, T>(&self, work: F) -> Result<T, Status>
+    where
+        F: FnOnce(&State) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = self.state.clone();
+        self.state.workers.query(move || work(&state)).await.map_err(worker_status)
+    }
+
+    /// Same check as the REST auth middleware, with the bearer token taken from the `authorization` metadata.
+    /// Returns the tenant of the request, which is set with the `tenant` metadata.
+    /// Collection names with the separator are rejected, once qualified they would reach collections of other tenants.
+    async fn authorize<R>(&self, request: &Request<R>, collection_name: &str, permission: Permission) -> Result<String, Status> {
+        let tenant = match request.metadata().get("tenant") {
+            Some(value) => value.to_str().map_err(|_| Status::invalid_argument("invalid tenant"))?.to_owned(),
+            None => tenant::DEFAULT_TENANT.to_owned(),
+        };
+        if !tenant::is_valid_name(&tenant) {
+            return Err(Status::invalid_argument(format!("invalid tenant '{}'", tenant)));
+        }
+        if collection_name != ALL_COLLECTIONS && !tenant::is_valid_name(collection_name) {
+            return Err(Status::invalid_argument(format!("invalid collection name '{}'", collection_name)));
+        }
+        if !self.state.auth.is_enabled() {
+            return Ok(tenant);
+        }
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(auth::bearer_token)
+            .map(|token| token.to_owned());
+        let collection_name = collection_name.to_owned();
+        let name = tenant.clone();
+        self.query(move |state| state.auth.authorize(&state.db, token.as_deref(), &name, &collection_name, permission))
+            .await?
+            .map_err(auth_status)?;
+        Ok(tenant)
+    }
+}
+
+#[tonic::async_trait]
+impl Hiddb for HiddbService {
+    async fn list_collections(&self, request: Request<proto::ListCollectionsRequest>) -> Result<Response<proto::ListCollectionsResponse>, Status> {
+        let tenant = self.authorize(&request, ALL_COLLECTIONS, Permission::Read).await?;
+        let collections = self
+            .query(move |state| tenant::get_collections(&state.db, &tenant))
+            .await?
+            .map_err(|_| Status::internal("internal error"))?;
+        Ok(Response::new(proto::ListCollectionsResponse {
+            collections: collections.iter().map(collection_to_proto).collect(),
+        }))
+    }
+
+    async fn create_collection(&self, request: Request<proto::CreateCollectionRequest>) -> Result<Response<proto::Collection>, Status> {
+        let tenant = self.authorize(&request, ALL_COLLECTIONS, Permission::Admin).await?;
+        let request = request.into_inner();
+        if !tenant::is_valid_name(&request.collection_name) {
+            return Err(Status::invalid_argument(format!("invalid collection name '{}'", request.collection_name)));
+        }
+        let schema: Option<Schema> = match request.schema_json.as_str() {
+            "" => None,
+            schema => Some(serde_json::from_str(schema).map_err(|e| Status::invalid_argument(format!("invalid schema: {}", e)))?),
+        };
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let collection = self
+            .ingest(move |state| {
+                tenant::check_collection_quota(&state.db, &state.quota, &tenant)?;
+                collection::create_with_schema(&state.db, &state.index_store, &state.index_defaults, &collection_name, schema)
+            })
+            .await?
+            .map_err(collection_status)?;
+        Ok(Response::new(collection_to_proto(&collection)))
+    }
+
+    async fn get_collection(&self, request: Request<proto::CollectionRequest>) -> Result<Response<proto::Collection>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Read).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let collection = self
+            .query(move |state| collection::get(&state.db, &collection_name))
+            .await?
+            .map_err(collection_status)?;
+        Ok(Response::new(collection_to_proto(&collection)))
+    }
+
+    async fn delete_collection(&self, request: Request<proto::CollectionRequest>) -> Result<Response<proto::Collection>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Admin).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let collection = self
+            .ingest({
+                let collection_name = collection_name.clone();
+                move |state| collection::delete(&state.db, &collection_name, &state.index_store)
+            })
+            .await?
+            .map_err(collection_status)?;
+        State::schedule_compaction(&self.state, move |db| collection::compact(db, &collection_name));
+        Ok(Response::new(collection_to_proto(&collection)))
+    }
+
+    async fn list_indices(&self, request: Request<proto::CollectionRequest>) -> Result<Response<proto::ListIndicesResponse>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Read).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let indices = self
+            .query(move |state| index::get_all(&state.db, &collection_name))
+            .await?
+            .map_err(index_status)?;
+        Ok(Response::new(proto::ListIndicesResponse {
+            indices: indices.iter().map(index_to_proto).collect(),
+        }))
+    }
+
+    async fn create_index(&self, request: Request<proto::CreateIndexRequest>) -> Result<Response<proto::Index>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Admin).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let (index, job) = self
+            .ingest(move |state| job::create_index(state, &tenant, &collection_name, &request.field_name, request.dimension as usize))
+            .await?
+            .map_err(index_status)?;
+        let mut index = index_to_proto(&index);
+        if let Some(job) = job {
+            job::schedule(&self.state, &job.job_id);
+            index.backfill_job_id = job.job_id;
+        }
+        Ok(Response::new(index))
+    }
+
+    async fn get_index(&self, request: Request<proto::IndexRequest>) -> Result<Response<proto::Index>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Read).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let index = self
+            .query(move |state| index::get(&state.db, &collection_name, &request.field_name))
+            .await?
+            .map_err(index_status)?;
+        Ok(Response::new(index_to_proto(&index)))
+    }
+
+    async fn delete_index(&self, request: Request<proto::IndexRequest>) -> Result<Response<proto::Index>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Admin).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let index = self
+            .ingest(move |state| index::delete(&state.db, &state.index_store, &collection_name, &request.field_name))
+            .await?
+            .map_err(index_status)?;
+        let deleted = index.clone();
+        State::schedule_compaction(&self.state, move |db| index::compact(db, &deleted));
+        Ok(Response::new(index_to_proto(&index)))
+    }
+
+    async fn insert_documents(&self, request: Request<proto::InsertDocumentsRequest>) -> Result<Response<proto::InsertDocumentsResponse>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Write).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let documents = request
+            .documents
+            .into_iter()
+            .map(document_from_proto)
+            .collect::<Result<Vec<Value>, document::Error>>()
+            .map_err(document_status)?;
+        self.ingest(move |state| {
+            tenant::check_document_quota(&state.db, &state.quota, &tenant, documents.len())?;
+            document::insert(&state.db, &state.index_store, &collection_name, &documents)
+        })
+        .await?
+        .map_err(document_status)?;
+        Ok(Response::new(proto::InsertDocumentsResponse {}))
+    }
+
+    async fn get_document(&self, request: Request<proto::DocumentRequest>) -> Result<Response<proto::Document>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Read).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let (document, indices) = self
+            .query(move |state| {
+                let document = document::get_by_id(&state.db, &collection_name, &request.document_id)?;
+                let indices = index::get_all(&state.db, &collection_name).or(Err(document::Error::InternalError))?;
+                Ok((document, indices))
+            })
+            .await?
+            .map_err(document_status)?;
+        let vector_fields: Vec<String> = indices.into_iter().map(|index| index.field_id).collect();
+        Ok(Response::new(document_to_proto(document, &vector_fields)))
+    }
+
+    async fn delete_document(&self, request: Request<proto::DocumentRequest>) -> Result<Response<proto::Document>, Status> {
+        self.authorize(&request, &request.get_ref().collection_name, Permission::Write).await?;
+        // Same as the REST API, removing documents from the graph is not supported yet
+        Err(Status::unimplemented("deleting documents is not implemented"))
+    }
+
+    async fn search(&self, request: Request<proto::SearchRequest>) -> Result<Response<proto::SearchResponse>, Status> {
+        let tenant = self.authorize(&request, &request.get_ref().collection_name, Permission::Read).await?;
+        let request = request.into_inner();
+        let collection_name = tenant::qualify(&tenant, &request.collection_name);
+        let item = search_item(&request);
+        let results = self
+            .query(move |state| document::search_ann(&state.db, &state.index_store, &collection_name, &item))
+            .await?
+            .map_err(document_status)?;
+        Ok(Response::new(proto::SearchResponse {
+            results: results.into_iter().map(|ids| proto::Neighbors { ids }).collect(),
+        }))
+    }
+}
+
+fn collection_to_proto(collection: &Collection) -> proto::Collection {
+    proto::Collection {
+        collection_name: tenant::split(&collection.collection_id).1.to_owned(),
+        n_documents: collection.n_documents as u64,
+        created_at: collection.created_at,
+        updated_at: collection.updated_at,
+    }
+}
+
+fn index_to_proto(index: &IndexDB) -> proto::Index {
+    proto::Index {
+        collection_name: tenant::split(&index.collection_id).1.to_owned(),
+        field_name: index.field_id.clone(),
+        n_documents: index.n_elements,
+        distance_metric: match index.metric.as_str() {
+            "" => "euclidean".to_owned(),
+            metric => metric.to_owned(),
+        },
+        dimension: index.dimension as u64,
+        ..Default::default()
+    }
+}
+
+/// JSON document as accepted by `document::insert`
+fn document_from_proto(document: proto::Document) -> Result<Value, document::Error> {
+    let mut data = match document.payload_json.as_str() {
+        "" => Map::new(),
+        payload => match serde_json::from_str(payload) {
+            Ok(Value::Object(payload)) => payload,
+            _ => return Err(document::Error::InvalidInput),
+        },
+    };
+    data.insert("id".to_owned(), Value::String(document.id));
+    for (field, vector) in document.vectors {
+        let vector: Vec<f64> = vector.values.iter().map(|&x| x as f64).collect();
+        data.insert(field, json!(vector));
+    }
+    Ok(Value::Object(data))
+}
+
+/// Splits a stored document into packed vectors of `vector_fields` and the remaining payload
+fn document_to_proto(document: Document, vector_fields: &[String]) -> proto::Document {
+    let mut data = match document.data {
+        Value::Object(data) => data,
+        _ => Map::new(),
+    };
+    data.remove("id");
+
+    let mut vectors = std::collections::HashMap::new();
+    for field in vector_fields.iter() {
+        let values: Option<Vec<f32>> = data
+            .get(field)
+            .and_then(|v| v.as_array())
+            .and_then(|v| v.iter().map(|x| x.as_f64().map(|x| x as f32)).collect());
+        if let Some(values) = values {
+            data.remove(field);
+            vectors.insert(field.clone(), proto::Vector { values });
+        }
+    }
+
+    proto::Document {
+        id: document.id_user,
+        vectors,
+        payload_json: Value::Object(data).to_string(),
+    }
+}
+
+/// Search parameters in the JSON form of the REST API
+fn search_item(request: &proto::SearchRequest) -> Value {
+    let mut item = json!({ "field_name": request.field_name, "range": request.range });
+    if request.ids.is_empty() {
+        let vectors: Vec<Vec<f64>> = request
+            .vectors
+            .iter()
+            .map(|vector| vector.values.iter().map(|&x| x as f64).collect())
+            .collect();
+        item["vectors"] = json!(vectors);
+    } else {
+        item["ids"] = json!(request.ids);
+    }
+    if request.max_neighbors > 0 {
+        item["max_neighbors"] = json!(request.max_neighbors);
+    }
+    if request.max_results > 0 {
+        item["max_results"] = json!(request.max_results);
+    }
+    if let Some(radius) = &request.radius {
+        item["radius"] = json!(radius.value);
+    }
+    if let Some(min_score) = &request.min_score {
+        item["min_score"] = json!(min_score.value);
+    }
+    if let Some(diversity) = &request.diversity {
+        item["diversity"] = json!(diversity.value);
+    }
+    item
+}
+
+fn auth_status(error: auth::Error) -> Status {
+    match error {
+        auth::Error::MissingKey => Status::unauthenticated("missing API key"),
+        auth::Error::InvalidKey => Status::unauthenticated("invalid API key"),
+        auth::Error::Forbidden => Status::permission_denied("API key does not grant access to this resource"),
+        auth::Error::KeyDoesNotExist => Status::not_found("API key does not exist"),
+        auth::Error::InvalidInput => Status::invalid_argument("a key needs at least one scope with a collection name or '*'"),
+        auth::Error::InternalError => Status::internal("internal error"),
+    }
+}
+
+fn worker_status(error: workers::Error) -> Status {
+    match error {
+        workers::Error::Cancelled => Status::cancelled("request was cancelled"),
+        workers::Error::Panicked => Status::internal("internal error"),
+    }
+}
+
+fn collection_status(error: collection::Error) -> Status {
+    match error {
+        collection::Error::AlreadyExists => Status::already_exists("collection already exists"),
+        collection::Error::DoesNotExist => Status::not_found("collection does not exist"),
+        collection::Error::InvalidSchema(reason) => Status::invalid_argument(format!("invalid schema: {}", reason)),
+        collection::Error::QuotaExceeded { limit } => Status::resource_exhausted(format!("quota exceeded: at most {} collections per tenant", limit)),
+        collection::Error::InternalError => Status::internal("internal error"),
+    }
+}
+
+fn index_status(error: index::Error) -> Status {
+    match error {
+        index::Error::AlreadyExists => Status::already_exists("index already exists"),
+        index::Error::CollectionDoesNotExist => Status::not_found("collection does not exist"),
+        index::Error::IndexDoesNotExist => Status::not_found("index does not exist"),
+        index::Error::InvalidInput => Status::invalid_argument("k must be positive and m between 2 and 100"),
+        index::Error::RebuildInProgress => Status::failed_precondition("index is being rebuilt"),
+        index::Error::Cancelled => Status::cancelled("request was cancelled"),
+        index::Error::InternalError => Status::internal("internal error"),
+    }
+}
+
+fn document_status(error: document::Error) -> Status {
+    match error {
+        document::Error::CollectionDoesNotExist => Status::not_found("collection does not exist"),
+        document::Error::DocumentDoesNotExist => Status::not_found("document does not exist"),
+        document::Error::IndexDoesNotExist { field_name } => Status::not_found(format!("index '{}' does not exist", field_name)),
+        document::Error::AlreadyExists {
+            collection_name,
+            document_id,
+        } => Status::already_exists(format!(
+            "document '{}' already exists in collection '{}'",
+            document_id,
+            tenant::split(&collection_name).1
+        )),
+        document::Error::MissingFieldId => Status::invalid_argument("document should have an id"),
+        document::Error::InvalidInput => Status::invalid_argument("invalid input"),
+        document::Error::DimensionsNotEqual {
+            field,
+            index_dimension,
+            vector_dimension,
+        } => Status::invalid_argument(format!(
+            "vector in field '{}' has dimension {} but index has dimension {}",
+            field, vector_dimension, index_dimension
+        )),
+        document::Error::InvalidVector { field, reason } => Status::invalid_argument(format!("vector in field '{}' {}", field, reason)),
+        document::Error::SchemaViolation { document_id, field, reason } => {
+            Status::invalid_argument(format!("document '{}' violates schema: field '{}' {}", document_id, field, reason))
+        }
+        document::Error::BatchTooLarge { batch_size, max_batch_size } => Status::invalid_argument(format!(
+            "request contains {} queries but at most {} are allowed",
+            batch_size, max_batch_size
+        )),
+        document::Error::NotImplemented => Status::unimplemented("not implemented"),
+        document::Error::Cancelled => Status::cancelled("request was cancelled"),
+        document::Error::QuotaExceeded { limit } => Status::resource_exhausted(format!("quota exceeded: at most {} documents per tenant", limit)),
+        document::Error::InternalError => Status::internal("internal error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::RocksDB;
+
+    #[actix_rt::test]
+    async fn test_service() {
+        let db_options;
+        {
+            let db = RocksDB::init("./build/grpc_service.rdb");
+            db_options = db.options.clone();
+            let service = HiddbService::new(Arc::new(State::new(db).unwrap()));
+
+            let collection_name = "test_collection".to_owned();
+            service
+                .create_collection(Request::new(proto::CreateCollectionRequest {
+                    collection_name: collection_name.clone(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            service
+                .create_index(Request::new(proto::CreateIndexRequest {
+                    collection_name: collection_name.clone(),
+                    field_name: "vector".to_owned(),
+                    dimension: 2,
+                }))
+                .await
+                .unwrap();
+
+            let documents = (0..3)
+                .map(|i| proto::Document {
+                    id: i.to_string(),
+                    vectors: vec![("vector".to_owned(), proto::Vector { values: vec![i as f32, 0.0] })]
+                        .into_iter()
+                        .collect(),
+                    payload_json: json!({ "title": format!("document {}", i) }).to_string(),
+                })
+                .collect::<Vec<_>>();
+            service
+                .insert_documents(Request::new(proto::InsertDocumentsRequest {
+                    collection_name: collection_name.clone(),
+                    documents: documents.clone(),
+                }))
+                .await
+                .unwrap();
+
+            let document = service
+                .get_document(Request::new(proto::DocumentRequest {
+                    collection_name: collection_name.clone(),
+                    document_id: "1".to_owned(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(document, documents[1]);
+
+            let response = service
+                .search(Request::new(proto::SearchRequest {
+                    collection_name: collection_name.clone(),
+                    field_name: "vector".to_owned(),
+                    vectors: vec![proto::Vector { values: vec![2.1, 0.0] }],
+                    max_neighbors: 2,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.results[0].ids, vec!["2".to_owned(), "1".to_owned()]);
+
+            let status = service
+                .get_collection(Request::new(proto::CollectionRequest {
+                    collection_name: "does_not_exist".to_owned(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+
+            // Vector fields of a schema are indexed together with the collection
+            service
+                .create_collection(Request::new(proto::CreateCollectionRequest {
+                    collection_name: "with_schema".to_owned(),
+                    schema_json: json!({ "vectors": [{ "name": "vector", "dimension": 2 }] }).to_string(),
+                }))
+                .await
+                .unwrap();
+            let index = service
+                .get_index(Request::new(proto::IndexRequest {
+                    collection_name: "with_schema".to_owned(),
+                    field_name: "vector".to_owned(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(index.dimension, 2);
+            let status = service
+                .create_collection(Request::new(proto::CreateCollectionRequest {
+                    collection_name: "invalid_schema".to_owned(),
+                    schema_json: "[".to_owned(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+            // Qualified names can't be used to reach collections of other tenants
+            let mut request = Request::new(proto::CreateCollectionRequest {
+                collection_name: collection_name.clone(),
+                ..Default::default()
+            });
+            request.metadata_mut().insert("tenant", "other".parse().unwrap());
+            service.create_collection(request).await.unwrap();
+            let status = service
+                .get_collection(Request::new(proto::CollectionRequest {
+                    collection_name: "other/test_collection".to_owned(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            let status = service
+                .delete_collection(Request::new(proto::CollectionRequest {
+                    collection_name: "other/test_collection".to_owned(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            let status = service
+                .create_collection(Request::new(proto::CreateCollectionRequest {
+                    collection_name: "other/coll".to_owned(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+            let mut request = Request::new(proto::CollectionRequest {
+                collection_name: collection_name.clone(),
+            });
+            request.metadata_mut().insert("tenant", "other".parse().unwrap());
+            assert_eq!(
+                service.get_collection(request).await.unwrap().into_inner().collection_name,
+                collection_name
+            );
+
+            // Errors name collections without their tenant
+            let insert = || {
+                let mut request = Request::new(proto::InsertDocumentsRequest {
+                    collection_name: collection_name.clone(),
+                    documents: vec![proto::Document {
+                        id: "a".to_owned(),
+                        ..Default::default()
+                    }],
+                });
+                request.metadata_mut().insert("tenant", "other".parse().unwrap());
+                service.insert_documents(request)
+            };
+            insert().await.unwrap();
+            let status = insert().await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::AlreadyExists);
+            assert_eq!(status.message(), "document 'a' already exists in collection 'test_collection'");
+        }
+        RocksDB::destroy(&db_options, "./build/grpc_service.rdb");
+    }
+}
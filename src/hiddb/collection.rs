@@ -1,86 +1,195 @@
 use crate::api::types::*;
+use crate::config::IndexConfig;
 use crate::db::dbtypes::*;
 use crate::hnsw::key::*;
 
-use crate::db::RocksDB;
+use crate::db::{collection_prefixes, RocksDB};
 
 use seahash::hash;
 
 use crate::hnsw::index::get_index_hash;
+use crate::hnsw::{Index, IndexBuilder};
+
+use crate::hiddb::{schema, tenant};
+
+use std::sync::Mutex;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     AlreadyExists,
     InternalError,
     DoesNotExist,
+    InvalidSchema(String),
+    QuotaExceeded { limit: usize },
 }
 
-pub fn get_all(db: &RocksDB) -> Result<Vec<Collection>, Error> {
-    let prefix = Prefix::new().prefix_type(COLLECTION).finish();
-    let collections = db.get_by_prefix("default", &prefix).or(Err(Error::InternalError))?;
-    let collections: Vec<Collection> = collections
-        .iter()
-        .map(|c| {
-            let collection = Collection::from_binary(c);
-            let collection_hash = hash(collection.collection_id.as_bytes()).to_be_bytes();
+impl From<tenant::Error> for Error {
+    fn from(error: tenant::Error) -> Self {
+        match error {
+            tenant::Error::QuotaExceeded { limit, .. } => Error::QuotaExceeded { limit },
+            tenant::Error::InternalError => Error::InternalError,
+        }
+    }
+}
 
-            db.get_collection(&collection_hash).unwrap()
-        })
-        .collect();
-    Ok(collections)
+pub fn get_all(db: &RocksDB) -> Result<Vec<Collection>, Error> {
+    db.get_collections().or(Err(Error::InternalError))
 }
 
 pub fn create(db: &RocksDB, name: &str) -> Result<Collection, Error> {
     let collection = Collection::new(name);
     let collection_hash = hash(name.as_bytes()).to_be_bytes();
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {
             return Err(Error::AlreadyExists);
         }
         _ => {}
     };
-    db.insert_collection(&collection_hash, &collection).unwrap();
+    db.insert_collection(&collection_hash, &collection).or(Err(Error::InternalError))?;
+
+    Ok(collection)
+}
+
+/// Creates a collection and the indices declared in its schema, with `defaults` for the parameters they don't set.
+/// The collection and its indices are written in a single batch.
+pub fn create_with_schema(
+    db: &RocksDB,
+    index_store: &IndexStore,
+    defaults: &IndexConfig,
+    name: &str,
+    schema: Option<Schema>,
+) -> Result<Collection, Error> {
+    if let Some(schema) = &schema {
+        schema::validate_schema(schema).map_err(Error::InvalidSchema)?;
+    }
+
+    // Holding the write lock from the existence check until the collection is written
+    // lets only one of several concurrent creates of the same name succeed
+    let mut index_store = index_store.write().or(Err(Error::InternalError))?;
+    let schema = match schema {
+        Some(schema) => schema,
+        _ => return create(db, name),
+    };
+
+    let collection_hash = hash(name.as_bytes()).to_be_bytes();
+    if db.get_collection(&collection_hash).or(Err(Error::InternalError))?.is_some() {
+        return Err(Error::AlreadyExists);
+    }
+
+    let indices: Vec<Index> = schema
+        .vectors
+        .iter()
+        .map(|vector| {
+            let mut builder = IndexBuilder::with_defaults(defaults)
+                .set_collection(name)
+                .set_field(&vector.name)
+                .set_distance_metric(&vector.metric)
+                .set_dimension(vector.dimension);
+            if let Some(k) = vector.k {
+                builder = builder.set_k(k);
+            }
+            if let Some(m) = vector.m {
+                builder = builder.set_m(m);
+            }
+            builder.build()
+        })
+        .collect();
+
+    if indices.iter().any(|index| index_store.contains_key(&index.index_hash)) {
+        return Err(Error::InternalError);
+    }
+
+    let collection = Collection::new(name).with_schema(schema);
+    db.insert_collection_with_indices(&collection_hash, &collection, &indices)
+        .or(Err(Error::InternalError))?;
+    for index in indices {
+        index_store.insert(index.index_hash, Mutex::new(index));
+    }
 
     Ok(collection)
 }
 
 pub fn get(db: &RocksDB, name: &str) -> Result<Collection, Error> {
-    // TODO: include more info like n_indices, createdAt, deletedAt, ...
     let collection_id = name;
     let collection_hash = hash(collection_id.as_bytes()).to_be_bytes();
 
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(collection) => Ok(collection),
         _ => return Err(Error::DoesNotExist),
     }
 }
 
+pub struct CollectionStatistics {
+    pub collection: Collection,
+    pub indices: Vec<IndexStatistics>,
+    pub approximate_bytes: u64,
+}
+
+pub struct IndexStatistics {
+    pub index: IndexDB,
+    pub approximate_bytes: u64,
+}
+
+/// Collection with its indices and the approximate number of bytes they occupy
+pub fn get_statistics(db: &RocksDB, name: &str) -> Result<CollectionStatistics, Error> {
+    let collection = get(db, name)?;
+    let collection_hash = hash(name.as_bytes()).to_be_bytes();
+
+    // Vectors and graphs of each index, followed by the remaining keys of the collection
+    let indices = db.get_indices_in_collection(&collection_hash).or(Err(Error::InternalError))?;
+    let mut prefixes = Vec::new();
+    for index in indices.iter() {
+        let field_hash = hash(index.field_id.as_bytes()).to_be_bytes();
+        prefixes.push(Prefix::new().prefix_type(VALUE).collection(&collection_hash).field(&field_hash).finish());
+        for prefix_type in [NEIGHBORS, REVERSE_NEIGHBORS].iter() {
+            prefixes.push(Prefix::new().prefix_type(*prefix_type).collection(&collection_hash).field(&index.graph_hash).finish());
+        }
+    }
+    for prefix_type in [COLLECTION, INDEX, DOCUMENT, TEXT_INDEX, POSTINGS, FIELD_LENGTH].iter() {
+        prefixes.push(Prefix::new().prefix_type(*prefix_type).collection(&collection_hash).finish());
+    }
+    let sizes = db.get_approximate_sizes(&prefixes).or(Err(Error::InternalError))?;
+
+    let approximate_bytes = sizes.iter().sum();
+    let indices = indices
+        .into_iter()
+        .zip(sizes.chunks(3))
+        .map(|(index, sizes)| IndexStatistics {
+            index,
+            approximate_bytes: sizes.iter().sum(),
+        })
+        .collect();
+
+    Ok(CollectionStatistics {
+        collection,
+        indices,
+        approximate_bytes,
+    })
+}
+
 pub fn delete(db: &RocksDB, name: &str, index_store: &IndexStore) -> Result<Collection, Error> {
     let collection_hash = hash(name.as_bytes()).to_be_bytes();
-    let collection = match db.get_collection(&collection_hash) {
+    let collection = match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(collection) => collection,
         _ => return Err(Error::DoesNotExist),
     };
 
     // Delete associated indices from index_store
     let indices = db
-        .get_by_prefix_key_value("default", &Prefix::new().prefix_type(INDEX).collection(&collection_hash).finish())
-        .unwrap();
-    for (key, _) in indices.iter() {
-        let key = get_index_hash(key.get_collection_id(), key.get_field_id());
-        index_store.write().unwrap().remove(&key).unwrap();
+        .get_keys_by_prefix("metadata", &Prefix::new().prefix_type(INDEX).collection(&collection_hash).finish())
+        .or(Err(Error::InternalError))?;
+    let mut index_store = index_store.write().or(Err(Error::InternalError))?;
+    for key in indices.iter() {
+        index_store.remove(&get_index_hash(key.get_collection_id(), key.get_field_id()));
     }
 
-    // Delete associated indices from db
-    db.delete_by_prefix("default", &Prefix::new().prefix_type(INDEX).collection(&collection_hash).finish())
-        .unwrap();
-
-    // Delete associated documents from db
-    db.delete_by_prefix("default", &Prefix::new().prefix_type(DOCUMENT).collection(&collection_hash).finish())
-        .unwrap();
+    db.delete_collection(&collection_hash).or(Err(Error::InternalError))?;
+    Ok(collection)
+}
 
-    match db.delete_collection(&collection_hash) {
-        Ok(_) => Ok(collection),
-        _ => Err(Error::InternalError),
+/// Reclaims the space of a deleted collection. Space left behind is reclaimed by regular compactions, so failures are only logged.
+pub fn compact(db: &RocksDB, name: &str) {
+    if let Err(error) = db.compact_prefixes(&collection_prefixes(&hash(name.as_bytes()).to_be_bytes())) {
+        log::warn!("Could not compact collection '{}': {}", name, error);
     }
 }
@@ -2,15 +2,37 @@ use crate::db::RocksDB;
 
 use crate::api::types::*;
 use crate::db::dbtypes::*;
+use crate::distance;
 use crate::hnsw::key::*;
-use crate::hnsw::{Document, IndexBuilder};
+use crate::hnsw::{self, Document, Index, IndexBuilder};
 
 use crate::hnsw::index::get_index_hash;
 
+use crate::hiddb::fusion::{fuse, Fusion, Ranking};
+use crate::hiddb::schema;
+use crate::hiddb::text_index;
+use crate::hiddb::tenant;
+use crate::workers::Cancellation;
+
+use rayon::prelude::*;
 use seahash::hash;
 
 use serde_json::Value;
 
+use std::collections::{HashMap, HashSet};
+
+/// Upper limit of query vectors or ids in a single search request
+pub const MAX_BATCH_SIZE: usize = 256;
+
+/// Upper limit of hits per group in a grouped search
+pub const MAX_GROUP_SIZE: usize = 100;
+
+/// Upper limit of `max_neighbors` and `max_results`, keeps the candidate counts derived from them in range
+pub const MAX_NEIGHBORS: usize = 10_000;
+
+/// Number of candidates per requested result that are re-ranked when `diversity` is set
+const MMR_CANDIDATES_PER_RESULT: usize = 4;
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     AlreadyExists {
@@ -31,18 +53,52 @@ pub enum Error {
         index_dimension: usize,
         vector_dimension: usize,
     },
+    /// Vector whose distances can't be ordered, see `distance::validate`
+    InvalidVector {
+        field: String,
+        reason: String,
+    },
+    SchemaViolation {
+        document_id: String,
+        field: String,
+        reason: String,
+    },
+    BatchTooLarge {
+        batch_size: usize,
+        max_batch_size: usize,
+    },
+    Cancelled,
+    QuotaExceeded {
+        limit: usize,
+    },
+}
+
+impl From<tenant::Error> for Error {
+    fn from(error: tenant::Error) -> Self {
+        match error {
+            tenant::Error::QuotaExceeded { limit, .. } => Error::QuotaExceeded { limit },
+            tenant::Error::InternalError => Error::InternalError,
+        }
+    }
+}
+
+impl From<hnsw::Error> for Error {
+    fn from(error: hnsw::Error) -> Self {
+        log::error!("{}", error);
+        Error::InternalError
+    }
 }
 
 pub fn get_by_id(db: &RocksDB, collection_name: &str, document_id: &str) -> Result<Document, Error> {
     let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
     let document_hash = hash(document_id.as_bytes()).to_be_bytes();
 
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {}
         _ => return Err(Error::CollectionDoesNotExist),
     };
 
-    match db.get_document(&collection_hash, &document_hash) {
+    match db.get_document(&collection_hash, &document_hash).or(Err(Error::InternalError))? {
         Some(document) => Ok(document),
         _ => return Err(Error::DocumentDoesNotExist),
     }
@@ -53,14 +109,49 @@ pub fn insert(db: &RocksDB, index_store: &IndexStore, collection_name: &str, doc
     let collection_hash = hash(&collection_name.as_bytes()).to_be_bytes();
 
     let index_store = index_store.read().or(Err(Error::InternalError))?;
-    let indexed_fields = index_store.values().map(|index| index.lock().unwrap().field_id.clone());
+    let indexed_fields = index_store
+        .values()
+        .map(|index| index.lock().map(|index| index.field_id.clone()).or(Err(Error::InternalError)))
+        .collect::<Result<Vec<String>, Error>>()?;
 
     // Only possible if collection exists
-    match db.get_collection(&collection_hash) {
-        Some(_) => {}
+    let collection = match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(collection) => collection,
         _ => return Err(Error::CollectionDoesNotExist),
     };
 
+    // Validate all documents before anything is written
+    if let Some(schema) = &collection.schema {
+        for document in documents {
+            if let Err(violation) = schema::validate_document(schema, document) {
+                return Err(Error::SchemaViolation {
+                    document_id: document["id"].as_str().unwrap_or("").to_owned(),
+                    field: violation.field,
+                    reason: violation.reason,
+                });
+            }
+        }
+    }
+
+    // Vectors are checked for all indices of the collection before anything is written
+    for index in index_store.values() {
+        let index = index.lock().or(Err(Error::InternalError))?;
+        if index.collection_hash != collection_hash {
+            continue;
+        }
+        for document in documents {
+            match document.get(&index.field_id) {
+                None => continue,
+                Some(value) => parse_vector(value, &index)?,
+            };
+        }
+    }
+
+    // Inserts are not transactional, so they can only be cancelled before anything is written
+    if Cancellation::current().is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+
     // Check if any document already exists in collection
     // TODO: don't do this check and instead update element and don't count size
     for document in documents {
@@ -69,7 +160,7 @@ pub fn insert(db: &RocksDB, index_store: &IndexStore, collection_name: &str, doc
             _ => return Err(Error::MissingFieldId),
         };
         let document_hash = hash(&document_id.as_bytes()).to_be_bytes();
-        match db.get_document(&collection_hash, &document_hash) {
+        match db.get_document(&collection_hash, &document_hash).or(Err(Error::InternalError))? {
             Some(_) => {
                 return Err(Error::AlreadyExists {
                     collection_name: collection_name.to_owned(),
@@ -82,13 +173,19 @@ pub fn insert(db: &RocksDB, index_store: &IndexStore, collection_name: &str, doc
                 db.insert_document(&collection_hash, &entry.id_hash, &entry)
                     .or(Err(Error::InternalError))?;
 
-                let mut collection = db.get_collection(&collection_hash).unwrap();
+                let mut collection = db
+                    .get_collection(&collection_hash)
+                    .or(Err(Error::InternalError))?
+                    .ok_or(Error::CollectionDoesNotExist)?;
                 collection.n_documents += 1;
-                db.insert_collection(&collection_hash, &collection).unwrap();
+                collection.touch();
+                db.insert_collection(&collection_hash, &collection).or(Err(Error::InternalError))?;
             }
         };
     }
 
+    text_index::index_documents(db, &collection_hash, documents).or(Err(Error::InternalError))?;
+
     for field_id in indexed_fields {
         for document in documents.iter() {
             match document.get(&field_id) {
@@ -99,31 +196,17 @@ pub fn insert(db: &RocksDB, index_store: &IndexStore, collection_name: &str, doc
 
                     match index_store.get(&index_hash) {
                         Some(index) => {
-                            let mut index = index.lock().unwrap();
-                            let vector = match document[&field_id].as_array() {
-                                Some(vec) => vec,
-                                _ => return Err(Error::InvalidInput),
-                            };
-                            let vector: Vec<f64> = vector
-                                .iter()
-                                .map(|v| v.as_f64().ok_or(Error::InvalidInput))
-                                .collect::<Result<Vec<f64>, Error>>()?;
-
-                            if index.dimension != vector.len() {
-                                return Err(Error::DimensionsNotEqual {
-                                    field: field_id,
-                                    index_dimension: index.dimension,
-                                    vector_dimension: vector.len(),
-                                });
-                            }
-
+                            let mut index = index.lock().or(Err(Error::InternalError))?;
                             let document_id = match document["id"].as_str() {
                                 Some(doc_id) => doc_id.to_owned(),
                                 _ => return Err(Error::InvalidInput),
                             };
                             let entry = Document::new(document_id, document.clone());
 
-                            index.insert(&db, &entry);
+                            // Added already if a backfill of the index got to the document first
+                            if !index.contains(&db, &entry.id_hash)? {
+                                index.insert(&db, &entry)?;
+                            }
                         }
                         _ => {}
                     }
@@ -135,6 +218,13 @@ pub fn insert(db: &RocksDB, index_store: &IndexStore, collection_name: &str, doc
 }
 
 pub fn search_ann(db: &RocksDB, index_store: &IndexStore, collection_name: &str, item: &Value) -> Result<Vec<Vec<String>>, Error> {
+    if item.get("fields").is_some() {
+        return search_multi(db, index_store, collection_name, item);
+    }
+    if item.get("text").is_some() {
+        return search_hybrid(db, index_store, collection_name, item);
+    }
+
     let field_id = match item.get("field_name") {
         Some(field_id) => match field_id.as_str() {
             Some(f) => f,
@@ -148,90 +238,39 @@ pub fn search_ann(db: &RocksDB, index_store: &IndexStore, collection_name: &str,
     let index_hash = get_index_hash(collection_hash, field_hash);
 
     // Only possible if collection exists
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {}
         _ => return Err(Error::CollectionDoesNotExist),
     }
 
-    let index_store = index_store.read().or(Err(Error::InternalError))?;
-    let index = index_store.get(&index_hash);
+    let index = match index_store.read().or(Err(Error::InternalError))?.get(&index_hash) {
+        Some(index) => Some(index.lock().or(Err(Error::InternalError))?.clone()),
+        _ => None,
+    };
     match index {
         Some(index) => {
-            let max_neighbors = match item.get("max_neighbors") {
-                Some(max_neighbors) => match max_neighbors.as_u64() {
-                    Some(max_n) => max_n as usize,
-                    _ => return Err(Error::InvalidInput),
-                },
-                _ => 20 as usize,
-            };
+            let options = SearchOptions::from_value(item)?;
 
-            let index = index.lock().or(Err(Error::InternalError))?;
-            let data: Vec<Vec<[u8; 8]>> = match (item.get("vectors"), item.get("ids")) {
-                (Some(vectors), _) => {
-                    let vectors: Vec<Vec<f64>> = match vectors.as_array() {
-                        Some(vecs) => vecs
-                            .iter()
-                            .map(|vector| match vector.as_array() {
-                                Some(vec) => vec
-                                    .iter()
-                                    .map(|x| x.as_f64().ok_or(Error::InvalidInput))
-                                    .collect::<Result<Vec<f64>, Error>>(),
-                                _ => return Err(Error::InvalidInput),
-                            })
-                            .collect::<Result<Vec<Vec<f64>>, Error>>()?,
-                        _ => return Err(Error::InvalidInput),
-                    };
-
-                    for vector in vectors.iter() {
-                        if index.dimension != vector.len() {
-                            return Err(Error::DimensionsNotEqual {
-                                field: field_id.to_owned(),
-                                index_dimension: index.dimension,
-                                vector_dimension: vector.len(),
-                            });
-                        }
-                    }
-                    vectors.iter().map(|vector| index.knn_search(db, &vector, max_neighbors)).collect()
-                }
-                (_, Some(ids)) => {
-                    let mut data = Vec::new();
-
-                    let ids = match ids.as_array() {
-                        Some(i) => i,
-                        _ => return Err(Error::InvalidInput),
-                    };
-                    for id in ids.iter() {
-                        let id_user = match id.as_str() {
-                            Some(id) => id.as_bytes(),
-                            _ => return Err(Error::InvalidInput),
-                        };
-                        let id_hash = hash(id_user).to_be_bytes();
-
-                        let vector = match db.get_document(&collection_hash, &id_hash) {
-                            Some(document) => document.get_field_vector(&index.field_id).clone(),
-                            _ => {
-                                return Err(Error::InvalidInput);
-                            }
-                        };
-                        data.push(index.knn_search(&db, &vector, max_neighbors));
+            let vectors = query_vectors(db, &collection_hash, &index, item)?;
+
+            // Vectors are searched in parallel on the query pool running this request, collect keeps them in request order.
+            // No lock may be held here: a thread waiting for the parallel iterator runs other queued requests.
+            let index = &index;
+            let cancellation = Cancellation::current();
+            let data: Vec<Vec<(f64, [u8; 8])>> = vectors
+                .par_iter()
+                .map(|vector| {
+                    if cancellation.is_cancelled() {
+                        return Err(Error::Cancelled);
                     }
-                    data
-                }
-                (_, _) => {
-                    return Err(Error::InvalidInput);
-                }
-            };
+                    search_vector(db, index, vector, &options)
+                })
+                .collect::<Result<_, Error>>()?;
 
-            // Get id_user from id_hash
-            // TODO: do this more efficiently!
             let data: Vec<Vec<String>> = data
                 .iter()
-                .map(|knn| {
-                    knn.iter()
-                        .map(|id_hash| db.get_document(&collection_hash, &id_hash).unwrap().id_user.clone())
-                        .collect()
-                })
-                .collect();
+                .map(|knn| to_user_ids(db, &collection_hash, knn))
+                .collect::<Result<_, Error>>()?;
             return Ok(data);
         }
         _ => {
@@ -242,4 +281,628 @@ pub fn search_ann(db: &RocksDB, index_store: &IndexStore, collection_name: &str,
     }
 }
 
+/// Hits sharing the same value of the `group_by` field, nearest first
+#[derive(Debug, PartialEq)]
+pub struct Group {
+    pub key: Value,
+    pub ids: Vec<String>,
+}
+
+/// Searches like `search_ann` but nests the hits by the value of the payload field `group_by`.
+/// The candidate set is doubled until `max_neighbors` groups hold `group_size` (default 1) hits each
+/// or the index is exhausted. Documents without the field are skipped.
+/// Multi-field and hybrid searches fuse rankings instead of distances, so `fields` and `text` are rejected.
+pub fn search_grouped(db: &RocksDB, index_store: &IndexStore, collection_name: &str, item: &Value) -> Result<Vec<Vec<Group>>, Error> {
+    if item.get("fields").is_some() || item.get("text").is_some() {
+        return Err(Error::InvalidInput);
+    }
+    let group_by = item.get("group_by").and_then(|g| g.as_str()).ok_or(Error::InvalidInput)?;
+    let group_size = get_usize(item, "group_size")?.unwrap_or(1);
+    if group_size == 0 || group_size > MAX_GROUP_SIZE {
+        return Err(Error::InvalidInput);
+    }
+
+    let field_id = item.get("field_name").and_then(|f| f.as_str()).ok_or(Error::InvalidInput)?;
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_id.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let options = SearchOptions::from_value(item)?;
+    // Range search and diversification choose hits themselves and can't be combined with grouping
+    if options.range || options.diversity.is_some() {
+        return Err(Error::InvalidInput);
+    }
+
+    // A copy of the metadata is searched, see `search_ann`
+    let index_hash = get_index_hash(collection_hash, field_hash);
+    let index = match index_store.read().or(Err(Error::InternalError))?.get(&index_hash) {
+        Some(index) => index.lock().or(Err(Error::InternalError))?.clone(),
+        _ => {
+            return Err(Error::IndexDoesNotExist {
+                field_name: field_id.to_owned(),
+            })
+        }
+    };
+    let vectors = query_vectors(db, &collection_hash, &index, item)?;
+
+    let index = &index;
+    let threshold = options.threshold(index);
+    let cancellation = Cancellation::current();
+    // Like in `search_ann`, the vectors are spread over the query pool running this request
+    vectors
+        .par_iter()
+        .map(|vector| {
+            if cancellation.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            search_groups(db, index, vector, group_by, group_size, options.max_neighbors, threshold)
+        })
+        .collect()
+}
+
+fn search_groups(
+    db: &RocksDB,
+    index: &Index,
+    vector: &Vec<f64>,
+    group_by: &str,
+    group_size: usize,
+    n_groups: usize,
+    threshold: Option<f64>,
+) -> Result<Vec<Group>, Error> {
+    let mut documents: HashMap<[u8; 8], Document> = HashMap::new();
+    let mut ef = n_groups * group_size;
+    loop {
+        let candidates = index.knn_search_with_distances(db, vector, ef)?;
+        let exhausted = candidates.len() < ef || matches!((threshold, candidates.last()), (Some(t), Some(c)) if c.0 > t);
+
+        let mut groups: Vec<Group> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (distance, id) in candidates.iter() {
+            if matches!(threshold, Some(t) if *distance > t) {
+                break;
+            }
+            if !documents.contains_key(id) {
+                let document = db
+                    .get_document(&index.collection_hash, id)
+                    .or(Err(Error::InternalError))?
+                    .ok_or(Error::InternalError)?;
+                documents.insert(*id, document);
+            }
+            let document = &documents[id];
+            let key = match document.data.get(group_by) {
+                Some(Value::Null) | None => continue,
+                Some(key) => key,
+            };
+
+            match positions.get(&key.to_string()) {
+                Some(&position) if groups[position].ids.len() < group_size => {
+                    groups[position].ids.push(document.id_user.clone());
+                }
+                Some(_) => {}
+                None if groups.len() < n_groups => {
+                    positions.insert(key.to_string(), groups.len());
+                    groups.push(Group {
+                        key: key.clone(),
+                        ids: vec![document.id_user.clone()],
+                    });
+                }
+                None => {}
+            }
+        }
+
+        let filled = groups.len() == n_groups && groups.iter().all(|group| group.ids.len() == group_size);
+        if filled || exhausted || ef >= index.n_elements as usize {
+            return Ok(groups);
+        }
+        ef *= 2;
+    }
+}
+
+/// Query vectors of a search request, given either as `vectors` or as `ids` of stored documents
+fn query_vectors(db: &RocksDB, collection_hash: &[u8; 8], index: &Index, item: &Value) -> Result<Vec<Vec<f64>>, Error> {
+    let vectors = match (item.get("vectors"), item.get("ids")) {
+        (Some(vectors), _) => {
+            let vectors = vectors.as_array().ok_or(Error::InvalidInput)?;
+            check_batch_size(vectors.len())?;
+            vectors
+                .iter()
+                .map(|vector| parse_vector(vector, index))
+                .collect::<Result<Vec<Vec<f64>>, Error>>()?
+        }
+        (_, Some(ids)) => {
+            let ids = ids.as_array().ok_or(Error::InvalidInput)?;
+            check_batch_size(ids.len())?;
+
+            let mut vectors = Vec::new();
+            for id in ids.iter() {
+                let id_user = match id.as_str() {
+                    Some(id) => id.as_bytes(),
+                    _ => return Err(Error::InvalidInput),
+                };
+                let id_hash = hash(id_user).to_be_bytes();
+
+                match db.get_document(collection_hash, &id_hash).or(Err(Error::InternalError))? {
+                    Some(document) => vectors.push(document.get_field_vector(&index.field_id).ok_or(Error::InvalidInput)?),
+                    _ => {
+                        return Err(Error::InvalidInput);
+                    }
+                };
+            }
+            vectors
+        }
+        (_, _) => {
+            return Err(Error::InvalidInput);
+        }
+    };
+    Ok(vectors)
+}
+
+/// Parses a vector given for the field of `index`.
+/// Rejects vectors of another dimension and vectors the graph can't order by distance.
+fn parse_vector(value: &Value, index: &Index) -> Result<Vec<f64>, Error> {
+    let vector = value
+        .as_array()
+        .ok_or(Error::InvalidInput)?
+        .iter()
+        .map(|x| x.as_f64().ok_or(Error::InvalidInput))
+        .collect::<Result<Vec<f64>, Error>>()?;
+    if index.dimension != vector.len() {
+        return Err(Error::DimensionsNotEqual {
+            field: index.field_id.clone(),
+            index_dimension: index.dimension,
+            vector_dimension: vector.len(),
+        });
+    }
+    if let Err(reason) = distance::validate(&vector, &index.distance_metric) {
+        return Err(Error::InvalidVector {
+            field: index.field_id.clone(),
+            reason: reason.to_owned(),
+        });
+    }
+    Ok(vector)
+}
+
+/// Searches several vector fields at once and fuses the results into a single ranking.
+/// Expects `fields` as a list of `{"field_name", "vector", "weight"}` and an optional `fusion`
+/// of "weighted_sum" (default) or "rrf". An optional `text` query is fused in as well, see `search_hybrid`.
+pub fn search_multi(db: &RocksDB, index_store: &IndexStore, collection_name: &str, item: &Value) -> Result<Vec<Vec<String>>, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let options = SearchOptions::from_value(item)?;
+    let fusion = match item.get("fusion") {
+        Some(fusion) => fusion.as_str().and_then(Fusion::from_name).ok_or(Error::InvalidInput)?,
+        _ => Fusion::WeightedSum,
+    };
+    let fields = match item["fields"].as_array() {
+        Some(fields) if !fields.is_empty() => fields,
+        _ => return Err(Error::InvalidInput),
+    };
+
+    // Fetch more candidates per field than requested so fusion can reorder them
+    let field_options = SearchOptions {
+        max_neighbors: options.max_neighbors * 2,
+        ..options
+    };
+
+    let index_store = index_store.read().or(Err(Error::InternalError))?;
+    let mut rankings: Vec<(f64, Ranking)> = Vec::new();
+    for field in fields.iter() {
+        let field_id = field.get("field_name").and_then(|f| f.as_str()).ok_or(Error::InvalidInput)?;
+        let weight = get_f64(field, "weight")?.unwrap_or(1.0);
+        let vector = field.get("vector").ok_or(Error::InvalidInput)?;
+
+        let field_hash = hash(field_id.as_bytes()).to_be_bytes();
+        let index = match index_store.get(&get_index_hash(collection_hash, field_hash)) {
+            Some(index) => index.lock().or(Err(Error::InternalError))?,
+            _ => {
+                return Err(Error::IndexDoesNotExist {
+                    field_name: field_id.to_owned(),
+                })
+            }
+        };
+        let vector = parse_vector(vector, &index)?;
+
+        let ranking = search_vector(db, &index, &vector, &field_options)?
+            .iter()
+            .map(|&(distance, id)| (index.score(distance), id))
+            .collect();
+        rankings.push((weight, ranking));
+    }
+    if let Some(text) = item.get("text") {
+        rankings.push(text_ranking(db, &collection_hash, text, field_options.max_neighbors)?);
+    }
+
+    let fused = fuse(&rankings, fusion, options.max_neighbors);
+    Ok(vec![to_user_ids(db, &collection_hash, &fused)?])
+}
+
+/// Combines a BM25 keyword query with ANN search.
+/// Expects `text` as `{"field_name", "query", "weight"}` of a field with a text index. With `field_name`
+/// and `vectors` every vector yields one list fused with the keyword ranking, otherwise only the keyword
+/// ranking is returned. `fusion` is "rrf" (default) or "weighted_sum"; for the latter BM25 scores are
+/// divided by the best score so they are on the same scale as vector scores.
+pub fn search_hybrid(db: &RocksDB, index_store: &IndexStore, collection_name: &str, item: &Value) -> Result<Vec<Vec<String>>, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let options = SearchOptions::from_value(item)?;
+    let fusion = match item.get("fusion") {
+        Some(fusion) => fusion.as_str().and_then(Fusion::from_name).ok_or(Error::InvalidInput)?,
+        _ => Fusion::ReciprocalRank,
+    };
+
+    // Fetch more candidates per ranking than requested so fusion can reorder them
+    let field_options = SearchOptions {
+        max_neighbors: options.max_neighbors * 2,
+        ..options
+    };
+    let text = text_ranking(db, &collection_hash, &item["text"], field_options.max_neighbors)?;
+
+    let (field_id, vectors) = match (item.get("field_name"), item.get("vectors")) {
+        (Some(field_id), Some(vectors)) => (
+            field_id.as_str().ok_or(Error::InvalidInput)?,
+            vectors.as_array().ok_or(Error::InvalidInput)?,
+        ),
+        (None, None) => {
+            let mut ranking = text.1;
+            ranking.truncate(options.max_neighbors);
+            return Ok(vec![to_user_ids(db, &collection_hash, &ranking)?]);
+        }
+        _ => return Err(Error::InvalidInput),
+    };
+
+    check_batch_size(vectors.len())?;
+
+    let field_hash = hash(field_id.as_bytes()).to_be_bytes();
+    let index_store = index_store.read().or(Err(Error::InternalError))?;
+    let index = match index_store.get(&get_index_hash(collection_hash, field_hash)) {
+        Some(index) => index.lock().or(Err(Error::InternalError))?,
+        _ => {
+            return Err(Error::IndexDoesNotExist {
+                field_name: field_id.to_owned(),
+            })
+        }
+    };
+
+    let mut data = Vec::new();
+    for vector in vectors.iter() {
+        let vector = parse_vector(vector, &index)?;
+
+        let ranking: Ranking = search_vector(db, &index, &vector, &field_options)?
+            .iter()
+            .map(|&(distance, id)| (index.score(distance), id))
+            .collect();
+        let fused = fuse(&[(1.0, ranking), text.clone()], fusion, options.max_neighbors);
+        data.push(to_user_ids(db, &collection_hash, &fused)?);
+    }
+    Ok(data)
+}
+
+/// BM25 ranking for a `{"field_name", "query", "weight"}` object, scores normalized to [0, 1]
+fn text_ranking(db: &RocksDB, collection_hash: &[u8; 8], text: &Value, k: usize) -> Result<(f64, Ranking), Error> {
+    let field_id = text.get("field_name").and_then(|f| f.as_str()).ok_or(Error::InvalidInput)?;
+    let query = text.get("query").and_then(|q| q.as_str()).ok_or(Error::InvalidInput)?;
+    let weight = get_f64(text, "weight")?.unwrap_or(1.0);
+
+    let mut ranking = match text_index::search(db, collection_hash, field_id, query, k) {
+        Ok(ranking) => ranking,
+        Err(text_index::Error::IndexDoesNotExist) => {
+            return Err(Error::IndexDoesNotExist {
+                field_name: field_id.to_owned(),
+            })
+        }
+        _ => return Err(Error::InternalError),
+    };
+    if let Some(&(best, _)) = ranking.first() {
+        if best > 0.0 {
+            ranking.iter_mut().for_each(|entry| entry.0 /= best);
+        }
+    }
+    Ok((weight, ranking))
+}
+
+/// How `recommend` turns example documents into results
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Strategy {
+    /// Search once around `mean(positive) + negative_weight * (mean(positive) - mean(negative))`
+    AverageVector,
+    /// Search around every positive and rank candidates by their best positive score
+    /// minus `negative_weight` times their best negative score
+    BestScore,
+}
+
+impl Strategy {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "average_vector" => Some(Strategy::AverageVector),
+            "best_score" => Some(Strategy::BestScore),
+            _ => None,
+        }
+    }
+}
+
+/// Recommends documents similar to the `positive` and dissimilar to the `negative` example ids.
+/// Examples are never part of the result. Accepts `strategy`, `negative_weight` and the options of `search_ann`.
+pub fn recommend(db: &RocksDB, index_store: &IndexStore, collection_name: &str, item: &Value) -> Result<Vec<String>, Error> {
+    let field_id = item.get("field_name").and_then(|f| f.as_str()).ok_or(Error::InvalidInput)?;
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_id.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let options = SearchOptions::from_value(item)?;
+    let strategy = match item.get("strategy") {
+        Some(strategy) => strategy.as_str().and_then(Strategy::from_name).ok_or(Error::InvalidInput)?,
+        _ => Strategy::AverageVector,
+    };
+    let negative_weight = get_f64(item, "negative_weight")?.unwrap_or(1.0);
+    let positive = get_ids(item, "positive")?;
+    let negative = get_ids(item, "negative")?;
+    if positive.is_empty() {
+        return Err(Error::InvalidInput);
+    }
+
+    let index_store = index_store.read().or(Err(Error::InternalError))?;
+    let index = match index_store.get(&get_index_hash(collection_hash, field_hash)) {
+        Some(index) => index.lock().or(Err(Error::InternalError))?,
+        _ => {
+            return Err(Error::IndexDoesNotExist {
+                field_name: field_id.to_owned(),
+            })
+        }
+    };
+
+    let example_vectors = |ids: &[[u8; 8]]| -> Result<Vec<Vec<f64>>, Error> {
+        ids.iter()
+            .map(|id| {
+                if db.get_document(&collection_hash, id).or(Err(Error::InternalError))?.is_none() {
+                    return Err(Error::DocumentDoesNotExist);
+                }
+                // Documents without a vector in this field are not in the index
+                db.get_value(&collection_hash, &field_hash, id)
+                    .or(Err(Error::InternalError))?
+                    .ok_or(Error::InvalidInput)
+            })
+            .collect()
+    };
+    let positive_vectors = example_vectors(&positive)?;
+    let negative_vectors = example_vectors(&negative)?;
+
+    // Examples are removed afterwards, so fetch enough candidates to make up for them
+    let n_examples = positive.len() + negative.len();
+    let candidate_options = SearchOptions {
+        max_neighbors: options.max_neighbors + n_examples,
+        max_results: options.max_results.map(|max_results| max_results + n_examples),
+        ..options
+    };
+    let limit = match (options.range, options.max_results) {
+        (true, Some(max_results)) => max_results,
+        (true, None) => usize::MAX,
+        (false, _) => options.max_neighbors,
+    };
+    let is_example = |id: &[u8; 8]| positive.contains(id) || negative.contains(id);
+
+    let ranking: Ranking = match strategy {
+        Strategy::AverageVector => {
+            let mut query = mean(&positive_vectors);
+            if !negative_vectors.is_empty() {
+                let negative_mean = mean(&negative_vectors);
+                for (x, n) in query.iter_mut().zip(negative_mean.iter()) {
+                    *x += negative_weight * (*x - n);
+                }
+            }
+            // Examples can cancel each other out, e.g. into a zero vector
+            if let Err(reason) = distance::validate(&query, &index.distance_metric) {
+                return Err(Error::InvalidVector {
+                    field: index.field_id.clone(),
+                    reason: reason.to_owned(),
+                });
+            }
+            search_vector(db, &index, &query, &candidate_options)?
+                .into_iter()
+                .filter(|(_, id)| !is_example(id))
+                .take(limit)
+                .collect()
+        }
+        Strategy::BestScore => {
+            let mut candidates: Vec<[u8; 8]> = Vec::new();
+            let mut seen: HashSet<[u8; 8]> = HashSet::new();
+            for vector in positive_vectors.iter() {
+                for (_, id) in search_vector(db, &index, vector, &candidate_options)? {
+                    if !is_example(&id) && seen.insert(id) {
+                        candidates.push(id);
+                    }
+                }
+            }
+
+            let best_score = |candidate: &Vec<f64>, examples: &[Vec<f64>]| {
+                examples
+                    .iter()
+                    .map(|example| index.score(index.distance(candidate, example)))
+                    .fold(None, |best: Option<f64>, score| Some(best.map_or(score, |best| best.max(score))))
+            };
+            let mut ranking: Ranking = Vec::new();
+            for id in candidates {
+                let vector = db
+                    .get_value(&collection_hash, &field_hash, &id)
+                    .or(Err(Error::InternalError))?
+                    .ok_or(Error::InternalError)?;
+                let score = best_score(&vector, &positive_vectors).unwrap_or(0.0)
+                    - negative_weight * best_score(&vector, &negative_vectors).unwrap_or(0.0);
+                ranking.push((score, id));
+            }
+            ranking.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+            ranking.truncate(limit);
+            ranking
+        }
+    };
+
+    to_user_ids(db, &collection_hash, &ranking)
+}
+
+fn get_ids(item: &Value, key: &str) -> Result<Vec<[u8; 8]>, Error> {
+    match item.get(key) {
+        Some(ids) => ids
+            .as_array()
+            .ok_or(Error::InvalidInput)?
+            .iter()
+            .map(|id| id.as_str().map(|id| hash(id.as_bytes()).to_be_bytes()).ok_or(Error::InvalidInput))
+            .collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn mean(vectors: &[Vec<f64>]) -> Vec<f64> {
+    let mut mean = vec![0.0; vectors[0].len()];
+    for vector in vectors.iter() {
+        for (m, x) in mean.iter_mut().zip(vector.iter()) {
+            *m += x / vectors.len() as f64;
+        }
+    }
+    mean
+}
+
+/// Get id_user from id_hash
+// TODO: do this more efficiently!
+fn to_user_ids(db: &RocksDB, collection_hash: &[u8; 8], neighbors: &[(f64, [u8; 8])]) -> Result<Vec<String>, Error> {
+    neighbors
+        .iter()
+        .map(
+            |(_, id_hash)| match db.get_document(collection_hash, id_hash).or(Err(Error::InternalError))? {
+                Some(document) => Ok(document.id_user),
+                None => Err(Error::InternalError),
+            },
+        )
+        .collect()
+}
+
+/// Parameters shared by all search modes
+#[derive(Clone, Copy)]
+struct SearchOptions {
+    max_neighbors: usize,
+    radius: Option<f64>,
+    min_score: Option<f64>,
+    range: bool, // return everything within the threshold instead of the top max_neighbors
+    max_results: Option<usize>,
+    diversity: Option<f64>, // 0 ranks by similarity only, 1 by dissimilarity to previous results only
+}
+
+impl SearchOptions {
+    fn from_value(item: &Value) -> Result<Self, Error> {
+        let options = Self {
+            max_neighbors: get_usize(item, "max_neighbors")?.unwrap_or(20),
+            radius: get_f64(item, "radius")?,
+            min_score: get_f64(item, "min_score")?,
+            range: get_bool(item, "range")?.unwrap_or(false),
+            max_results: get_usize(item, "max_results")?,
+            diversity: get_f64(item, "diversity")?,
+        };
+        if matches!(options.radius, Some(radius) if radius < 0.0) {
+            return Err(Error::InvalidInput);
+        }
+        if options.max_neighbors > MAX_NEIGHBORS || matches!(options.max_results, Some(max_results) if max_results > MAX_NEIGHBORS) {
+            return Err(Error::InvalidInput);
+        }
+        if options.range && options.radius.is_none() && options.min_score.is_none() {
+            return Err(Error::InvalidInput);
+        }
+        if matches!(options.diversity, Some(diversity) if !(0.0..=1.0).contains(&diversity)) {
+            return Err(Error::InvalidInput);
+        }
+        Ok(options)
+    }
+
+    /// Distance bound implied by `radius` and `min_score` in the metric of `index`
+    fn threshold(&self, index: &Index) -> Option<f64> {
+        match (self.radius, self.min_score.map(|min_score| index.distance_bound(min_score))) {
+            (Some(radius), Some(bound)) => Some(radius.min(bound)),
+            (radius, bound) => radius.or(bound),
+        }
+    }
+}
+
+fn search_vector(db: &RocksDB, index: &Index, vector: &Vec<f64>, options: &SearchOptions) -> Result<Vec<(f64, [u8; 8])>, Error> {
+    let diversity = match options.diversity {
+        Some(diversity) if diversity > 0.0 => diversity,
+        _ => return search_candidates(db, index, vector, options),
+    };
+
+    // Over-fetch so that MMR has similar but redundant candidates to skip
+    let candidate_options = SearchOptions {
+        max_neighbors: options.max_neighbors * MMR_CANDIDATES_PER_RESULT,
+        max_results: options.max_results.map(|max_results| max_results * MMR_CANDIDATES_PER_RESULT),
+        ..*options
+    };
+    let candidates = search_candidates(db, index, vector, &candidate_options)?;
+    let k = if options.range {
+        options.max_results.unwrap_or(candidates.len())
+    } else {
+        options.max_neighbors
+    };
+    Ok(index.rerank_mmr(db, &candidates, k, diversity)?)
+}
+
+fn search_candidates(db: &RocksDB, index: &Index, vector: &Vec<f64>, options: &SearchOptions) -> Result<Vec<(f64, [u8; 8])>, Error> {
+    Ok(match options.threshold(index) {
+        Some(threshold) if options.range => index.range_search(db, vector, threshold, options.max_results)?,
+        Some(threshold) => index
+            .knn_search_with_distances(db, vector, options.max_neighbors)?
+            .into_iter()
+            .filter(|neighbor| neighbor.0 <= threshold)
+            .collect(),
+        None => index.knn_search_with_distances(db, vector, options.max_neighbors)?,
+    })
+}
+
+fn check_batch_size(batch_size: usize) -> Result<(), Error> {
+    if batch_size > MAX_BATCH_SIZE {
+        return Err(Error::BatchTooLarge {
+            batch_size,
+            max_batch_size: MAX_BATCH_SIZE,
+        });
+    }
+    Ok(())
+}
+
+fn get_usize(item: &Value, key: &str) -> Result<Option<usize>, Error> {
+    match item.get(key) {
+        Some(value) => value.as_u64().map(|v| Some(v as usize)).ok_or(Error::InvalidInput),
+        _ => Ok(None),
+    }
+}
+
+fn get_f64(item: &Value, key: &str) -> Result<Option<f64>, Error> {
+    match item.get(key) {
+        Some(value) => value.as_f64().map(Some).ok_or(Error::InvalidInput),
+        _ => Ok(None),
+    }
+}
+
+fn get_bool(item: &Value, key: &str) -> Result<Option<bool>, Error> {
+    match item.get(key) {
+        Some(value) => value.as_bool().map(Some).ok_or(Error::InvalidInput),
+        _ => Ok(None),
+    }
+}
+
 // pub fn delete(db: &RocksDB) -> Result<Value, Error> {}
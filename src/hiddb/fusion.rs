@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+/// Constant of reciprocal rank fusion, see Cormack et al. (2009)
+pub const RRF_K: f64 = 60.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fusion {
+    WeightedSum,
+    ReciprocalRank,
+}
+
+impl Fusion {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "weighted_sum" => Some(Fusion::WeightedSum),
+            "rrf" => Some(Fusion::ReciprocalRank),
+            _ => None,
+        }
+    }
+}
+
+/// List of (score, id) pairs sorted by descending score
+pub type Ranking = Vec<(f64, [u8; 8])>;
+
+/// Combines several weighted rankings into one.
+/// Returns the `k` best (fused score, id) pairs, best first.
+pub fn fuse(rankings: &[(f64, Ranking)], fusion: Fusion, k: usize) -> Ranking {
+    let mut positions: HashMap<[u8; 8], usize> = HashMap::new();
+    let mut fused: Vec<(f64, [u8; 8])> = Vec::new();
+
+    for (weight, ranking) in rankings.iter() {
+        for (rank, &(score, id)) in ranking.iter().enumerate() {
+            let contribution = match fusion {
+                Fusion::WeightedSum => weight * score,
+                Fusion::ReciprocalRank => weight / (RRF_K + (rank + 1) as f64),
+            };
+            match positions.get(&id) {
+                Some(&position) => fused[position].0 += contribution,
+                None => {
+                    positions.insert(id, fused.len());
+                    fused.push((contribution, id));
+                }
+            }
+        }
+    }
+
+    // Stable sort keeps the order of first appearance for equal scores
+    fused.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    fused.truncate(k);
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse() {
+        let a = [0u8; 8];
+        let b = [1u8; 8];
+        let c = [2u8; 8];
+        let rankings = vec![(1.0, vec![(0.9, a), (0.5, b)]), (2.0, vec![(0.8, b), (0.1, c)])];
+
+        let fused = fuse(&rankings, Fusion::WeightedSum, 3);
+        assert_eq!(fused.iter().map(|x| x.1).collect::<Vec<_>>(), vec![b, a, c]);
+        assert!((fused[0].0 - 2.1).abs() < 1e-12);
+
+        let fused = fuse(&rankings, Fusion::ReciprocalRank, 2);
+        assert_eq!(fused.iter().map(|x| x.1).collect::<Vec<_>>(), vec![b, c]);
+
+        assert_eq!(Fusion::from_name("rrf"), Some(Fusion::ReciprocalRank));
+        assert_eq!(Fusion::from_name("max"), None);
+    }
+}
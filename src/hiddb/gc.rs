@@ -0,0 +1,196 @@
+use crate::api::types::IndexStore;
+use crate::db::dbtypes::{BinaryConverison, Collection, IndexDB, TextIndexDB};
+use crate::db::{column_family, RocksDB};
+use crate::hnsw::key::*;
+use crate::hnsw::Index;
+use crate::utils;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Owners can't be told apart from orphans while a metadata record can't be decoded, so nothing is deleted
+    CorruptedMetadata,
+    InternalError,
+}
+
+/// Keys that belonged to a collection, index or text index that no longer exists
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub checked_keys: u64,
+    pub orphaned_keys: u64,
+    /// Bytes of the orphaned keys and values before compression
+    pub reclaimed_bytes: u64,
+}
+
+/// Key types with the length of the part naming their owner, and the type of the owner.
+/// NEIGHBORS stands for the graphs, which are named by the INDEX metadata and the indices being rebuilt in `IndexStore`.
+/// POSTINGS are named by their term instead of the field, so only their collection is known.
+const OWNERS: [(u8, usize, u8); 8] = [
+    (INDEX, 9, COLLECTION),
+    (TEXT_INDEX, 9, COLLECTION),
+    (DOCUMENT, 9, COLLECTION),
+    (VALUE, 17, INDEX),
+    (NEIGHBORS, 17, NEIGHBORS),
+    (REVERSE_NEIGHBORS, 17, NEIGHBORS),
+    (POSTINGS, 9, COLLECTION),
+    (FIELD_LENGTH, 17, TEXT_INDEX),
+];
+
+/// Finds keys whose owner no longer exists and deletes them, unless `dry_run` is set.
+/// Such keys are left behind by crashes during deletes, interrupted rebuilds and versions that did not delete VALUE keys.
+/// Owners are checked again before deleting while `index_store` is locked, so collections and indices created during the scan keep their keys.
+pub fn collect(db: &RocksDB, index_store: &IndexStore, dry_run: bool) -> Result<Report, Error> {
+    let live = {
+        let index_store = index_store.read().or(Err(Error::InternalError))?;
+        owners(db, &index_store)?
+    };
+
+    let mut report = Report::default();
+    let mut orphans: Vec<(&'static str, Vec<u8>, u64, u64)> = Vec::new();
+    for (key_type, length, owner_type) in OWNERS.iter() {
+        let cf = column_family(*key_type);
+        let groups = db.get_sizes_by_group(cf, &vec![*key_type], *length).or(Err(Error::InternalError))?;
+        for (group, keys, size) in groups {
+            report.checked_keys += keys;
+            if !live.contains(&(*owner_type, group[1..].to_vec())) {
+                orphans.push((cf, group, keys, size));
+            }
+        }
+    }
+
+    let index_store = index_store.write().or(Err(Error::InternalError))?;
+    let live = owners(db, &index_store)?;
+    let orphans: Vec<(&str, Vec<u8>)> = orphans
+        .into_iter()
+        .filter(|(_, group, _, _)| {
+            !OWNERS
+                .iter()
+                .any(|(key_type, _, owner_type)| group[0] == *key_type && live.contains(&(*owner_type, group[1..].to_vec())))
+        })
+        .map(|(cf, group, keys, size)| {
+            report.orphaned_keys += keys;
+            report.reclaimed_bytes += size;
+            (cf, group)
+        })
+        .collect();
+    if dry_run || orphans.is_empty() {
+        return Ok(report);
+    }
+    db.delete_by_prefixes(&orphans).or(Err(Error::InternalError))?;
+    drop(index_store);
+
+    // The keys are deleted already, so regular compactions reclaim their space if this fails
+    if let Err(error) = db.compact_prefixes(&orphans) {
+        log::warn!("Could not compact orphaned keys: {}", error);
+    }
+    Ok(report)
+}
+
+/// Owners that exist, by type and id. Ids are the collection hash, followed by the field or graph hash.
+/// Fails if any metadata record can't be decoded, as the graph it names would look orphaned.
+fn owners(db: &RocksDB, index_store: &HashMap<[u8; 16], Mutex<Index>>) -> Result<HashSet<(u8, Vec<u8>)>, Error> {
+    let mut owners = HashSet::new();
+    let graph = |collection_hash: &[u8], graph_hash: &[u8; 8]| (NEIGHBORS, [collection_hash, &graph_hash[..]].concat());
+    for (key_type, length) in [(COLLECTION, 9), (INDEX, 17), (TEXT_INDEX, 17)].iter() {
+        let records = db.get_by_prefix_key_value("metadata", &vec![*key_type]).or(Err(Error::InternalError))?;
+        for (key, value) in records {
+            let key = key.to_vec();
+            let decoded = match *key_type {
+                COLLECTION => Collection::from_binary(&value).map(|_| ()),
+                INDEX => IndexDB::from_binary(&value).map(|index| {
+                    owners.insert(graph(&key[1..9], &index.graph_hash));
+                }),
+                _ => TextIndexDB::from_binary(&value).map(|_| ()),
+            };
+            if decoded.is_err() {
+                log::error!("Not collecting garbage, metadata {} is corrupted", utils::to_hex(&key));
+                return Err(Error::CorruptedMetadata);
+            }
+            owners.insert((*key_type, key[1..*length].to_vec()));
+        }
+    }
+
+    // Graphs being rebuilt are only named in memory until the rebuild finishes
+    for index in index_store.values() {
+        let index = index.lock().or(Err(Error::InternalError))?;
+        if let Some(shadow) = &index.shadow {
+            owners.insert(graph(&shadow.collection_hash, &shadow.graph_hash));
+        }
+    }
+    Ok(owners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IndexConfig;
+    use crate::hiddb;
+    use crate::index_store;
+
+    use seahash::hash;
+    use serde_json::json;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_gc() {
+        let db_name = "./build/gc.rdb";
+        let db_options;
+        {
+            let db = &RocksDB::init(db_name);
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+            for collection_name in ["movies", "books"].iter() {
+                hiddb::collection::create(db, collection_name).unwrap();
+                hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+                let documents = (0..10).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 1.0]})).collect();
+                hiddb::document::insert(db, &index_store, collection_name, &documents).unwrap();
+            }
+            assert_eq!(collect(db, &index_store, false).unwrap().orphaned_keys, 0);
+
+            // Left behind by a crash halfway through deleting "books"
+            let books_hash = hash("books".as_bytes()).to_be_bytes();
+            let mut key = Key::new();
+            key.set_type(COLLECTION);
+            key.set_collection_id(&books_hash);
+            db.delete("metadata", &key).unwrap();
+            key.set_type(INDEX);
+            key.set_field_id(&hash("vector".as_bytes()).to_be_bytes());
+            db.delete("metadata", &key).unwrap();
+            let index_store = index_store::init(db).unwrap();
+
+            let dry_run = collect(db, &index_store, true).unwrap();
+            assert!(dry_run.orphaned_keys >= 30);
+            assert!(dry_run.reclaimed_bytes > 0);
+            assert_eq!(collect(db, &index_store, false).unwrap(), dry_run);
+
+            let report = collect(db, &index_store, false).unwrap();
+            assert_eq!((report.orphaned_keys, report.reclaimed_bytes), (0, 0));
+            assert!(db
+                .get_by_prefix("vectors", &Prefix::new().prefix_type(VALUE).collection(&books_hash).finish())
+                .unwrap()
+                .is_empty());
+            assert_eq!(hiddb::collection::get(db, "movies").unwrap().n_documents, 10);
+            assert_eq!(hiddb::index::verify(db, &index_store, "movies", "vector").unwrap().violations.len(), 0);
+
+            // Graphs are owned by the INDEX metadata, even of indices missing in `IndexStore`
+            let empty_store: IndexStore = RwLock::new(HashMap::new());
+            assert_eq!(collect(db, &empty_store, true).unwrap().orphaned_keys, 0);
+
+            // Nothing is deleted while a metadata record can't be decoded
+            let movies_hash = hash("movies".as_bytes()).to_be_bytes();
+            let mut key = Key::new();
+            key.set_type(INDEX);
+            key.set_collection_id(&movies_hash);
+            key.set_field_id(&hash("vector".as_bytes()).to_be_bytes());
+            let value = db.get_by_key("metadata", &key).unwrap().unwrap();
+            db.put("metadata", &key, &vec![0xFF]).unwrap();
+            assert_eq!(collect(db, &index_store, false), Err(Error::CorruptedMetadata));
+            db.put("metadata", &key, &value).unwrap();
+            assert_eq!(collect(db, &index_store, false).unwrap().orphaned_keys, 0);
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+}
@@ -1,15 +1,21 @@
-use crate::db::RocksDB;
+use crate::db::{index_prefixes, RocksDB};
 
 use crate::api::types::*;
+use crate::config::IndexConfig;
 use crate::db::dbtypes::*;
+use crate::distance;
 use crate::hnsw::key::*;
 
 use crate::hnsw::index::get_index_hash;
+use crate::hnsw::verify::Verification;
 
 use seahash::hash;
 
 use crate::hnsw::key::*;
-use crate::hnsw::{Document, IndexBuilder};
+use crate::hnsw::{Document, Index, IndexBuilder};
+use crate::utils;
+use crate::workers::Cancellation;
+use std::convert::TryInto;
 use std::sync::{Mutex, RwLock};
 
 #[derive(Debug, PartialEq)]
@@ -18,7 +24,9 @@ pub enum Error {
     InternalError,
     CollectionDoesNotExist,
     IndexDoesNotExist,
-    NotImplemented,
+    InvalidInput,
+    RebuildInProgress,
+    Cancelled,
 }
 
 pub fn get_all(db: &RocksDB, collection_name: &str) -> Result<Vec<IndexDB>, Error> {
@@ -26,18 +34,24 @@ pub fn get_all(db: &RocksDB, collection_name: &str) -> Result<Vec<IndexDB>, Erro
     let collection_hash = hash(collection_id.as_bytes()).to_be_bytes();
 
     // Only possible if collection exists
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {}
         _ => return Err(Error::CollectionDoesNotExist),
     }
 
-    let indices = db.get_indices_in_collection(&collection_hash).or(Err(Error::InternalError))?;
-    let indices: Vec<IndexDB> = indices.iter().map(|c| IndexDB::from_binary(c)).collect();
-
-    Ok(indices)
+    db.get_indices_in_collection(&collection_hash).or(Err(Error::InternalError))
 }
 
-pub fn create(db: &RocksDB, index_store: &IndexStore, collection_name: &str, field_name: &str, dimension: usize) -> Result<IndexDB, Error> {
+/// Creates an index. Parameters other than the dimension are taken from `defaults`.
+/// Documents already in the collection are only searchable through the index once `backfill` added them.
+pub fn create(
+    db: &RocksDB,
+    index_store: &IndexStore,
+    defaults: &IndexConfig,
+    collection_name: &str,
+    field_name: &str,
+    dimension: usize,
+) -> Result<IndexDB, Error> {
     let collection_id = collection_name;
     let field_id = field_name;
 
@@ -46,19 +60,12 @@ pub fn create(db: &RocksDB, index_store: &IndexStore, collection_name: &str, fie
     let index_hash = get_index_hash(collection_hash, field_hash);
 
     // Only possible if collection exists
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {}
         _ => return Err(Error::CollectionDoesNotExist),
     }
-    // Creation of indices only possible if no documents present
-    // TODO: implement this feature
-    let prefix = Prefix::new().prefix_type(DOCUMENT).collection(&collection_hash).finish();
-    let documents = db.get_by_prefix("default", &prefix).or(Err(Error::InternalError))?;
-    if documents.len() > 0 {
-        return Err(Error::NotImplemented);
-    }
 
-    match db.get_index(&collection_hash, &field_hash) {
+    match db.get_index(&collection_hash, &field_hash).or(Err(Error::InternalError))? {
         Some(_) => return Err(Error::AlreadyExists),
         _ => {
             match index_store.read().or(Err(Error::InternalError))?.get(&index_hash) {
@@ -68,18 +75,22 @@ pub fn create(db: &RocksDB, index_store: &IndexStore, collection_name: &str, fie
         }
     };
 
-    let index = IndexBuilder::new()
+    let index = IndexBuilder::with_defaults(defaults)
         .set_collection(collection_id)
         .set_field(field_id)
         .set_dimension(dimension)
         .build();
 
     db.insert_index(&collection_hash, &field_hash, &index).or(Err(Error::InternalError))?;
+    touch_collection(db, &collection_hash)?;
     let index = Mutex::new(index);
     match index_store.write().or(Err(Error::InternalError))?.insert(index_hash, index) {
         Some(_) => Err(Error::InternalError),
         _ => {
-            let index = db.get_index(&collection_hash, &field_hash).unwrap();
+            let index = db
+                .get_index(&collection_hash, &field_hash)
+                .or(Err(Error::InternalError))?
+                .ok_or(Error::InternalError)?;
             Ok(IndexDB::from_hnsw_type(&index))
         }
     }
@@ -93,12 +104,12 @@ pub fn get(db: &RocksDB, collection_name: &str, field_name: &str) -> Result<Inde
     let field_hash = hash(field_id.as_bytes()).to_be_bytes();
 
     // Only possible if collection exists
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {}
         _ => return Err(Error::CollectionDoesNotExist),
     }
 
-    match db.get_index(&collection_hash, &field_hash) {
+    match db.get_index(&collection_hash, &field_hash).or(Err(Error::InternalError))? {
         Some(index) => {
             return Ok(IndexDB::from_hnsw_type(&index));
         }
@@ -113,20 +124,247 @@ pub fn delete(db: &RocksDB, index_store: &IndexStore, collection_name: &str, fie
     let index_hash = get_index_hash(collection_hash, field_hash);
 
     // Only possible if collection exists
-    match db.get_collection(&collection_hash) {
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
         Some(_) => {}
         _ => return Err(Error::CollectionDoesNotExist),
     }
 
-    match db.get_index(&collection_hash, &field_hash) {
+    match db.get_index(&collection_hash, &field_hash).or(Err(Error::InternalError))? {
         Some(index) => {
             // TODO: create collections and fields
-            db.delete_index(&collection_hash, &field_hash).or(Err(Error::InternalError))?;
-
+            // Removed first so no insert writes to the index anymore
             let mut index_store = index_store.write().or(Err(Error::InternalError))?;
             index_store.remove(&index_hash);
+            db.delete_index(&collection_hash, &field_hash, &index.graph_hash)
+                .or(Err(Error::InternalError))?;
+            touch_collection(db, &collection_hash)?;
             Ok(IndexDB::from_hnsw_type(&index))
         }
         _ => Err(Error::IndexDoesNotExist),
     }
 }
+
+/// Reclaims the space of a deleted index. Space left behind is reclaimed by regular compactions, so failures are only logged.
+pub fn compact(db: &RocksDB, index: &IndexDB) {
+    let collection_hash = hash(index.collection_id.as_bytes()).to_be_bytes();
+    let field_hash = hash(index.field_id.as_bytes()).to_be_bytes();
+    if let Err(error) = db.compact_prefixes(&index_prefixes(&collection_hash, &field_hash, &index.graph_hash)) {
+        log::warn!(
+            "Could not compact index '{}' of collection '{}': {}",
+            index.field_id,
+            index.collection_id,
+            error
+        );
+    }
+}
+
+/// Checks the graph of an index for broken invariants.
+/// The index stays locked meanwhile, so inserts into it wait until the verification finished.
+pub fn verify(db: &RocksDB, index_store: &IndexStore, collection_name: &str, field_name: &str) -> Result<Verification, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let index_store = index_store.read().or(Err(Error::InternalError))?;
+    let index = index_store
+        .get(&get_index_hash(collection_hash, field_hash))
+        .ok_or(Error::IndexDoesNotExist)?;
+    let index = index.lock().or(Err(Error::InternalError))?;
+    index.verify(db).or(Err(Error::InternalError))
+}
+
+/// Checks that an index exists and can be rebuilt with `k` and `m`, without starting the rebuild
+pub fn check_rebuild(db: &RocksDB, collection_name: &str, field_name: &str, k: Option<usize>, m: Option<f64>) -> Result<(), Error> {
+    if k == Some(0) || matches!(m, Some(m) if !(2.0..=100.0).contains(&m)) {
+        return Err(Error::InvalidInput);
+    }
+    get(db, collection_name, field_name).map(|_| ())
+}
+
+/// Builds a new graph from the stored vectors of an index and replaces the current one with it.
+/// The current graph keeps serving searches meanwhile and inserts are applied to both. `k` and `m` default to the current parameters.
+/// `progress` is called with the number of inserted and total elements.
+pub fn rebuild(
+    db: &RocksDB,
+    index_store: &IndexStore,
+    collection_name: &str,
+    field_name: &str,
+    k: Option<usize>,
+    m: Option<f64>,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<IndexDB, Error> {
+    check_rebuild(db, collection_name, field_name, k, m)?;
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+    let index_hash = get_index_hash(collection_hash, field_hash);
+
+    // From here on inserts go to the shadow graph as well
+    let (graph_hash, old_graph_hash) = with_index(index_store, &index_hash, |index| {
+        if index.shadow.is_some() {
+            return Err(Error::RebuildInProgress);
+        }
+        let mut builder = IndexBuilder::new()
+            .set_collection(&index.collection_id)
+            .set_field(&index.field_id)
+            .set_distance_metric(&index.distance_metric)
+            .set_buffer_size(index.buffer_size)
+            .set_dimension(index.dimension)
+            .set_k(k.unwrap_or(index.k));
+        if let Some(m) = m {
+            builder = builder.set_m(m);
+        }
+        let mut shadow = builder.build();
+        if m.is_none() {
+            shadow.reverse_size = index.reverse_size;
+        }
+        shadow.created_at = index.created_at;
+        shadow.graph_hash = loop {
+            let graph_hash = rand::random::<[u8; 8]>();
+            if graph_hash != index.graph_hash && graph_hash != index.field_hash {
+                break graph_hash;
+            }
+        };
+        let graph_hash = shadow.graph_hash;
+        index.shadow = Some(Box::new(shadow));
+        Ok((graph_hash, index.graph_hash))
+    })?;
+
+    if let Err(error) = build_shadow(db, index_store, &index_hash, progress) {
+        let _ = with_index(index_store, &index_hash, |index| {
+            index.shadow = None;
+            Ok(())
+        });
+        db.delete_graph(&collection_hash, &graph_hash).or(Err(Error::InternalError))?;
+        return Err(error);
+    }
+
+    // Swap both graphs. Writing the metadata switches the graph used after a restart.
+    let index = with_index(index_store, &index_hash, |index| {
+        let mut shadow = index.shadow.take().ok_or(Error::InternalError)?;
+        shadow.updated_at = utils::timestamp();
+        db.insert_index(&collection_hash, &field_hash, &shadow).or(Err(Error::InternalError))?;
+        *index = *shadow;
+        Ok(IndexDB::from_hnsw_type(index))
+    })?;
+    touch_collection(db, &collection_hash)?;
+
+    // Nothing reads the old graph anymore
+    db.delete_graph(&collection_hash, &old_graph_hash).or(Err(Error::InternalError))?;
+    Ok(index)
+}
+
+/// Inserts every stored vector of the index into its shadow graph.
+/// The index is locked per element, so searches and inserts interleave with the build.
+fn build_shadow(db: &RocksDB, index_store: &IndexStore, index_hash: &[u8; 16], progress: &mut dyn FnMut(u64, u64)) -> Result<(), Error> {
+    let collection_hash: [u8; 8] = index_hash[..8].try_into().unwrap();
+    let field_hash: [u8; 8] = index_hash[8..].try_into().unwrap();
+    let prefix = Prefix::new().prefix_type(VALUE).collection(&collection_hash).field(&field_hash).finish();
+    let keys = db.get_keys_by_prefix("vectors", &prefix).or(Err(Error::InternalError))?;
+    let total = keys.len() as u64;
+    for (idx, key) in keys.into_iter().enumerate() {
+        progress(idx as u64, total);
+        if Cancellation::current().is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let id_hash = key.get_document_id();
+        let vector = db
+            .get_value(&collection_hash, &field_hash, &id_hash)
+            .or(Err(Error::InternalError))?
+            .ok_or(Error::InternalError)?;
+        with_index(index_store, index_hash, |index| {
+            let shadow = index.shadow.as_mut().ok_or(Error::InternalError)?;
+            // Documents inserted since the rebuild started are already part of the shadow graph
+            if !shadow.contains(db, &id_hash).or(Err(Error::InternalError))? {
+                shadow.insert_vector(db, &id_hash, &vector).or(Err(Error::InternalError))?;
+            }
+            Ok(())
+        })?;
+    }
+    progress(total, total);
+    Ok(())
+}
+
+/// Adds the documents of the collection that are not part of the index yet, i.e. the ones stored before it was created.
+/// Documents whose field doesn't hold a valid vector for the index are skipped, like `document::insert` would have rejected them.
+/// The index is locked per document, so searches and inserts interleave with the backfill. Running it again continues where it stopped.
+/// `progress` is called with the number of visited and total documents.
+pub fn backfill(
+    db: &RocksDB,
+    index_store: &IndexStore,
+    collection_name: &str,
+    field_name: &str,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<IndexDB, Error> {
+    get(db, collection_name, field_name)?;
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+    let index_hash = get_index_hash(collection_hash, field_hash);
+
+    let prefix = Prefix::new().prefix_type(DOCUMENT).collection(&collection_hash).finish();
+    let keys = db.get_keys_by_prefix("documents", &prefix).or(Err(Error::InternalError))?;
+    let total = keys.len() as u64;
+    let mut n_skipped = 0;
+    for (idx, key) in keys.into_iter().enumerate() {
+        progress(idx as u64, total);
+        if Cancellation::current().is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let id_hash = key.get_document_id();
+        with_index(index_store, &index_hash, |index| {
+            // Deleted meanwhile, or inserted after the index was created
+            let document = match db.get_document(&collection_hash, &id_hash).or(Err(Error::InternalError))? {
+                Some(document) => document,
+                _ => return Ok(()),
+            };
+            if index.contains(db, &id_hash).or(Err(Error::InternalError))? {
+                return Ok(());
+            }
+            match document.get_field_vector(field_name) {
+                Some(vector) if vector.len() == index.dimension && distance::validate(&vector, &index.distance_metric).is_ok() => {
+                    index.insert(db, &document).or(Err(Error::InternalError))
+                }
+                Some(_) => {
+                    n_skipped += 1;
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        })?;
+    }
+    if n_skipped > 0 {
+        log::warn!(
+            "Skipped {} documents of collection '{}' without a valid vector in field '{}'",
+            n_skipped,
+            collection_name,
+            field_name
+        );
+    }
+    progress(total, total);
+    touch_collection(db, &collection_hash)?;
+    get(db, collection_name, field_name)
+}
+
+/// Runs `f` while holding the lock of the index. Fails if the index was deleted in the meantime.
+fn with_index<F, T>(index_store: &IndexStore, index_hash: &[u8; 16], f: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut Index) -> Result<T, Error>,
+{
+    let index_store = index_store.read().or(Err(Error::InternalError))?;
+    let index = index_store.get(index_hash).ok_or(Error::IndexDoesNotExist)?;
+    let mut index = index.lock().or(Err(Error::InternalError))?;
+    f(&mut index)
+}
+
+fn touch_collection(db: &RocksDB, collection_hash: &[u8; 8]) -> Result<(), Error> {
+    let mut collection = db
+        .get_collection(collection_hash)
+        .or(Err(Error::InternalError))?
+        .ok_or(Error::CollectionDoesNotExist)?;
+    collection.touch();
+    db.insert_collection(collection_hash, &collection).or(Err(Error::InternalError))
+}
@@ -0,0 +1,414 @@
+use crate::api::types::{IndexStore, State};
+use crate::db::dbtypes::*;
+use crate::db::RocksDB;
+use crate::hiddb::index;
+use crate::utils;
+use crate::workers::Cancellation;
+
+use rand::Rng;
+use seahash::hash;
+
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+/// Progress is written to RocksDB after this many units of work
+const PROGRESS_INTERVAL: u64 = 1000;
+
+/// Finished jobs kept per tenant, older ones are deleted when a new job is created
+pub const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    DoesNotExist,
+    AlreadyFinished,
+    Conflict,
+    InternalError,
+}
+
+/// Why a job did not succeed
+enum Failure {
+    Cancelled,
+    Failed(String),
+}
+
+impl JobKind {
+    /// Whether both jobs work on the same data and must not run at the same time
+    fn conflicts(&self, other: &JobKind) -> bool {
+        self.index() == other.index()
+    }
+
+    /// Collection and field of the index the job works on
+    fn index(&self) -> (&str, &str) {
+        match self {
+            JobKind::RebuildIndex { collection_id, field_id, .. } | JobKind::BackfillIndex { collection_id, field_id } => (collection_id, field_id),
+        }
+    }
+}
+
+/// Jobs scheduled by this process, by id.
+/// Every change of a job record goes through here, so cancellations and status updates don't overwrite each other.
+#[derive(Default)]
+pub struct Jobs {
+    scheduled: Mutex<HashMap<String, Cancellation>>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels all jobs of this process without marking them cancelled, so the next start resumes them
+    pub fn stop(&self) {
+        if let Ok(scheduled) = self.scheduled.lock() {
+            scheduled.values().for_each(Cancellation::cancel);
+        }
+    }
+
+    fn update<F>(&self, db: &RocksDB, job_id: &str, f: F) -> Result<JobDB, Error>
+    where
+        F: FnOnce(&mut JobDB) -> Result<(), Error>,
+    {
+        let _scheduled = self.scheduled.lock().or(Err(Error::InternalError))?;
+        let job_hash = hash(job_id.as_bytes()).to_be_bytes();
+        let mut job = db
+            .get_job(&job_hash)
+            .or(Err(Error::InternalError))?
+            .filter(|job| job.job_id == job_id)
+            .ok_or(Error::DoesNotExist)?;
+        f(&mut job)?;
+        job.updated_at = utils::timestamp();
+        db.insert_job(&job_hash, &job).or(Err(Error::InternalError))?;
+        Ok(job)
+    }
+}
+
+/// Records a queued job. It only runs once it is scheduled.
+/// Holds the lock of `jobs`, so concurrent requests can't both pass the conflict check.
+pub fn create(db: &RocksDB, jobs: &Jobs, tenant: &str, kind: JobKind) -> Result<JobDB, Error> {
+    let _scheduled = jobs.scheduled.lock().or(Err(Error::InternalError))?;
+    let existing = all(db)?;
+    if existing.iter().any(|job| !job.status.is_finished() && job.kind.conflicts(&kind)) {
+        return Err(Error::Conflict);
+    }
+
+    let finished: Vec<&JobDB> = existing.iter().filter(|job| job.tenant == tenant && job.status.is_finished()).collect();
+    for job in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+        db.delete_job(&hash(job.job_id.as_bytes()).to_be_bytes()).or(Err(Error::InternalError))?;
+    }
+
+    let job_id = utils::to_hex(&rand::thread_rng().gen::<[u8; 8]>());
+    let now = utils::timestamp();
+    let job = JobDB {
+        job_id: job_id.clone(),
+        tenant: tenant.to_owned(),
+        kind,
+        status: JobStatus::Queued,
+        progress: 0,
+        total: 0,
+        error: None,
+        cancel_requested: false,
+        created_at: now,
+        updated_at: now,
+    };
+    db.insert_job(&hash(job_id.as_bytes()).to_be_bytes(), &job)
+        .or(Err(Error::InternalError))?;
+    Ok(job)
+}
+
+pub fn get(db: &RocksDB, tenant: &str, job_id: &str) -> Result<JobDB, Error> {
+    match db.get_job(&hash(job_id.as_bytes()).to_be_bytes()).or(Err(Error::InternalError))? {
+        Some(job) if job.job_id == job_id && job.tenant == tenant => Ok(job),
+        _ => Err(Error::DoesNotExist),
+    }
+}
+
+/// Jobs of a tenant, oldest first
+pub fn get_all(db: &RocksDB, tenant: &str) -> Result<Vec<JobDB>, Error> {
+    Ok(all(db)?.into_iter().filter(|job| job.tenant == tenant).collect())
+}
+
+fn all(db: &RocksDB) -> Result<Vec<JobDB>, Error> {
+    let mut jobs = db.get_jobs().or(Err(Error::InternalError))?;
+    jobs.sort_by_key(|job| job.created_at);
+    Ok(jobs)
+}
+
+/// Cancels a job. Queued jobs are cancelled right away, running ones stop at their next check.
+pub fn cancel(db: &RocksDB, jobs: &Jobs, tenant: &str, job_id: &str) -> Result<JobDB, Error> {
+    get(db, tenant, job_id)?;
+    let job = jobs.update(db, job_id, |job| {
+        if job.status.is_finished() {
+            return Err(Error::AlreadyFinished);
+        }
+        job.cancel_requested = true;
+        if job.status == JobStatus::Queued {
+            job.status = JobStatus::Cancelled;
+        }
+        Ok(())
+    })?;
+    if let Some(cancellation) = jobs.scheduled.lock().or(Err(Error::InternalError))?.get(job_id) {
+        cancellation.cancel();
+    }
+    Ok(job)
+}
+
+/// Creates an index like `index::create`. If the collection already holds documents,
+/// a job that backfills them is recorded as well and has to be scheduled by the caller.
+pub fn create_index(
+    state: &State,
+    tenant: &str,
+    collection_name: &str,
+    field_name: &str,
+    dimension: usize,
+) -> Result<(IndexDB, Option<JobDB>), index::Error> {
+    let db = &state.db;
+    let index = index::create(db, &state.index_store, &state.index_defaults, collection_name, field_name, dimension)?;
+
+    // Inserts wait for the index to be created, so documents counted now either precede it or are part of it
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let collection = db
+        .get_collection(&collection_hash)
+        .or(Err(index::Error::InternalError))?
+        .ok_or(index::Error::CollectionDoesNotExist)?;
+    if collection.n_documents == 0 {
+        return Ok((index, None));
+    }
+    let kind = JobKind::BackfillIndex {
+        collection_id: collection_name.to_owned(),
+        field_id: field_name.to_owned(),
+    };
+    match create(db, &state.jobs, tenant, kind) {
+        Ok(job) => Ok((index, Some(job))),
+        Err(error) => {
+            // Without the job the index would miss the stored documents for good
+            let _ = index::delete(db, &state.index_store, collection_name, field_name);
+            match error {
+                // Still working on a deleted index with the same name
+                Error::Conflict => Err(index::Error::RebuildInProgress),
+                _ => Err(index::Error::InternalError),
+            }
+        }
+    }
+}
+
+/// Runs a job on the background pool
+pub fn schedule(state: &Arc<State>, job_id: &str) {
+    let cancellation = Cancellation::new();
+    if let Ok(mut scheduled) = state.jobs.scheduled.lock() {
+        scheduled.insert(job_id.to_owned(), cancellation.clone());
+    }
+    let shared = state.clone();
+    let job_id = job_id.to_owned();
+    state.workers.spawn(cancellation, move || {
+        run(&shared.db, &shared.index_store, &shared.jobs, &job_id);
+        if let Ok(mut scheduled) = shared.jobs.scheduled.lock() {
+            scheduled.remove(&job_id);
+        }
+    });
+}
+
+/// Schedules the jobs that were queued or running when the previous process stopped and returns their number.
+/// Interrupted jobs start over.
+pub fn resume(state: &Arc<State>) -> Result<usize, Error> {
+    let unfinished: Vec<JobDB> = all(&state.db)?.into_iter().filter(|job| !job.status.is_finished()).collect();
+    for job in unfinished.iter() {
+        schedule(state, &job.job_id);
+    }
+    Ok(unfinished.len())
+}
+
+/// Runs a job on the current thread and records its outcome. Jobs that already finished are skipped.
+pub fn run(db: &RocksDB, index_store: &IndexStore, jobs: &Jobs, job_id: &str) {
+    let job = jobs.update(db, job_id, |job| {
+        if job.status.is_finished() {
+            return Err(Error::AlreadyFinished);
+        }
+        job.status = match job.cancel_requested {
+            true => JobStatus::Cancelled,
+            false => JobStatus::Running,
+        };
+        Ok(())
+    });
+    let job = match job {
+        Ok(job) if job.status == JobStatus::Running => job,
+        _ => return,
+    };
+
+    let mut progress = |done: u64, total: u64| {
+        if done % PROGRESS_INTERVAL == 0 || done == total {
+            let _ = jobs.update(db, job_id, |job| {
+                job.progress = done;
+                job.total = total;
+                Ok(())
+            });
+        }
+    };
+    let result = catch_unwind(AssertUnwindSafe(|| execute(db, index_store, &job.kind, &mut progress)))
+        .unwrap_or_else(|_| Err(Failure::Failed("internal error".to_owned())));
+
+    let _ = jobs.update(db, job_id, |job| {
+        match result {
+            Ok(()) => job.status = JobStatus::Succeeded,
+            Err(Failure::Cancelled) if job.cancel_requested => job.status = JobStatus::Cancelled,
+            // Stopped by a shutdown
+            Err(Failure::Cancelled) => job.status = JobStatus::Queued,
+            Err(Failure::Failed(message)) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(message);
+            }
+        }
+        Ok(())
+    });
+}
+
+fn execute(db: &RocksDB, index_store: &IndexStore, kind: &JobKind, progress: &mut dyn FnMut(u64, u64)) -> Result<(), Failure> {
+    let result = match kind {
+        JobKind::RebuildIndex {
+            collection_id,
+            field_id,
+            k,
+            m,
+        } => index::rebuild(db, index_store, collection_id, field_id, *k, *m, progress),
+        JobKind::BackfillIndex { collection_id, field_id } => index::backfill(db, index_store, collection_id, field_id, progress),
+    };
+    match result {
+        Ok(_) => Ok(()),
+        Err(index::Error::Cancelled) => Err(Failure::Cancelled),
+        Err(index::Error::CollectionDoesNotExist) => Err(Failure::Failed("collection does not exist".to_owned())),
+        Err(index::Error::IndexDoesNotExist) => Err(Failure::Failed("index does not exist".to_owned())),
+        Err(index::Error::RebuildInProgress) => Err(Failure::Failed("index is already being rebuilt".to_owned())),
+        Err(index::Error::InvalidInput) => Err(Failure::Failed("k must be positive and m between 2 and 100".to_owned())),
+        Err(_) => Err(Failure::Failed("internal error".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IndexConfig;
+    use crate::hiddb;
+    use crate::index_store;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_jobs() {
+        let db_name = "./build/jobs.rdb";
+        let db_options;
+        {
+            let db = &RocksDB::init(db_name);
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+            let jobs = Jobs::new();
+            hiddb::collection::create(db, "movies").unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), "movies", "vector", 2).unwrap();
+            let documents = (0..20).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]})).collect();
+            hiddb::document::insert(db, &index_store, "movies", &documents).unwrap();
+
+            let rebuild = |k| JobKind::RebuildIndex {
+                collection_id: "movies".to_owned(),
+                field_id: "vector".to_owned(),
+                k: Some(k),
+                m: None,
+            };
+            let job = create(db, &jobs, "default", rebuild(4)).unwrap();
+            assert_eq!(create(db, &jobs, "default", rebuild(8)), Err(Error::Conflict));
+            assert_eq!(get(db, "other", &job.job_id), Err(Error::DoesNotExist));
+
+            run(db, &index_store, &jobs, &job.job_id);
+            let job = get(db, "default", &job.job_id).unwrap();
+            assert_eq!(job.status, JobStatus::Succeeded);
+            assert_eq!((job.progress, job.total), (20, 20));
+            assert_eq!(hiddb::index::get(db, "movies", "vector").unwrap().k, 4);
+            assert_eq!(cancel(db, &jobs, "default", &job.job_id), Err(Error::AlreadyFinished));
+
+            // Cancelled while queued, so it never runs
+            let job = create(db, &jobs, "default", rebuild(8)).unwrap();
+            assert_eq!(cancel(db, &jobs, "default", &job.job_id).unwrap().status, JobStatus::Cancelled);
+            run(db, &index_store, &jobs, &job.job_id);
+            assert_eq!(hiddb::index::get(db, "movies", "vector").unwrap().k, 4);
+
+            // Failures are recorded with their reason
+            let job = create(
+                db,
+                &jobs,
+                "default",
+                JobKind::RebuildIndex {
+                    collection_id: "movies".to_owned(),
+                    field_id: "title".to_owned(),
+                    k: None,
+                    m: None,
+                },
+            )
+            .unwrap();
+            run(db, &index_store, &jobs, &job.job_id);
+            let job = get(db, "default", &job.job_id).unwrap();
+            assert_eq!(job.status, JobStatus::Failed);
+            assert_eq!(job.error, Some("index does not exist".to_owned()));
+
+            assert_eq!(get_all(db, "default").unwrap().len(), 3);
+            assert!(get_all(db, "other").unwrap().is_empty());
+
+            // Only the latest finished jobs of a tenant are kept
+            for _ in 0..=MAX_FINISHED_JOBS {
+                let job = create(db, &jobs, "other", rebuild(4)).unwrap();
+                cancel(db, &jobs, "other", &job.job_id).unwrap();
+            }
+            let oldest = get_all(db, "other").unwrap()[0].job_id.clone();
+            create(db, &jobs, "other", rebuild(4)).unwrap();
+            let other = get_all(db, "other").unwrap();
+            assert_eq!(other.len(), MAX_FINISHED_JOBS + 1);
+            assert!(other.iter().all(|job| job.job_id != oldest));
+            assert_eq!(get_all(db, "default").unwrap().len(), 3);
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+
+    #[test]
+    fn test_backfill() {
+        let db_name = "./build/backfill.rdb";
+        let db_options;
+        {
+            let db = RocksDB::init(db_name);
+            db_options = db.options.clone();
+            let state = State::new(db).unwrap();
+            let db = &state.db;
+
+            hiddb::collection::create(db, "movies").unwrap();
+            let mut documents: Vec<_> = (0..20).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]})).collect();
+            documents.push(json!({"id": "wrong_dimension", "vector": [1.0]}));
+            documents.push(json!({"id": "no_vector"}));
+            hiddb::document::insert(db, &state.index_store, "movies", &documents).unwrap();
+
+            let (index, job) = create_index(&state, "default", "movies", "vector", 2).unwrap();
+            assert_eq!(index.n_elements, 0);
+            let job = job.unwrap();
+            assert_eq!(create_index(&state, "default", "movies", "vector", 2), Err(index::Error::AlreadyExists));
+
+            // Documents inserted meanwhile are indexed right away and not added twice
+            let document = json!({"id": "20", "vector": [20.0, 0.0]});
+            hiddb::document::insert(db, &state.index_store, "movies", &vec![document]).unwrap();
+            run(db, &state.index_store, &state.jobs, &job.job_id);
+            let job = get(db, "default", &job.job_id).unwrap();
+            assert_eq!(job.status, JobStatus::Succeeded);
+            assert_eq!((job.progress, job.total), (23, 23));
+            assert_eq!(hiddb::index::get(db, "movies", "vector").unwrap().n_elements, 21);
+            let search = json!({"field_name": "vector", "vectors": [[3.0, 0.0]], "max_neighbors": 1});
+            assert_eq!(
+                hiddb::document::search_ann(db, &state.index_store, "movies", &search).unwrap(),
+                vec![vec!["3".to_owned()]]
+            );
+
+            // Running it again adds nothing
+            let index = hiddb::index::backfill(db, &state.index_store, "movies", "vector", &mut |_, _| {}).unwrap();
+            assert_eq!(index.n_elements, 21);
+
+            // Indices of empty collections need no backfill
+            hiddb::collection::create(db, "empty").unwrap();
+            assert_eq!(create_index(&state, "default", "empty", "vector", 2).unwrap().1, None);
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+}
@@ -1,10 +1,18 @@
 pub mod collection;
 pub mod document;
+pub mod fusion;
+pub mod gc;
 pub mod index;
+pub mod job;
+pub mod recovery;
+pub mod schema;
+pub mod tenant;
+pub mod text_index;
 
 #[cfg(test)]
 mod tests {
 
+    use crate::config::IndexConfig;
     use crate::db::dbtypes::*;
     use crate::distance;
     use crate::hnsw::IndexBuilder;
@@ -14,6 +22,7 @@ mod tests {
 
     use crate::db::RocksDB;
     use crate::hnsw::document::*;
+    use crate::hnsw::key::*;
     use crate::hnsw::*;
     use serde_json::*;
 
@@ -27,15 +36,12 @@ mod tests {
             let db = &RocksDB::init("./build/small_scale.rdb");
             db_options = db.options.clone();
 
-            let index_store = index_store::init(db);
+            let index_store = index_store::init(db).unwrap();
 
             let collection_name = "test_collection".to_owned();
             let field_name = "vector";
 
-            let test_collection = Collection {
-                collection_id: collection_name.clone(),
-                n_documents: 0,
-            };
+            let test_collection = Collection::new(&collection_name);
 
             // First insert should work
             assert_eq!(
@@ -50,14 +56,14 @@ mod tests {
             );
 
             assert_eq!(
-                hiddb::index::create(db, &index_store, "collection_does_not_exist", field_name, 3),
+                hiddb::index::create(db, &index_store, &IndexConfig::default(), "collection_does_not_exist", field_name, 3),
                 Err(hiddb::index::Error::CollectionDoesNotExist)
             );
 
-            let index = hiddb::index::create(db, &index_store, &collection_name, field_name, 3).unwrap();
+            let index = hiddb::index::create(db, &index_store, &IndexConfig::default(), &collection_name, field_name, 3).unwrap();
 
             assert_eq!(
-                hiddb::index::create(db, &index_store, &collection_name, field_name, 3),
+                hiddb::index::create(db, &index_store, &IndexConfig::default(), &collection_name, field_name, 3),
                 Err(hiddb::index::Error::AlreadyExists)
             );
 
@@ -141,6 +147,101 @@ mod tests {
                 vec![vec![d_1.id_user.clone(), d_2.id_user.clone(), d_3.id_user.clone(), d_4.id_user.clone()]]
             );
 
+            // Range search returns everything within the radius
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![1.0f64, 2.0f64, 3.0f64]], "radius": 2.0, "range": true})
+                )
+                .unwrap(),
+                vec![vec![d_1.id_user.clone(), d_2.id_user.clone()]]
+            );
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![1.0f64, 2.0f64, 3.0f64]], "max_neighbors": u64::MAX})
+                ),
+                Err(hiddb::document::Error::InvalidInput)
+            );
+            // `max_results` caps range searches below the `k` elements of the seeding search
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![1.0f64, 2.0f64, 3.0f64]], "radius": 2.0, "range": true, "max_results": 1})
+                )
+                .unwrap(),
+                vec![vec![d_1.id_user.clone()]]
+            );
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![1.0f64, 2.0f64, 3.0f64]], "min_score": 0.5, "range": true})
+                )
+                .unwrap(),
+                vec![vec![d_1.id_user.clone()]]
+            );
+
+            // Thresholds on top of top-k
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![1.0f64, 2.0f64, 3.0f64]], "max_neighbors": 4, "radius": 2.5})
+                )
+                .unwrap(),
+                vec![vec![d_1.id_user.clone(), d_2.id_user.clone(), d_3.id_user.clone()]]
+            );
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![1.0f64, 2.0f64, 3.0f64]], "range": true})
+                ),
+                Err(hiddb::document::Error::InvalidInput)
+            );
+
+            // Batches are searched in parallel, results keep the order of the request
+            let vectors: Vec<Vec<f64>> = (0..8).map(|i| vec![i as f64, 0.0, 0.0]).collect();
+            let result = hiddb::document::search_ann(
+                &db,
+                &index_store,
+                &collection_name,
+                &json!({"field_name": "vector", "vectors": vectors, "max_neighbors": 1}),
+            )
+            .unwrap();
+            for (vector, neighbors) in vectors.iter().zip(result.iter()) {
+                let expected = hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": [vector], "max_neighbors": 1}),
+                )
+                .unwrap();
+                assert_eq!(neighbors, &expected[0]);
+            }
+            assert_eq!(
+                hiddb::document::search_ann(
+                    &db,
+                    &index_store,
+                    &collection_name,
+                    &json!({"field_name": "vector", "vectors": vec![vec![0.0f64; 3]; hiddb::document::MAX_BATCH_SIZE + 1]})
+                ),
+                Err(hiddb::document::Error::BatchTooLarge {
+                    batch_size: hiddb::document::MAX_BATCH_SIZE + 1,
+                    max_batch_size: hiddb::document::MAX_BATCH_SIZE
+                })
+            );
+
             assert_eq!(
                 hiddb::document::search_ann(
                     &db,
@@ -162,22 +263,19 @@ mod tests {
             let db = &RocksDB::init("./build/large_scale_1.rdb");
             db_options = db.options.clone();
 
-            let index_store = index_store::init(db);
+            let index_store = index_store::init(db).unwrap();
 
             let collection_name = "test_collection".to_owned();
             let field_name = "vector";
 
-            let test_collection = Collection {
-                collection_id: collection_name.clone(),
-                n_documents: 0,
-            };
+            let test_collection = Collection::new(&collection_name);
 
             assert_eq!(
                 hiddb::collection::create(db, &collection_name).unwrap().collection_id,
                 test_collection.collection_id
             );
 
-            hiddb::index::create(db, &index_store, &collection_name, field_name, 200).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), &collection_name, field_name, 200).unwrap();
 
             let mut rng = rand::thread_rng();
             for idx in 0..100 {
@@ -202,23 +300,28 @@ mod tests {
                 &vector,
                 &db.get_document(&collection_hash, &entry_point)
                     .unwrap()
-                    .get_field_vector(&hnsw_index.field_id),
+                    .unwrap()
+                    .get_field_vector(&hnsw_index.field_id)
+                    .unwrap(),
             );
 
-            let mut entry_point_document_new = db.get_document(&collection_hash, &entry_point).unwrap();
+            let mut entry_point_document_new = db.get_document(&collection_hash, &entry_point).unwrap().unwrap();
 
             for level_idx in (0..hnsw_index.n_layers).rev() {
                 let new_entry_point_id = hnsw_index
                     .search_level(&db, &vector, level_idx, &entry_point_document_new.id_hash)
+                    .unwrap()
                     .first()
                     .1;
-                entry_point_document_new = db.get_document(&hnsw_index.collection_hash, &new_entry_point_id).unwrap();
+                entry_point_document_new = db.get_document(&hnsw_index.collection_hash, &new_entry_point_id).unwrap().unwrap();
 
                 let distance_new = distance::euclidean(
                     &vector,
                     &db.get_document(&hnsw_index.collection_hash, &new_entry_point_id)
                         .unwrap()
-                        .get_field_vector(&hnsw_index.field_id),
+                        .unwrap()
+                        .get_field_vector(&hnsw_index.field_id)
+                        .unwrap(),
                 );
                 assert!(distance_new <= distance_to_vector_initial);
             }
@@ -233,22 +336,19 @@ mod tests {
             let db = &RocksDB::init("./build/large_scale_2.rdb");
             db_options = db.options.clone();
 
-            let index_store = index_store::init(db);
+            let index_store = index_store::init(db).unwrap();
 
             let collection_name = "test_collection".to_owned();
             let field_name = "vector";
 
-            let test_collection = Collection {
-                collection_id: collection_name.clone(),
-                n_documents: 0,
-            };
+            let test_collection = Collection::new(&collection_name);
 
             assert_eq!(
                 hiddb::collection::create(db, &collection_name).unwrap().collection_id,
                 test_collection.collection_id
             );
 
-            hiddb::index::create(db, &index_store, &collection_name, field_name, 200).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), &collection_name, field_name, 200).unwrap();
             let mut rng = rand::thread_rng();
             for idx in 0..100 {
                 let range = Uniform::new(-100.0, 100.0);
@@ -288,4 +388,546 @@ mod tests {
         }
         RocksDB::destroy(&db_options, "./build/large_scale_2.rdb");
     }
+
+    #[test]
+    fn test_collection_with_schema() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/collection_schema.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            let schema = Schema {
+                vectors: vec![VectorField {
+                    name: "vector".to_owned(),
+                    dimension: 3,
+                    metric: "cosine".to_owned(),
+                    k: Some(8),
+                    m: None,
+                }],
+                fields: vec![PayloadField {
+                    name: "title".to_owned(),
+                    field_type: FieldType::String,
+                    required: true,
+                }],
+            };
+
+            let collection =
+                hiddb::collection::create_with_schema(db, &index_store, &IndexConfig::default(), collection_name, Some(schema.clone())).unwrap();
+            assert_eq!(collection.schema, Some(schema.clone()));
+            assert_eq!(
+                hiddb::collection::create_with_schema(db, &index_store, &IndexConfig::default(), collection_name, Some(schema.clone())),
+                Err(hiddb::collection::Error::AlreadyExists)
+            );
+
+            // Only one of several concurrent creates of the same name succeeds, in a separate store to keep the index count below
+            let concurrent_store = index_store::init(db).unwrap();
+            let payload_only = Schema {
+                vectors: vec![],
+                ..schema.clone()
+            };
+            for schema in [schema, payload_only].iter() {
+                let name = format!("concurrent_{}", schema.vectors.len());
+                let results = std::sync::Mutex::new(Vec::new());
+                rayon::scope(|scope| {
+                    for _ in 0..8 {
+                        scope.spawn(|_| {
+                            let defaults = IndexConfig::default();
+                            let result = hiddb::collection::create_with_schema(db, &concurrent_store, &defaults, &name, Some(schema.clone()));
+                            results.lock().unwrap().push(result.map(|_| ()));
+                        });
+                    }
+                });
+                let mut results = results.into_inner().unwrap();
+                results.sort_by_key(|result| result.is_err());
+                assert_eq!(results[0], Ok(()));
+                assert!(results[1..].iter().all(|result| result == &Err(hiddb::collection::Error::AlreadyExists)));
+            }
+
+            // Declared index is created together with the collection
+            let index = hiddb::index::get(db, collection_name, "vector").unwrap();
+            assert_eq!(index.metric, "cosine");
+            assert_eq!(index.dimension, 3);
+            assert_eq!(index.k, 8);
+            assert_eq!(index_store.read().unwrap().len(), 1);
+
+            // Documents are validated against the schema
+            assert_eq!(
+                hiddb::document::insert(db, &index_store, collection_name, &vec![json!({"id": "1", "vector": [1.0, 0.0, 0.0]})]),
+                Err(hiddb::document::Error::SchemaViolation {
+                    document_id: "1".to_owned(),
+                    field: "title".to_owned(),
+                    reason: "is required".to_owned()
+                })
+            );
+            assert_eq!(hiddb::collection::get(db, collection_name).unwrap().n_documents, 0);
+
+            hiddb::document::insert(
+                db,
+                &index_store,
+                collection_name,
+                &vec![json!({"id": "1", "title": "a", "vector": [1.0, 0.0, 0.0]})],
+            )
+            .unwrap();
+            assert_eq!(hiddb::index::get(db, collection_name, "vector").unwrap().n_elements, 1);
+
+            // Vectors without a cosine distance are rejected before anything is written
+            assert_eq!(
+                hiddb::document::insert(
+                    db,
+                    &index_store,
+                    collection_name,
+                    &vec![
+                        json!({"id": "2", "title": "b", "vector": [0.0, 1.0, 0.0]}),
+                        json!({"id": "3", "title": "c", "vector": [0.0, 0.0, 0.0]}),
+                    ],
+                ),
+                Err(hiddb::document::Error::InvalidVector {
+                    field: "vector".to_owned(),
+                    reason: "is a zero vector, which has no cosine distance".to_owned()
+                })
+            );
+            assert_eq!(hiddb::collection::get(db, collection_name).unwrap().n_documents, 1);
+            assert_eq!(
+                hiddb::document::search_ann(
+                    db,
+                    &index_store,
+                    collection_name,
+                    &json!({"field_name": "vector", "vectors": [[1e300, 0.0, 0.0]]})
+                ),
+                Err(hiddb::document::Error::InvalidVector {
+                    field: "vector".to_owned(),
+                    reason: "has components too large to compute distances".to_owned()
+                })
+            );
+        }
+        RocksDB::destroy(&db_options, "./build/collection_schema.rdb");
+    }
+
+    #[test]
+    fn test_text_index_and_hybrid_search() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/text_index.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(db, collection_name).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+
+            // Documents present before the text index are indexed on creation
+            hiddb::document::insert(
+                db,
+                &index_store,
+                collection_name,
+                &vec![json!({"id": "1", "title": "Red apple pie", "vector": [0.0, 0.0]})],
+            )
+            .unwrap();
+            let text_index = hiddb::text_index::create(db, collection_name, "title").unwrap();
+            assert_eq!(text_index.n_documents, 1);
+            assert_eq!(hiddb::text_index::create(db, collection_name, "title"), Err(hiddb::text_index::Error::AlreadyExists));
+
+            hiddb::document::insert(
+                db,
+                &index_store,
+                collection_name,
+                &vec![
+                    json!({"id": "2", "title": "green apple", "vector": [10.0, 10.0]}),
+                    json!({"id": "3", "title": "banana bread", "vector": [1.0, 1.0]}),
+                    json!({"id": "4", "vector": [2.0, 2.0]}),
+                ],
+            )
+            .unwrap();
+            let text_index = hiddb::text_index::get(db, collection_name, "title").unwrap();
+            assert_eq!(text_index.n_documents, 3);
+            assert_eq!(text_index.total_length, 7);
+
+            // Keyword search alone, the shorter title matching "apple" ranks first
+            let result = hiddb::document::search_ann(
+                db,
+                &index_store,
+                collection_name,
+                &json!({"text": {"field_name": "title", "query": "APPLE"}}),
+            )
+            .unwrap();
+            assert_eq!(result, vec![vec!["2".to_owned(), "1".to_owned()]]);
+
+            // Hybrid search: "2" matches the query but is far away, "1" matches and is close
+            let result = hiddb::document::search_ann(
+                db,
+                &index_store,
+                collection_name,
+                &json!({
+                    "field_name": "vector",
+                    "vectors": [[0.0, 0.0]],
+                    "text": {"field_name": "title", "query": "apple"},
+                    "max_neighbors": 2
+                }),
+            )
+            .unwrap();
+            assert_eq!(result[0][0], "1");
+
+            assert_eq!(
+                hiddb::document::search_ann(
+                    db,
+                    &index_store,
+                    collection_name,
+                    &json!({"text": {"field_name": "body", "query": "apple"}}),
+                ),
+                Err(hiddb::document::Error::IndexDoesNotExist {
+                    field_name: "body".to_owned()
+                })
+            );
+
+            hiddb::text_index::delete(db, collection_name, "title").unwrap();
+            assert_eq!(hiddb::text_index::get_all(db, collection_name).unwrap().len(), 0);
+            assert!(db.get_by_prefix("default", &vec![POSTINGS]).unwrap().is_empty());
+            assert!(db.get_by_prefix("default", &vec![FIELD_LENGTH]).unwrap().is_empty());
+        }
+        RocksDB::destroy(&db_options, "./build/text_index.rdb");
+    }
+
+    #[test]
+    fn test_recommend() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/recommend.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(db, collection_name).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+            let documents = [("a", 0.0), ("b", 1.0), ("c", 2.0), ("d", -1.0), ("e", 5.0)]
+                .iter()
+                .map(|(id, x)| json!({"id": id, "vector": [x, 0.0]}))
+                .collect();
+            hiddb::document::insert(db, &index_store, collection_name, &documents).unwrap();
+
+            for strategy in ["average_vector", "best_score"].iter() {
+                let result = hiddb::document::recommend(
+                    db,
+                    &index_store,
+                    collection_name,
+                    &json!({"field_name": "vector", "positive": ["a"], "negative": ["c"], "strategy": strategy, "max_neighbors": 2}),
+                )
+                .unwrap();
+                assert_eq!(result, vec!["d".to_owned(), "b".to_owned()]);
+            }
+
+            assert_eq!(
+                hiddb::document::recommend(db, &index_store, collection_name, &json!({"field_name": "vector", "positive": ["x"]})),
+                Err(hiddb::document::Error::DocumentDoesNotExist)
+            );
+            assert_eq!(
+                hiddb::document::recommend(db, &index_store, collection_name, &json!({"field_name": "vector", "positive": []})),
+                Err(hiddb::document::Error::InvalidInput)
+            );
+
+            // Examples averaging to a zero vector have no cosine distance
+            let cosine = IndexConfig {
+                distance_metric: "cosine".to_owned(),
+                ..IndexConfig::default()
+            };
+            hiddb::collection::create(db, "cosine_collection").unwrap();
+            hiddb::index::create(db, &index_store, &cosine, "cosine_collection", "vector", 2).unwrap();
+            let documents = vec![json!({"id": "x", "vector": [1.0, 0.0]}), json!({"id": "y", "vector": [-1.0, 0.0]})];
+            hiddb::document::insert(db, &index_store, "cosine_collection", &documents).unwrap();
+            assert_eq!(
+                hiddb::document::recommend(
+                    db,
+                    &index_store,
+                    "cosine_collection",
+                    &json!({"field_name": "vector", "positive": ["x", "y"]})
+                ),
+                Err(hiddb::document::Error::InvalidVector {
+                    field: "vector".to_owned(),
+                    reason: "is a zero vector, which has no cosine distance".to_owned()
+                })
+            );
+        }
+        RocksDB::destroy(&db_options, "./build/recommend.rdb");
+    }
+
+    #[test]
+    fn test_diversity() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/diversity.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(db, collection_name).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+            let documents = vec![
+                json!({"id": "a", "vector": [0.0, 0.0]}),
+                json!({"id": "b", "vector": [0.1, 0.0]}),
+                json!({"id": "c", "vector": [0.2, 0.0]}),
+                json!({"id": "d", "vector": [0.0, 1.0]}),
+            ];
+            hiddb::document::insert(db, &index_store, collection_name, &documents).unwrap();
+
+            let search = |diversity: f64| {
+                hiddb::document::search_ann(
+                    db,
+                    &index_store,
+                    collection_name,
+                    &json!({"field_name": "vector", "vectors": [[0.0, 0.0]], "max_neighbors": 2, "diversity": diversity}),
+                )
+            };
+            assert_eq!(search(0.0).unwrap(), vec![vec!["a".to_owned(), "b".to_owned()]]);
+            // "b" and "c" are close to "a", so the far away "d" is preferred
+            assert_eq!(search(0.7).unwrap(), vec![vec!["a".to_owned(), "d".to_owned()]]);
+            assert_eq!(search(1.5), Err(hiddb::document::Error::InvalidInput));
+        }
+        RocksDB::destroy(&db_options, "./build/diversity.rdb");
+    }
+
+    #[test]
+    fn test_grouped_search() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/grouped_search.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(db, collection_name).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+            let documents = vec![
+                json!({"id": "a1", "source": "a", "vector": [0.0, 0.0]}),
+                json!({"id": "x", "vector": [0.05, 0.0]}),
+                json!({"id": "a2", "source": "a", "vector": [0.1, 0.0]}),
+                json!({"id": "a3", "source": "a", "vector": [0.2, 0.0]}),
+                json!({"id": "b1", "source": "b", "vector": [1.0, 0.0]}),
+                json!({"id": "b2", "source": "b", "vector": [1.1, 0.0]}),
+                json!({"id": "c1", "source": "c", "vector": [5.0, 0.0]}),
+            ];
+            hiddb::document::insert(db, &index_store, collection_name, &documents).unwrap();
+
+            // The first candidates all belong to "a", so the search has to expand to fill "b"
+            let result = hiddb::document::search_grouped(
+                db,
+                &index_store,
+                collection_name,
+                &json!({"field_name": "vector", "vectors": [[0.0, 0.0]], "max_neighbors": 2, "group_by": "source", "group_size": 2}),
+            )
+            .unwrap();
+            assert_eq!(
+                result,
+                vec![vec![
+                    hiddb::document::Group {
+                        key: json!("a"),
+                        ids: vec!["a1".to_owned(), "a2".to_owned()]
+                    },
+                    hiddb::document::Group {
+                        key: json!("b"),
+                        ids: vec!["b1".to_owned(), "b2".to_owned()]
+                    },
+                ]]
+            );
+
+            // Fewer groups than requested when the threshold cuts off the candidates
+            let result = hiddb::document::search_grouped(
+                db,
+                &index_store,
+                collection_name,
+                &json!({"field_name": "vector", "vectors": [[0.0, 0.0]], "max_neighbors": 3, "group_by": "source", "radius": 1.5}),
+            )
+            .unwrap();
+            assert_eq!(result[0].iter().map(|group| group.ids.len()).collect::<Vec<_>>(), vec![1, 1]);
+
+            // Grouping can't be combined with multi-field or hybrid searches
+            let fields = json!([{"field_name": "vector"}]);
+            let text = json!({"field_name": "title", "query": "a"});
+            for (key, value) in [("fields", fields), ("text", text)].iter() {
+                let mut item = json!({"field_name": "vector", "vectors": [[0.0, 0.0]], "group_by": "source"});
+                item[*key] = value.clone();
+                assert_eq!(
+                    hiddb::document::search_grouped(db, &index_store, collection_name, &item),
+                    Err(hiddb::document::Error::InvalidInput)
+                );
+            }
+        }
+        RocksDB::destroy(&db_options, "./build/grouped_search.rdb");
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/rebuild_index.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(db, collection_name).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+            let documents = (0..30).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]})).collect();
+            hiddb::document::insert(db, &index_store, collection_name, &documents).unwrap();
+
+            let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+            let old_graph_hash = hiddb::index::get(db, collection_name, "vector").unwrap().graph_hash;
+            assert_eq!(
+                hiddb::index::rebuild(db, &index_store, collection_name, "vector", Some(0), None, &mut |_, _| {}),
+                Err(hiddb::index::Error::InvalidInput)
+            );
+
+            let mut progress = (0, 0);
+            let index = hiddb::index::rebuild(db, &index_store, collection_name, "vector", Some(4), None, &mut |done, total| {
+                progress = (done, total)
+            })
+            .unwrap();
+            assert_eq!(progress, (30, 30));
+            assert_eq!(index.k, 4);
+            assert_eq!(index.n_elements, 30);
+            assert_ne!(index.graph_hash, old_graph_hash);
+            assert_eq!(hiddb::index::get(db, collection_name, "vector").unwrap().graph_hash, index.graph_hash);
+            let verification = hiddb::index::verify(db, &index_store, collection_name, "vector").unwrap();
+            assert!(verification.is_healthy(), "{:?}", verification.violations);
+
+            // The old graph is gone
+            let prefix = Prefix::new().prefix_type(NEIGHBORS).collection(&collection_hash).field(&old_graph_hash).finish();
+            assert!(db.get_keys_by_prefix("neighbors", &prefix).unwrap().is_empty());
+
+            // Inserts and searches use the new graph
+            hiddb::document::insert(db, &index_store, collection_name, &vec![json!({"id": "new", "vector": [10.2, 0.0]})]).unwrap();
+            let result = hiddb::document::search_ann(
+                db,
+                &index_store,
+                collection_name,
+                &json!({"field_name": "vector", "vectors": [[10.0, 0.0]], "max_neighbors": 2}),
+            )
+            .unwrap();
+            assert_eq!(result, vec![vec!["10".to_owned(), "new".to_owned()]]);
+            assert_eq!(hiddb::index::get(db, collection_name, "vector").unwrap().n_elements, 31);
+        }
+        RocksDB::destroy(&db_options, "./build/rebuild_index.rdb");
+    }
+
+    #[test]
+    fn test_inconsistent_metadata() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/inconsistent_metadata.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(db, collection_name).unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+            let documents = (0..3).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]})).collect();
+            hiddb::document::insert(db, &index_store, collection_name, &documents).unwrap();
+
+            // Elements without an entry point, as left by a crash, are reported instead of panicking
+            let index_hash = crate::hnsw::index::get_index_hash(hash(collection_name.as_bytes()).to_be_bytes(), hash(b"vector").to_be_bytes());
+            index_store.read().unwrap()[&index_hash].lock().unwrap().entry_point = None;
+            let query = json!({"field_name": "vector", "vectors": [[0.0, 0.0]]});
+            assert_eq!(
+                hiddb::document::search_ann(db, &index_store, collection_name, &query),
+                Err(hiddb::document::Error::InternalError)
+            );
+            assert_eq!(
+                hiddb::document::insert(db, &index_store, collection_name, &vec![json!({"id": "3", "vector": [3.0, 0.0]})]),
+                Err(hiddb::document::Error::InternalError)
+            );
+        }
+        RocksDB::destroy(&db_options, "./build/inconsistent_metadata.rdb");
+    }
+
+    #[test]
+    fn test_index_defaults() {
+        let db_options;
+        {
+            let db = &RocksDB::init("./build/index_defaults.rdb");
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+            let defaults = IndexConfig {
+                k: 5,
+                m: 4.0,
+                distance_metric: "cosine".to_owned(),
+            };
+
+            hiddb::collection::create(db, "plain").unwrap();
+            let index = hiddb::index::create(db, &index_store, &defaults, "plain", "vector", 2).unwrap();
+            assert_eq!((index.k, index.metric.as_str()), (5, "cosine"));
+
+            // Parameters set in a schema take precedence
+            let schema = Schema {
+                vectors: vec![VectorField {
+                    name: "vector".to_owned(),
+                    dimension: 2,
+                    metric: "euclidean".to_owned(),
+                    k: None,
+                    m: None,
+                }],
+                fields: vec![],
+            };
+            hiddb::collection::create_with_schema(db, &index_store, &defaults, "with_schema", Some(schema)).unwrap();
+            let index = hiddb::index::get(db, "with_schema", "vector").unwrap();
+            assert_eq!((index.k, index.metric.as_str()), (5, "euclidean"));
+        }
+        RocksDB::destroy(&db_options, "./build/index_defaults.rdb");
+    }
+
+    #[actix_rt::test]
+    async fn test_concurrent_batched_search() {
+        use crate::api::types::State;
+        use crate::config::Config;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let db_options;
+        {
+            let db = RocksDB::init("./build/concurrent_batched_search.rdb");
+            db_options = db.options.clone();
+            let mut config = Config::default();
+            config.server.query_threads = Some(2);
+            let state = Arc::new(State::from_config(db, &config).unwrap());
+
+            let collection_name = "test_collection";
+            hiddb::collection::create(&state.db, collection_name).unwrap();
+            hiddb::index::create(&state.db, &state.index_store, &IndexConfig::default(), collection_name, "vector", 2).unwrap();
+            let documents = (0..50)
+                .map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]}))
+                .collect::<Vec<_>>();
+            hiddb::document::insert(&state.db, &state.index_store, collection_name, &documents).unwrap();
+
+            // Threads waiting for a batch run other queued searches of the same index
+            let vectors = (0..32).map(|i| json!([i as f64, 0.0])).collect::<Vec<_>>();
+            let searches = (0..16).map(|_| {
+                let state = state.clone();
+                let item = json!({"field_name": "vector", "vectors": vectors, "max_neighbors": 1});
+                async move {
+                    state
+                        .workers
+                        .query({
+                            let state = state.clone();
+                            move || hiddb::document::search_ann(&state.db, &state.index_store, collection_name, &item)
+                        })
+                        .await
+                }
+            });
+            let results = actix_rt::time::timeout(Duration::from_secs(60), futures::future::join_all(searches))
+                .await
+                .expect("batched searches didn't finish");
+            for result in results {
+                let ids = result.unwrap().unwrap();
+                assert_eq!(ids.len(), 32);
+                assert!(ids.iter().enumerate().all(|(i, ids)| ids == &vec![i.to_string()]));
+            }
+        }
+        RocksDB::destroy(&db_options, "./build/concurrent_batched_search.rdb");
+    }
 }
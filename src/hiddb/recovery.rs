@@ -0,0 +1,174 @@
+use crate::api::types::IndexStore;
+use crate::db::RocksDB;
+use crate::hiddb::collection;
+use crate::hnsw::key::*;
+use crate::hnsw::Index;
+
+use seahash::hash;
+
+const STOPPED: u8 = 0;
+const RUNNING: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InternalError,
+}
+
+/// Metadata that the consistency check found out of date
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub checked_collections: usize,
+    pub checked_indices: usize,
+    pub stale_collections: Vec<String>,
+    pub stale_indices: Vec<(String, String)>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.stale_collections.is_empty() && self.stale_indices.is_empty()
+    }
+}
+
+/// Marks the database as in use and returns whether the previous process shut down cleanly.
+/// Databases written before the marker existed count as clean.
+pub fn start(db: &RocksDB) -> Result<bool, Error> {
+    let clean = db.get_run_state().or(Err(Error::InternalError))? != Some(RUNNING);
+    db.set_run_state(RUNNING).or(Err(Error::InternalError))?;
+    Ok(clean)
+}
+
+/// Persists all writes and marks the database as cleanly shut down.
+/// Must only be called once no more writes can happen.
+pub fn shutdown(db: &RocksDB) -> Result<(), Error> {
+    db.set_run_state(STOPPED).or(Err(Error::InternalError))?;
+    db.flush().or(Err(Error::InternalError))
+}
+
+/// Recomputes document counts and index metadata from the stored data and reports where they differ.
+/// With `repair` the recomputed values are written, in memory and to RocksDB.
+/// Metadata is written after the data it describes, so a crash can leave it behind but never ahead.
+pub fn check(db: &RocksDB, index_store: &IndexStore, repair: bool) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    for mut collection in collection::get_all(db).or(Err(Error::InternalError))? {
+        report.checked_collections += 1;
+        let collection_hash = hash(collection.collection_id.as_bytes()).to_be_bytes();
+        let prefix = Prefix::new().prefix_type(DOCUMENT).collection(&collection_hash).finish();
+        let n_documents = db.get_keys_by_prefix("documents", &prefix).or(Err(Error::InternalError))?.len();
+        if collection.n_documents != n_documents {
+            if repair {
+                collection.n_documents = n_documents;
+                db.insert_collection(&collection_hash, &collection).or(Err(Error::InternalError))?;
+            }
+            report.stale_collections.push(collection.collection_id);
+        }
+    }
+
+    let index_store = index_store.read().or(Err(Error::InternalError))?;
+    for index in index_store.values() {
+        let mut index = index.lock().or(Err(Error::InternalError))?;
+        report.checked_indices += 1;
+        let (n_elements, n_layers, entry_point) = count_index(db, &index)?;
+        if index.n_elements != n_elements || index.n_layers != n_layers || index.entry_point != entry_point {
+            if repair {
+                index.n_elements = n_elements;
+                index.n_layers = n_layers;
+                index.entry_point = entry_point;
+                db.insert_index(&index.collection_hash, &index.field_hash, &index)
+                    .or(Err(Error::InternalError))?;
+            }
+            report.stale_indices.push((index.collection_id.clone(), index.field_id.clone()));
+        }
+    }
+    Ok(report)
+}
+
+/// Derives `n_elements`, `n_layers` and `entry_point` from the neighbor lists of `index`.
+/// The current entry point is kept if it is still valid.
+fn count_index(db: &RocksDB, index: &Index) -> Result<(u64, u8, Option<[u8; 8]>), Error> {
+    let prefix = Prefix::new()
+        .prefix_type(NEIGHBORS)
+        .collection(&index.collection_hash)
+        .field(&index.graph_hash)
+        .finish();
+    let keys = db.get_keys_by_prefix("neighbors", &prefix).or(Err(Error::InternalError))?;
+
+    // Every element has neighbors on layer 0, the top layer always contains the entry point
+    let n_elements = keys.iter().filter(|key| key.get_layer() == 0).count() as u64;
+    let (n_layers, entry_point) = match keys.iter().map(|key| key.get_layer()).max() {
+        Some(top_layer) => {
+            let mut top_elements = keys.iter().filter(|key| key.get_layer() == top_layer).map(|key| key.get_document_id());
+            let entry_point = match index.entry_point {
+                Some(entry_point) if top_elements.clone().any(|id| id == entry_point) => entry_point,
+                _ => top_elements.next().unwrap(),
+            };
+            (top_layer + 1, Some(entry_point))
+        }
+        None => (1, None),
+    };
+    Ok((n_elements, n_layers, entry_point))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IndexConfig;
+    use crate::hiddb;
+    use crate::index_store;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_recovery() {
+        let db_name = "./build/recovery.rdb";
+        let db_options;
+        {
+            let db = &RocksDB::init(db_name);
+            db_options = db.options.clone();
+
+            // A new database counts as clean, a second start without shutdown does not
+            assert_eq!(start(db), Ok(true));
+            assert_eq!(start(db), Ok(false));
+            shutdown(db).unwrap();
+            assert_eq!(start(db), Ok(true));
+
+            let index_store = index_store::init(db).unwrap();
+            hiddb::collection::create(db, "movies").unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), "movies", "vector", 2).unwrap();
+            let documents = (0..20).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]})).collect();
+            hiddb::document::insert(db, &index_store, "movies", &documents).unwrap();
+            assert!(check(db, &index_store, false).unwrap().is_consistent());
+
+            // Simulate a crash between writing the graph and its metadata
+            let index_hash = crate::hnsw::index::get_index_hash(hash(b"movies").to_be_bytes(), hash(b"vector").to_be_bytes());
+            let expected = {
+                let store = index_store.read().unwrap();
+                let mut index = store[&index_hash].lock().unwrap();
+                let expected = (index.n_elements, index.n_layers);
+                index.n_elements = 3;
+                index.n_layers = 1;
+                index.entry_point = None;
+                expected
+            };
+            let mut collection = db.get_collection(&hash(b"movies").to_be_bytes()).unwrap().unwrap();
+            collection.n_documents = 19;
+            db.insert_collection(&hash(b"movies").to_be_bytes(), &collection).unwrap();
+
+            let report = check(db, &index_store, false).unwrap();
+            assert_eq!(report.checked_indices, 1);
+            assert_eq!(report.stale_collections, vec!["movies".to_owned()]);
+            assert_eq!(report.stale_indices, vec![("movies".to_owned(), "vector".to_owned())]);
+            assert_eq!(hiddb::collection::get(db, "movies").unwrap().n_documents, 19);
+
+            assert_eq!(check(db, &index_store, true).unwrap(), report);
+            assert!(check(db, &index_store, false).unwrap().is_consistent());
+
+            let index = hiddb::index::get(db, "movies", "vector").unwrap();
+            assert_eq!(index.n_elements, expected.0);
+            assert_eq!(index.n_layers, expected.1);
+            assert!(index.entry_point.is_some());
+            assert_eq!(hiddb::collection::get(db, "movies").unwrap().n_documents, 20);
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+}
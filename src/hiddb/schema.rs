@@ -0,0 +1,170 @@
+use crate::db::dbtypes::*;
+use crate::distance;
+
+use serde_json::Value;
+
+use std::collections::HashSet;
+
+/// Reason why a document does not match the schema of its collection
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub field: String,
+    pub reason: String,
+}
+
+impl Violation {
+    fn new(field: &str, reason: &str) -> Self {
+        Self {
+            field: field.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+/// Checks that a schema is consistent before any collection or index is created.
+pub fn validate_schema(schema: &Schema) -> Result<(), String> {
+    let mut names: HashSet<&str> = HashSet::new();
+
+    for vector in schema.vectors.iter() {
+        if vector.name.is_empty() || vector.name == "id" {
+            return Err(format!("invalid vector field name '{}'", vector.name));
+        }
+        if !names.insert(&vector.name) {
+            return Err(format!("field '{}' is declared more than once", vector.name));
+        }
+        if vector.dimension == 0 {
+            return Err(format!("vector field '{}' must have a dimension greater than 0", vector.name));
+        }
+        if !distance::is_supported(&vector.metric) {
+            return Err(format!("unknown metric '{}' for vector field '{}'", vector.metric, vector.name));
+        }
+        if let Some(k) = vector.k {
+            if k == 0 {
+                return Err(format!("k of vector field '{}' must be greater than 0", vector.name));
+            }
+        }
+        if let Some(m) = vector.m {
+            // from https://github.com/nmslib/hnswlib/blob/master/ALGO_PARAMS.md
+            if !(2.0..=100.0).contains(&m) {
+                return Err(format!("m of vector field '{}' should be between 2 and 100", vector.name));
+            }
+        }
+    }
+
+    for field in schema.fields.iter() {
+        if field.name.is_empty() {
+            return Err("payload field names must not be empty".to_owned());
+        }
+        if !names.insert(&field.name) {
+            return Err(format!("field '{}' is declared more than once", field.name));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a document against the schema of its collection.
+/// Vector fields are optional; documents without them are not indexed.
+pub fn validate_document(schema: &Schema, document: &Value) -> Result<(), Violation> {
+    for vector in schema.vectors.iter() {
+        let value = match document.get(&vector.name) {
+            Some(Value::Null) | None => continue,
+            Some(value) => value,
+        };
+        let components = match value.as_array() {
+            Some(components) => components,
+            _ => return Err(Violation::new(&vector.name, "should be an array of numbers")),
+        };
+        if components.len() != vector.dimension {
+            return Err(Violation::new(
+                &vector.name,
+                &format!("has dimension {} but schema declares {}", components.len(), vector.dimension),
+            ));
+        }
+        if components.iter().any(|component| !component.is_number()) {
+            return Err(Violation::new(&vector.name, "should be an array of numbers"));
+        }
+    }
+
+    for field in schema.fields.iter() {
+        let value = match document.get(&field.name) {
+            Some(Value::Null) | None => {
+                if field.required {
+                    return Err(Violation::new(&field.name, "is required"));
+                }
+                continue;
+            }
+            Some(value) => value,
+        };
+        let matches = match field.field_type {
+            FieldType::String => value.is_string(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Float => value.is_number(),
+            FieldType::Boolean => value.is_boolean(),
+            FieldType::Array => value.is_array(),
+            FieldType::Object => value.is_object(),
+        };
+        if !matches {
+            return Err(Violation::new(&field.name, &format!("should be of type {:?}", field.field_type).to_lowercase()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Schema {
+        Schema {
+            vectors: vec![VectorField {
+                name: "vector".to_owned(),
+                dimension: 2,
+                metric: "euclidean".to_owned(),
+                k: None,
+                m: None,
+            }],
+            fields: vec![
+                PayloadField {
+                    name: "title".to_owned(),
+                    field_type: FieldType::String,
+                    required: true,
+                },
+                PayloadField {
+                    name: "year".to_owned(),
+                    field_type: FieldType::Integer,
+                    required: false,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_validate_schema() {
+        assert_eq!(validate_schema(&schema()), Ok(()));
+
+        let mut invalid = schema();
+        invalid.vectors[0].metric = "manhattan".to_owned();
+        assert!(validate_schema(&invalid).is_err());
+
+        let mut invalid = schema();
+        invalid.fields[0].name = "vector".to_owned();
+        assert!(validate_schema(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_validate_document() {
+        let schema = schema();
+        assert_eq!(validate_document(&schema, &json!({"id": "1", "title": "a", "vector": [1.0, 2.0]})), Ok(()));
+        assert_eq!(validate_document(&schema, &json!({"id": "1", "title": "a", "year": 2021})), Ok(()));
+        assert_eq!(
+            validate_document(&schema, &json!({"id": "1", "vector": [1.0, 2.0]})),
+            Err(Violation::new("title", "is required"))
+        );
+        assert_eq!(
+            validate_document(&schema, &json!({"id": "1", "title": "a", "year": "2021"})),
+            Err(Violation::new("year", "should be of type integer"))
+        );
+        assert!(validate_document(&schema, &json!({"id": "1", "title": "a", "vector": [1.0]})).is_err());
+    }
+}
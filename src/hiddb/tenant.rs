@@ -0,0 +1,110 @@
+use crate::config::TenantConfig;
+use crate::db::dbtypes::*;
+use crate::db::RocksDB;
+use crate::hiddb::collection;
+
+
+/// Tenant of requests outside of `/ns/{tenant}`. Its collections keep their unqualified names,
+/// so data written before namespaces existed belongs to it.
+pub const DEFAULT_TENANT: &str = "default";
+
+const SEPARATOR: char = '/';
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    QuotaExceeded { resource: &'static str, limit: usize },
+    InternalError,
+}
+
+/// Limits applied to every tenant. `None` means unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Quota {
+    pub max_collections: Option<usize>,
+    pub max_documents: Option<usize>,
+}
+
+impl Quota {
+    pub fn from_config(config: &TenantConfig) -> Self {
+        Self {
+            max_collections: config.max_collections,
+            max_documents: config.max_documents,
+        }
+    }
+}
+
+/// Tenant and collection names can't contain the separator of qualified names
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(SEPARATOR) && name != "*"
+}
+
+/// Name under which a collection of `tenant` is stored. All keys of the collection derive from its hash.
+pub fn qualify(tenant: &str, collection_name: &str) -> String {
+    if tenant == DEFAULT_TENANT {
+        return collection_name.to_owned();
+    }
+    format!("{}{}{}", tenant, SEPARATOR, collection_name)
+}
+
+/// Inverse of `qualify`
+pub fn split(qualified_name: &str) -> (&str, &str) {
+    let mut parts = qualified_name.splitn(2, SEPARATOR);
+    match (parts.next(), parts.next()) {
+        (Some(tenant), Some(collection_name)) => (tenant, collection_name),
+        _ => (DEFAULT_TENANT, qualified_name),
+    }
+}
+
+pub fn get_collections(db: &RocksDB, tenant: &str) -> Result<Vec<Collection>, Error> {
+    let collections = collection::get_all(db).or(Err(Error::InternalError))?;
+    Ok(collections
+        .into_iter()
+        .filter(|collection| split(&collection.collection_id).0 == tenant)
+        .collect())
+}
+
+/// Checks that `tenant` may create another collection.
+/// Quotas are checked before writing without a lock, so concurrent requests may overshoot them slightly.
+pub fn check_collection_quota(db: &RocksDB, quota: &Quota, tenant: &str) -> Result<(), Error> {
+    let limit = match quota.max_collections {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    if get_collections(db, tenant)?.len() >= limit {
+        return Err(Error::QuotaExceeded {
+            resource: "collections",
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `tenant` may insert `n_documents` more documents across all its collections
+pub fn check_document_quota(db: &RocksDB, quota: &Quota, tenant: &str, n_documents: usize) -> Result<(), Error> {
+    let limit = match quota.max_documents {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let stored: usize = get_collections(db, tenant)?.iter().map(|collection| collection.n_documents).sum();
+    if stored + n_documents > limit {
+        return Err(Error::QuotaExceeded {
+            resource: "documents",
+            limit,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qualify() {
+        assert_eq!(qualify(DEFAULT_TENANT, "movies"), "movies");
+        assert_eq!(qualify("team_a", "movies"), "team_a/movies");
+        assert_eq!(split("movies"), (DEFAULT_TENANT, "movies"));
+        assert_eq!(split("team_a/movies"), ("team_a", "movies"));
+        assert!(!is_valid_name("a/b"));
+        assert!(!is_valid_name("*"));
+    }
+}
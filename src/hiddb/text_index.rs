@@ -0,0 +1,248 @@
+use crate::db::{term_id, RocksDB};
+
+use crate::db::dbtypes::*;
+use crate::hiddb::fusion::Ranking;
+use crate::hnsw::index::get_index_hash;
+use crate::utils;
+
+use lazy_static::lazy_static;
+use seahash::hash;
+
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    // Document counts and lengths of a text index are updated read-modify-write, so concurrent inserts into it must not interleave
+    static ref LOCKS: Mutex<HashMap<[u8; 16], Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    AlreadyExists,
+    InternalError,
+    CollectionDoesNotExist,
+    IndexDoesNotExist,
+    InvalidInput,
+}
+
+/// Splits text into lowercase alphanumeric terms
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+/// Lock of the text index on `field_hash`, held while its metadata is read and written
+fn lock(collection_hash: &[u8; 8], field_hash: &[u8; 8]) -> Result<Arc<Mutex<()>>, Error> {
+    let mut locks = LOCKS.lock().or(Err(Error::InternalError))?;
+    Ok(locks.entry(get_index_hash(*collection_hash, *field_hash)).or_default().clone())
+}
+
+/// Term ids and frequencies of the terms in `text`
+fn term_frequencies(field_hash: &[u8; 8], text: &str) -> HashMap<[u8; 8], u32> {
+    let mut frequencies: HashMap<[u8; 8], u32> = HashMap::new();
+    for term in tokenize(text).iter() {
+        *frequencies.entry(term_id(field_hash, &hash(term.as_bytes()).to_be_bytes())).or_insert(0) += 1;
+    }
+    frequencies
+}
+
+pub fn get_all(db: &RocksDB, collection_name: &str) -> Result<Vec<TextIndexDB>, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    db.get_text_indices_in_collection(&collection_hash).or(Err(Error::InternalError))
+}
+
+pub fn create(db: &RocksDB, collection_name: &str, field_name: &str) -> Result<TextIndexDB, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let lock = lock(&collection_hash, &field_hash)?;
+    let _lock = lock.lock().or(Err(Error::InternalError))?;
+    if db.get_text_index(&collection_hash, &field_hash).or(Err(Error::InternalError))?.is_some() {
+        return Err(Error::AlreadyExists);
+    }
+    let mut text_index = TextIndexDB::new(collection_name, field_name);
+
+    // Documents inserted before the index was created are indexed right away
+    let documents: Vec<Value> = db
+        .get_documents(&collection_hash)
+        .or(Err(Error::InternalError))?
+        .into_iter()
+        .map(|document| document.data)
+        .collect();
+    index_field(db, &collection_hash, &mut text_index, &documents)?;
+
+    Ok(text_index)
+}
+
+pub fn get(db: &RocksDB, collection_name: &str, field_name: &str) -> Result<TextIndexDB, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    db.get_text_index(&collection_hash, &field_hash)
+        .or(Err(Error::InternalError))?
+        .ok_or(Error::IndexDoesNotExist)
+}
+
+pub fn delete(db: &RocksDB, collection_name: &str, field_name: &str) -> Result<TextIndexDB, Error> {
+    let collection_hash = hash(collection_name.as_bytes()).to_be_bytes();
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+
+    // Only possible if collection exists
+    match db.get_collection(&collection_hash).or(Err(Error::InternalError))? {
+        Some(_) => {}
+        _ => return Err(Error::CollectionDoesNotExist),
+    }
+
+    let lock = lock(&collection_hash, &field_hash)?;
+    let _lock = lock.lock().or(Err(Error::InternalError))?;
+    let text_index = db
+        .get_text_index(&collection_hash, &field_hash)
+        .or(Err(Error::InternalError))?
+        .ok_or(Error::IndexDoesNotExist)?;
+
+    // Postings are found again from the indexed documents, as their keys are named by the term
+    let mut postings: Vec<([u8; 8], [u8; 8])> = Vec::new();
+    for document in db.get_documents(&collection_hash).or(Err(Error::InternalError))?.iter() {
+        if let Some(text) = document.data.get(&text_index.field_id).and_then(|t| t.as_str()) {
+            postings.extend(term_frequencies(&field_hash, text).into_keys().map(|term_id| (term_id, document.id_hash)));
+        }
+    }
+    db.delete_text_index(&collection_hash, &field_hash, &postings)
+        .or(Err(Error::InternalError))?;
+    Ok(text_index)
+}
+
+/// Adds newly inserted documents to all text indices of a collection.
+pub fn index_documents(db: &RocksDB, collection_hash: &[u8; 8], documents: &[Value]) -> Result<(), Error> {
+    let text_indices = db.get_text_indices_in_collection(collection_hash).or(Err(Error::InternalError))?;
+
+    for text_index in text_indices {
+        let field_hash = hash(text_index.field_id.as_bytes()).to_be_bytes();
+        let lock = lock(collection_hash, &field_hash)?;
+        let _lock = lock.lock().or(Err(Error::InternalError))?;
+        // Read again under the lock, the index may have been updated or deleted in the meantime
+        if let Some(mut text_index) = db.get_text_index(collection_hash, &field_hash).or(Err(Error::InternalError))? {
+            index_field(db, collection_hash, &mut text_index, documents)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes postings and field lengths of `documents` and stores the updated index.
+/// Must be called while holding the lock of the text index.
+fn index_field(db: &RocksDB, collection_hash: &[u8; 8], text_index: &mut TextIndexDB, documents: &[Value]) -> Result<(), Error> {
+    let field_hash = hash(text_index.field_id.as_bytes()).to_be_bytes();
+
+    // Collect new postings of the whole batch so they are written at once
+    let mut new_postings: HashMap<[u8; 8], Postings> = HashMap::new();
+    for document in documents.iter() {
+        let text = match document.get(&text_index.field_id).and_then(|t| t.as_str()) {
+            Some(text) => text,
+            _ => continue,
+        };
+        let document_hash = match document.get("id").and_then(|id| id.as_str()) {
+            Some(id) => hash(id.as_bytes()).to_be_bytes(),
+            _ => return Err(Error::InvalidInput),
+        };
+
+        let frequencies = term_frequencies(&field_hash, text);
+        let length: u32 = frequencies.values().sum();
+        for (term_id, frequency) in frequencies {
+            new_postings.entry(term_id).or_default().push((document_hash, frequency));
+        }
+
+        db.insert_field_length(collection_hash, &field_hash, &document_hash, length)
+            .or(Err(Error::InternalError))?;
+        text_index.n_documents += 1;
+        text_index.total_length += length as u64;
+    }
+
+    let new_postings: Vec<([u8; 8], Postings)> = new_postings.into_iter().collect();
+    db.insert_postings(collection_hash, &new_postings).or(Err(Error::InternalError))?;
+
+    text_index.updated_at = utils::timestamp();
+    db.insert_text_index(collection_hash, &field_hash, text_index)
+        .or(Err(Error::InternalError))
+}
+
+/// Ranks documents by their BM25 score for `query`.
+/// Returns at most `k` (score, id) pairs, best first. Documents without any query term are omitted.
+pub fn search(db: &RocksDB, collection_hash: &[u8; 8], field_name: &str, query: &str, k: usize) -> Result<Ranking, Error> {
+    let field_hash = hash(field_name.as_bytes()).to_be_bytes();
+    let text_index = db
+        .get_text_index(collection_hash, &field_hash)
+        .or(Err(Error::InternalError))?
+        .ok_or(Error::IndexDoesNotExist)?;
+
+    let mut terms: Vec<[u8; 8]> = term_frequencies(&field_hash, query).into_keys().collect();
+    terms.sort();
+
+    let n = text_index.n_documents as f64;
+    let average_length = text_index.average_length();
+    let mut lengths: HashMap<[u8; 8], f64> = HashMap::new();
+    let mut scores: HashMap<[u8; 8], f64> = HashMap::new();
+    for term_id in terms.iter() {
+        let postings = db.get_postings(collection_hash, term_id).or(Err(Error::InternalError))?;
+        if postings.is_empty() {
+            continue;
+        }
+
+        let df = postings.len() as f64;
+        let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+        for (document_hash, frequency) in postings.iter() {
+            let length = match lengths.get(document_hash) {
+                Some(length) => *length,
+                None => {
+                    let length = db
+                        .get_field_length(collection_hash, &field_hash, document_hash)
+                        .or(Err(Error::InternalError))?
+                        .unwrap_or(0) as f64;
+                    lengths.insert(*document_hash, length);
+                    length
+                }
+            };
+            let tf = *frequency as f64;
+            let norm = text_index.k1 * (1.0 - text_index.b + text_index.b * length / average_length);
+            *scores.entry(*document_hash).or_insert(0.0) += idf * tf * (text_index.k1 + 1.0) / (tf + norm);
+        }
+    }
+
+    let mut ranking: Ranking = scores.into_iter().map(|(id, score)| (score, id)).collect();
+    ranking.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal).then(a.1.cmp(&b.1)));
+    ranking.truncate(k);
+    Ok(ranking)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("Hello, World! hello-42"), vec!["hello", "world", "hello", "42"]);
+        assert!(tokenize("  ,. ").is_empty());
+    }
+}
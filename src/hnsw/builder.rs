@@ -3,22 +3,29 @@ use crate::hnsw::IndexBuilder;
 
 use seahash::hash;
 
+use crate::config::IndexConfig;
 use crate::hnsw::index::get_index_hash;
+use crate::utils;
 use rand::prelude::*;
 use rand::rngs::StdRng;
 
 impl IndexBuilder {
     pub fn new() -> Self {
+        Self::with_defaults(&IndexConfig::default())
+    }
+
+    /// Starts from `defaults` for the parameters that aren't set, see `State::index_defaults`
+    pub fn with_defaults(defaults: &IndexConfig) -> Self {
         Self {
             collection_id: String::new(),
             field_id: String::new(),
-            distance_metric: String::new(),
+            distance_metric: defaults.distance_metric.clone(),
             buffer_size: 0,
             dimension: 0,
-            k: 16,                              // number of nearest neighbors to save
+            k: defaults.k,                      // number of nearest neighbors to save
             seed: StdRng::from_entropy().gen(), // random seed
 
-            m: 2.0,
+            m: defaults.m,
         }
     }
     pub fn set_collection(mut self, collection_id: &str) -> Self {
@@ -67,6 +74,7 @@ impl IndexBuilder {
         let collection_hash = hash(self.collection_id.as_bytes()).to_be_bytes();
         let field_hash = hash(self.field_id.as_bytes()).to_be_bytes();
         let index_hash = get_index_hash(collection_hash, field_hash);
+        let now = utils::timestamp();
 
         Index {
             collection_id: self.collection_id.to_owned(),
@@ -74,6 +82,7 @@ impl IndexBuilder {
             collection_hash,
             field_hash,
             index_hash,
+            graph_hash: field_hash,
             distance_metric: self.distance_metric,
             buffer_size: self.buffer_size,
             dimension: self.dimension,
@@ -84,6 +93,11 @@ impl IndexBuilder {
             reverse_size: 1.0 / (1.0 / self.m.ln()),
             n_layers: 1,
             n_elements: 0,
+
+            created_at: now,
+            updated_at: now,
+
+            shadow: None,
         }
     }
 }
@@ -19,8 +19,9 @@ impl Document {
         &self.id_user
     }
 
-    pub fn get_field_vector(&self, field_id: &str) -> Vec<f64> {
-        self.data[field_id].as_array().unwrap().iter().map(|v| v.as_f64().unwrap()).collect()
+    /// None if the document has no vector in `field_id`
+    pub fn get_field_vector(&self, field_id: &str) -> Option<Vec<f64>> {
+        self.data.get(field_id)?.as_array()?.iter().map(|v| v.as_f64()).collect()
     }
 }
 
@@ -35,15 +36,17 @@ impl Document {
         serialize(&serde_json::to_string(&document_to_value(self)).unwrap()).unwrap()
     }
 
-    pub fn from_binary(document: Vec<u8>) -> Document {
-        value_to_document(serde_json::from_str(deserialize(&document).unwrap()).unwrap())
+    /// None if the stored document is corrupted
+    pub fn from_binary(document: Vec<u8>) -> Option<Document> {
+        let json: String = deserialize(&document).ok()?;
+        value_to_document(serde_json::from_str(&json).ok()?)
     }
 }
 
-pub fn value_to_document(value: Value) -> Document {
-    // TODO: handle unwraps
-    let id_user: String = value.get("id").unwrap().as_str().unwrap().to_owned();
-    Document::new(id_user, value)
+/// None if the value has no string id
+pub fn value_to_document(value: Value) -> Option<Document> {
+    let id_user: String = value.get("id")?.as_str()?.to_owned();
+    Some(Document::new(id_user, value))
 }
 
 pub fn document_to_value(document: Document) -> Value {
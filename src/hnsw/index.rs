@@ -1,5 +1,5 @@
 use rand::prelude::*;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::HashSet;
 
 use crate::distance;
@@ -9,16 +9,13 @@ use crate::reverse_sorted_list::ReverseSortedList;
 use crate::db::dbtypes::BinaryConverison;
 use crate::db::RocksDB;
 use crate::hnsw::key::*;
-use crate::hnsw::{Document, Index};
+use crate::hnsw::{Document, Error, Index};
+use crate::utils;
 use std::convert::TryInto;
 
 impl Index {
-    pub fn insert_in_layer(&mut self, db: &RocksDB, document: &Document, layer_id: u8) {
-        // TODO: do this in collection wide: When multiple fields to index are present this is done multiple times
-        let document_vector = document.get_field_vector(&self.field_id);
-
-        db.insert_value(&self.collection_hash, &self.field_hash, &document.id_hash, &document_vector)
-            .unwrap();
+    /// Adds an element whose VALUE is already stored to the graph. The metadata of the index is not written.
+    pub fn insert_in_layer(&mut self, db: &RocksDB, id_hash: &[u8; 8], document_vector: &Vec<f64>, layer_id: u8) -> Result<(), Error> {
         // db.insert_document(&self.collection_hash, &document.id_hash, &document).unwrap();
 
         // if element is already present and the value is different
@@ -46,37 +43,29 @@ impl Index {
 
                 for level_idx in ((random_level_idx + 1)..self.n_layers).rev() {
                     let new_entry_point_id = self
-                        .search_level(
-                            &db,
-                            &db.get_value(&self.collection_hash, &self.field_hash, &entry_point_document_id).unwrap(),
-                            level_idx,
-                            &entry_point_document_id,
-                        )
+                        .search_level(&db, &self.value(db, &entry_point_document_id)?, level_idx, &entry_point_document_id)?
                         .first()
                         .1;
                     entry_point_document_id = new_entry_point_id;
                 }
 
                 for level_idx in (0..=min(random_level_idx, self.n_layers - 1)).rev() {
-                    let nearest_neighbors = self.search_level(&db, &document_vector, level_idx, &entry_point_document_id);
+                    let nearest_neighbors = self.search_level(&db, document_vector, level_idx, &entry_point_document_id)?;
 
                     // Select neighbors
                     let nn_neighbors = nearest_neighbors.n_first(k);
 
                     // Add bidirectional connections from neighbors to q
                     for &(_, nn_id) in nn_neighbors.iter() {
-                        let nn_vector = db.get_value(&self.collection_hash, &self.field_hash, &nn_id).unwrap();
-                        let distance_to_nn = distance::euclidean(&nn_vector, &document_vector);
+                        let nn_vector = self.value(db, &nn_id)?;
+                        let distance_to_nn = self.distance(&nn_vector, document_vector);
 
                         // let nn_from_map = self.neighbor_map[level_idx].get_mut(&nn_id).unwrap();
                         // let mut nn_from_db = self.get_neighbors_from_level(db, &nn_id, &level_idx).unwrap().clone();
-                        let mut nn_from_db = db
-                            .get_neighbors(&self.collection_hash, &self.field_hash, level_idx, &nn_id)
-                            .unwrap()
-                            .clone();
+                        let mut nn_from_db = self.neighbors(db, level_idx, &nn_id)?;
 
                         // TODO: if distance_to_nn is further away than furthest nn do nothing
-                        nn_from_db.insert((distance_to_nn, document.id_hash));
+                        nn_from_db.insert((distance_to_nn, *id_hash));
                         // Shrink connections if needed
                         if nn_from_db.len() > k {
                             // nn.nearest_neighbors =
@@ -84,17 +73,15 @@ impl Index {
                             nn_from_db.pop().unwrap();
                         }
 
-                        db.insert_neighbors(&self.collection_hash, &self.field_hash, level_idx as u8, &nn_id, &nn_from_db)
-                            .unwrap();
+                        db.insert_neighbors(&self.collection_hash, &self.graph_hash, level_idx as u8, &nn_id, &nn_from_db)?;
                     }
                     db.insert_neighbors(
                         &self.collection_hash,
-                        &self.field_hash,
+                        &self.graph_hash,
                         level_idx as u8,
-                        &document.id_hash,
+                        id_hash,
                         &SortedList::from_sorted_vec(nn_neighbors.to_vec()),
-                    )
-                    .unwrap();
+                    )?;
 
                     entry_point_document_id = nearest_neighbors.first().1;
                 }
@@ -102,72 +89,222 @@ impl Index {
                 // document.level = random_level_idx;
                 if random_level_idx >= self.n_layers {
                     // Create new layer with document as a single entry
-                    self.entry_point = Some(document.id_hash);
+                    self.entry_point = Some(*id_hash);
                     random_level_idx = self.n_layers;
                     self.n_layers += 1;
 
-                    db.insert_neighbors(
-                        &self.collection_hash,
-                        &self.field_hash,
-                        random_level_idx,
-                        &document.id_hash,
-                        &SortedList::new(),
-                    )
-                    .unwrap();
+                    db.insert_neighbors(&self.collection_hash, &self.graph_hash, random_level_idx, id_hash, &SortedList::new())?;
                 }
             }
             None => {
                 // No entry point set: Index must be empty
-                assert_eq!(self.n_layers, 1);
-                assert_eq!(self.n_elements, 0);
+                if self.n_layers != 1 || self.n_elements != 0 {
+                    return Err(self.inconsistent());
+                }
 
-                self.entry_point = Some(document.id_hash);
+                self.entry_point = Some(*id_hash);
 
                 db.insert_neighbors(
                     &self.collection_hash,
-                    &self.field_hash,
+                    &self.graph_hash,
                     (self.n_layers - 1) as u8,
-                    &document.id_hash,
+                    id_hash,
                     &SortedList::new(),
-                )
-                .unwrap();
+                )?;
             }
         }
         self.n_elements += 1;
-        db.insert_index(&self.collection_hash, &self.field_hash, &self).unwrap();
+        self.updated_at = utils::timestamp();
+        Ok(())
+    }
+
+    /// Stores the field of `document` and adds it to the graph, and to the graph being rebuilt if there is one
+    pub fn insert(&mut self, db: &RocksDB, document: &Document) -> Result<(), Error> {
+        // TODO: do this in collection wide: When multiple fields to index are present this is done multiple times
+        let document_vector = document.get_field_vector(&self.field_id).ok_or(Error::MissingValue {
+            document_id: document.id_hash,
+        })?;
+        db.insert_value(&self.collection_hash, &self.field_hash, &document.id_hash, &document_vector)?;
+
+        self.insert_vector(db, &document.id_hash, &document_vector)?;
+        if let Some(shadow) = &mut self.shadow {
+            shadow.insert_vector(db, &document.id_hash, &document_vector)?;
+        }
+        db.insert_index(&self.collection_hash, &self.field_hash, &self)?;
+        Ok(())
     }
 
-    pub fn insert(&mut self, db: &RocksDB, document: &Document) {
+    /// Whether an element is part of the graph. Every element has a neighbor list on the bottom layer.
+    pub fn contains(&self, db: &RocksDB, id: &[u8; 8]) -> Result<bool, Error> {
+        Ok(db.get_neighbors(&self.collection_hash, &self.graph_hash, 0, id)?.is_some())
+    }
+
+    /// Adds an element whose VALUE is already stored to the graph on a random layer. The metadata of the index is not written.
+    pub fn insert_vector(&mut self, db: &RocksDB, id_hash: &[u8; 8], vector: &Vec<f64>) -> Result<(), Error> {
         let random_level_idx = self.random_level(self.reverse_size);
-        self.insert_in_layer(db, document, random_level_idx);
+        self.insert_in_layer(db, id_hash, vector, random_level_idx)
     }
 
-    pub fn knn_search(&self, db: &RocksDB, vector: &Vec<f64>, max_neighbors: usize) -> Vec<[u8; 8]> {
+    pub fn knn_search(&self, db: &RocksDB, vector: &Vec<f64>, max_neighbors: usize) -> Result<Vec<[u8; 8]>, Error> {
+        Ok(self.knn_search_with_distances(db, vector, max_neighbors)?.iter().map(|x| x.1).collect())
+    }
+
+    /// Nearest neighbors of `vector` together with their distances.
+    /// At least `k` candidates are explored in the bottom layer.
+    pub fn knn_search_with_distances(&self, db: &RocksDB, vector: &Vec<f64>, max_neighbors: usize) -> Result<Vec<(f64, [u8; 8])>, Error> {
         if self.n_elements == 0 {
-            return vec![];
+            return Ok(vec![]);
         }
 
-        let entry_point = self.entry_point.unwrap();
+        let entry_point_id = self.search_entry_point(db, vector)?;
+        let nearest_neighbors = self.search_level_ef(db, vector, 0, &entry_point_id, max(self.k, max_neighbors))?;
 
-        let mut entry_point_document_new = db.get_document(&self.collection_hash, &entry_point).unwrap();
+        // Select neighbors
+        Ok(nearest_neighbors.n_first(max_neighbors).to_vec())
+    }
 
-        for level_idx in (1..self.n_layers).rev() {
-            let new_entry_point_id = self.search_level(&db, &vector, level_idx, &entry_point_document_new.id_hash).first().1;
-            entry_point_document_new = db.get_document(&self.collection_hash, &new_entry_point_id).unwrap();
+    /// All elements within distance `radius` of `vector`, nearest first.
+    /// The bottom layer is expanded until the nearest unexplored candidate is further away than `radius`.
+    pub fn range_search(&self, db: &RocksDB, vector: &Vec<f64>, radius: f64, max_results: Option<usize>) -> Result<Vec<(f64, [u8; 8])>, Error> {
+        if self.n_elements == 0 || max_results == Some(0) {
+            return Ok(vec![]);
         }
 
-        let nearest_neighbors = self.search_level(db, &vector, 0, &entry_point_document_new.id_hash);
+        let entry_point_id = self.search_entry_point(db, vector)?;
 
-        // Select neighbors
-        let nearest_neighbors = nearest_neighbors.n_first(max_neighbors);
-        nearest_neighbors.iter().map(|x| x.1).collect()
+        // Seed the expansion with a regular search to reach the region around `vector`
+        let seeds = self.search_level(db, vector, 0, &entry_point_id)?;
+
+        let mut candidates: ReverseSortedList<f64, [u8; 8]> = ReverseSortedList::new();
+        let mut results: SortedList<f64, [u8; 8]> = SortedList::new();
+        let mut visited: HashSet<[u8; 8]> = HashSet::new();
+        for &(seed_distance, seed_id) in seeds.get_data().iter() {
+            visited.insert(seed_id);
+            candidates.insert((seed_distance, seed_id));
+            if seed_distance <= radius {
+                results.insert((seed_distance, seed_id));
+            }
+        }
+        // The seeds hold up to `k` elements, keep only the nearest ones
+        if let Some(max_results) = max_results {
+            while results.len() > max_results {
+                results.pop();
+            }
+        }
+
+        while let Some(nearest_candidate) = candidates.pop() {
+            if nearest_candidate.0 > radius {
+                break;
+            }
+            if let Some(max_results) = max_results {
+                if results.len() >= max_results && nearest_candidate.0 > results.last().0 {
+                    break;
+                }
+            }
+
+            let neighbor_ids: Vec<[u8; 8]> = self.neighbors(db, 0, &nearest_candidate.1)?.get_data().iter().map(|d| d.1).collect();
+
+            for &neighbor_id in neighbor_ids.iter() {
+                if !visited.insert(neighbor_id) {
+                    continue;
+                }
+                let neighbor_vector = self.value(db, &neighbor_id)?;
+                let neighbor_distance = self.distance(vector, &neighbor_vector);
+
+                candidates.insert((neighbor_distance, neighbor_id));
+                if neighbor_distance <= radius {
+                    results.insert((neighbor_distance, neighbor_id));
+                    if let Some(max_results) = max_results {
+                        if results.len() > max_results {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(results.to_vec())
+    }
+
+    /// Reorders `candidates` by maximal marginal relevance, see Carbonell and Goldstein (1998).
+    /// Each step picks the candidate maximizing `(1 - diversity) * score to the query - diversity * highest
+    /// score to an already selected candidate`. Returns at most `k` candidates with their query distances.
+    pub fn rerank_mmr(&self, db: &RocksDB, candidates: &[(f64, [u8; 8])], k: usize, diversity: f64) -> Result<Vec<(f64, [u8; 8])>, Error> {
+        let vectors: Vec<Vec<f64>> = candidates
+            .iter()
+            .map(|candidate| self.value(db, &candidate.1))
+            .collect::<Result<_, _>>()?;
+
+        let mut redundancy = vec![0.0; candidates.len()];
+        let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+        let mut selected: Vec<(f64, [u8; 8])> = Vec::new();
+        while selected.len() < k && !remaining.is_empty() {
+            let mut best = 0;
+            let mut best_value = f64::NEG_INFINITY;
+            for (position, &i) in remaining.iter().enumerate() {
+                let value = (1.0 - diversity) * self.score(candidates[i].0) - diversity * redundancy[i];
+                if value > best_value {
+                    best = position;
+                    best_value = value;
+                }
+            }
+
+            let chosen = remaining.remove(best);
+            selected.push(candidates[chosen]);
+            for &i in remaining.iter() {
+                let similarity = self.score(self.distance(&vectors[i], &vectors[chosen]));
+                redundancy[i] = f64::max(redundancy[i], similarity);
+            }
+        }
+        Ok(selected)
+    }
+
+    /// Stored vector of an element of the graph
+    fn value(&self, db: &RocksDB, id: &[u8; 8]) -> Result<Vec<f64>, Error> {
+        db.get_value(&self.collection_hash, &self.field_hash, id)?
+            .ok_or(Error::MissingValue { document_id: *id })
+    }
+
+    /// Neighbor list of an element of the graph on `layer`
+    fn neighbors(&self, db: &RocksDB, layer: u8, id: &[u8; 8]) -> Result<SortedList<f64, [u8; 8]>, Error> {
+        db.get_neighbors(&self.collection_hash, &self.graph_hash, layer, id)?
+            .ok_or(Error::MissingNeighbors { layer, document_id: *id })
+    }
+
+    /// Error reporting the metadata of the index, which contradicts itself
+    fn inconsistent(&self) -> Error {
+        Error::InconsistentMetadata {
+            n_layers: self.n_layers,
+            n_elements: self.n_elements,
+            entry_point: self.entry_point,
+        }
     }
 
-    pub fn search_level(&self, db: &RocksDB, vector: &Vec<f64>, level_idx: u8, entry_point: &[u8; 8]) -> SortedList<f64, [u8; 8]> {
+    /// Greedy search through the upper layers for the entry point of the bottom layer. Only called on a non-empty index.
+    fn search_entry_point(&self, db: &RocksDB, vector: &Vec<f64>) -> Result<[u8; 8], Error> {
+        let mut entry_point_id = self.entry_point.ok_or_else(|| self.inconsistent())?;
+        for level_idx in (1..self.n_layers).rev() {
+            entry_point_id = self.search_level(db, vector, level_idx, &entry_point_id)?.first().1;
+        }
+        Ok(entry_point_id)
+    }
+
+    pub fn search_level(&self, db: &RocksDB, vector: &Vec<f64>, level_idx: u8, entry_point: &[u8; 8]) -> Result<SortedList<f64, [u8; 8]>, Error> {
+        self.search_level_ef(db, vector, level_idx, entry_point, self.k)
+    }
+
+    /// Search within a single layer keeping the `ef` nearest elements found
+    pub fn search_level_ef(
+        &self,
+        db: &RocksDB,
+        vector: &Vec<f64>,
+        level_idx: u8,
+        entry_point: &[u8; 8],
+        ef: usize,
+    ) -> Result<SortedList<f64, [u8; 8]>, Error> {
         // let entry_point: Document = db.get_document(&self.collection_hash, &entry_point).unwrap();
-        let entry_point_vector: Vec<f64> = db.get_value(&self.collection_hash, &self.field_hash, &entry_point).unwrap();
+        let entry_point_vector: Vec<f64> = self.value(db, &entry_point)?;
 
-        let distance_to_entry_point: f64 = distance::euclidean(&entry_point_vector, vector);
+        let distance_to_entry_point: f64 = self.distance(&entry_point_vector, vector);
 
         let mut candidates: ReverseSortedList<f64, [u8; 8]> = ReverseSortedList::new();
         candidates.insert((distance_to_entry_point, entry_point.clone()));
@@ -183,19 +320,11 @@ impl Index {
             let nearest_candidate = candidates.pop().unwrap();
             let mut furthest_nearest_neighbor = nearest_neighbors.last();
 
-            if distance::squared_euclidean(
-                &db.get_value(&self.collection_hash, &self.field_hash, &nearest_candidate.1).unwrap(),
-                vector,
-            ) > distance::squared_euclidean(
-                &db.get_value(&self.collection_hash, &self.field_hash, &furthest_nearest_neighbor.1)
-                    .unwrap(),
-                vector,
-            ) {
+            if self.distance(&self.value(db, &nearest_candidate.1)?, vector) > self.distance(&self.value(db, &furthest_nearest_neighbor.1)?, vector) {
                 break;
             }
-            let neighbor_ids: Vec<[u8; 8]> = db
-                .get_neighbors(&self.collection_hash, &self.field_hash, level_idx, &nearest_candidate.1)
-                .unwrap()
+            let neighbor_ids: Vec<[u8; 8]> = self
+                .neighbors(db, level_idx, &nearest_candidate.1)?
                 .get_data()
                 .iter()
                 .map(|d| d.1)
@@ -208,25 +337,25 @@ impl Index {
                 visited.insert(neighbor_id);
                 furthest_nearest_neighbor = nearest_neighbors.last();
 
-                let neighbor_vector = db.get_value(&self.collection_hash, &self.field_hash, &neighbor_id).unwrap();
-                let neighbor_distance = distance::euclidean(vector, &neighbor_vector);
+                let neighbor_vector = self.value(db, &neighbor_id)?;
+                let neighbor_distance = self.distance(vector, &neighbor_vector);
 
-                if neighbor_distance < furthest_nearest_neighbor.0 || nearest_neighbors.len() < self.k {
+                if neighbor_distance < furthest_nearest_neighbor.0 || nearest_neighbors.len() < ef {
                     candidates.insert((neighbor_distance, neighbor_id));
                     nearest_neighbors.insert((neighbor_distance, neighbor_id));
 
-                    if nearest_neighbors.len() > self.k {
+                    if nearest_neighbors.len() > ef {
                         nearest_neighbors.pop();
                     }
                 }
             }
         }
 
-        return nearest_neighbors
+        return Ok(nearest_neighbors
             .to_vec()
             .iter()
             .map(|distance_neighbor_pair| (distance_neighbor_pair.0, distance_neighbor_pair.1)) // TODO: more efficient implementation necessay: Don't call insert all the time!
-            .collect();
+            .collect());
     }
 
     pub fn remove(&mut self, id: &u64) -> Option<u64> {
@@ -239,6 +368,33 @@ impl Index {
 }
 
 impl Index {
+    /// Distance between two vectors in the metric of this index.
+    /// Indices created without a metric use the euclidean distance.
+    pub fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self.distance_metric.as_str() {
+            distance::COSINE => distance::cosine(a, b),
+            _ => distance::euclidean(a, b),
+        }
+    }
+
+    /// Similarity score of a distance in the metric of this index.
+    /// Higher is more similar: cosine similarity for cosine indices and `1 / (1 + d)` otherwise.
+    pub fn score(&self, distance: f64) -> f64 {
+        match self.distance_metric.as_str() {
+            distance::COSINE => 1.0 - distance,
+            _ => 1.0 / (1.0 + distance),
+        }
+    }
+
+    /// Largest distance with a score of at least `min_score`
+    pub fn distance_bound(&self, min_score: f64) -> f64 {
+        match self.distance_metric.as_str() {
+            distance::COSINE => 1.0 - min_score,
+            _ if min_score <= 0.0 => f64::INFINITY,
+            _ => 1.0 / min_score - 1.0,
+        }
+    }
+
     pub fn get_entry_point(&self) -> Option<[u8; 8]> {
         self.entry_point
     }
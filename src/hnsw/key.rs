@@ -6,6 +6,33 @@ pub const VALUE: u8 = 'v' as u8;
 pub const INDEX: u8 = 'i' as u8;
 pub const NEIGHBORS: u8 = 'n' as u8;
 pub const REVERSE_NEIGHBORS: u8 = 'r' as u8;
+pub const TEXT_INDEX: u8 = 't' as u8;
+pub const POSTINGS: u8 = 'q' as u8; // field_id holds the hash of the field and term, see `db::term_id`
+pub const LEGACY_POSTINGS: u8 = 'p' as u8; // earlier versions, all postings of a term in one value, document_id holds the hash of the term
+pub const FIELD_LENGTH: u8 = 'l' as u8;
+pub const API_KEY: u8 = 'k' as u8; // document_id holds the hash of the key id
+pub const RUN_STATE: u8 = 's' as u8; // single key, whether the server is running or was shut down cleanly
+pub const JOB: u8 = 'j' as u8; // document_id holds the hash of the job id
+
+/// Name of a key type for diagnostics
+pub fn type_name(key_type: u8) -> Option<&'static str> {
+    match key_type {
+        COLLECTION => Some("collection"),
+        DOCUMENT => Some("document"),
+        VALUE => Some("value"),
+        INDEX => Some("index"),
+        NEIGHBORS => Some("neighbors"),
+        REVERSE_NEIGHBORS => Some("reverse_neighbors"),
+        TEXT_INDEX => Some("text_index"),
+        POSTINGS => Some("postings"),
+        LEGACY_POSTINGS => Some("legacy_postings"),
+        FIELD_LENGTH => Some("field_length"),
+        API_KEY => Some("api_key"),
+        RUN_STATE => Some("run_state"),
+        JOB => Some("job"),
+        _ => None,
+    }
+}
 
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct Key([u8; 26]);
@@ -4,10 +4,65 @@ use serde::{Deserialize, Serialize};
 
 use serde_json::Value;
 
+use crate::db;
+use crate::utils;
+
+use std::fmt;
+
 pub mod builder;
 pub mod document;
 pub mod index;
 pub mod key;
+pub mod verify;
+
+#[derive(Debug)]
+pub enum Error {
+    Storage(db::Error),
+    /// Element of the graph whose vector is not stored
+    MissingValue {
+        document_id: [u8; 8],
+    },
+    /// Element of the graph without a neighbor list on a layer it was found on
+    MissingNeighbors {
+        layer: u8,
+        document_id: [u8; 8],
+    },
+    /// Metadata contradicting itself, like elements without an entry point left by a crash
+    InconsistentMetadata {
+        n_layers: u8,
+        n_elements: u64,
+        entry_point: Option<[u8; 8]>,
+    },
+}
+
+impl From<db::Error> for Error {
+    fn from(error: db::Error) -> Self {
+        Error::Storage(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Storage(error) => write!(f, "{}", error),
+            Error::MissingValue { document_id } => write!(f, "missing vector of element {}", utils::to_hex(document_id)),
+            Error::MissingNeighbors { layer, document_id } => {
+                write!(f, "missing neighbors of element {} on layer {}", utils::to_hex(document_id), layer)
+            }
+            Error::InconsistentMetadata {
+                n_layers,
+                n_elements,
+                entry_point,
+            } => write!(
+                f,
+                "inconsistent metadata: {} elements in {} layers with entry point {}",
+                n_elements,
+                n_layers,
+                entry_point.map(|id| utils::to_hex(&id)).unwrap_or_else(|| "none".to_owned())
+            ),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Document {
@@ -17,7 +72,9 @@ pub struct Document {
     pub data: Value,
 }
 
-#[derive(Debug)]
+/// Metadata of an HNSW graph, the graph itself is stored in the database.
+/// Cloning is cheap and lets searches run without holding the lock of the index store.
+#[derive(Clone, Debug)]
 pub struct Index {
     pub collection_id: String,
     pub field_id: String,
@@ -25,6 +82,8 @@ pub struct Index {
     pub collection_hash: [u8; 8],
     pub field_hash: [u8; 8],
     pub index_hash: [u8; 16],
+    // NEIGHBORS keys use this in place of the field hash, so a rebuild can write a new graph next to the old one
+    pub graph_hash: [u8; 8],
 
     pub distance_metric: String,
     pub buffer_size: usize,
@@ -42,6 +101,12 @@ pub struct Index {
     pub reverse_size: f64,
     pub n_layers: u8, // number of layers
     pub n_elements: u64,
+
+    pub created_at: u64,
+    pub updated_at: u64,
+
+    // Graph being rebuilt, receives every insert until it replaces this one
+    pub shadow: Option<Box<Index>>,
 }
 
 pub struct IndexBuilder {
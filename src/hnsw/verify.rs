@@ -0,0 +1,277 @@
+use crate::db::dbtypes::BinaryConverison;
+use crate::db::{self, RocksDB};
+use crate::hnsw::key::*;
+use crate::hnsw::{Document, Index};
+use crate::sorted_list::SortedList;
+use crate::utils;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Violations beyond this number are only counted
+pub const MAX_VIOLATIONS: usize = 1000;
+
+/// Broken invariant of an index. Documents are named by their id, or by the hex of their hash if the document is gone.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Violation {
+    MissingValue {
+        document_id: String,
+    },
+    MissingNeighbors {
+        document_id: String,
+    },
+    DanglingNeighbor {
+        layer: u8,
+        document_id: String,
+        neighbor_id: String,
+    },
+    DegreeExceeded {
+        layer: u8,
+        document_id: String,
+        degree: usize,
+        max_degree: usize,
+    },
+    InvalidEntryPoint {
+        entry_point: Option<String>,
+        top_layer: Option<u8>,
+    },
+    ElementCountMismatch {
+        n_elements: u64,
+        counted: u64,
+    },
+    Unreachable {
+        document_id: String,
+    },
+    /// Stored value which could not be decoded, with the hex of its key
+    Corrupted {
+        key: String,
+    },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Verification {
+    pub n_nodes: u64,
+    pub n_reachable: u64,
+    pub n_violations: usize,
+    pub violations: Vec<Violation>,
+}
+
+impl Verification {
+    pub fn is_healthy(&self) -> bool {
+        self.n_violations == 0
+    }
+
+    fn report(&mut self, violation: Violation) {
+        self.n_violations += 1;
+        if self.violations.len() < MAX_VIOLATIONS {
+            self.violations.push(violation);
+        }
+    }
+}
+
+impl Index {
+    /// Checks the stored graph against the invariants of HNSW.
+    /// Every neighbor list is loaded into memory, and the caller must prevent concurrent inserts for a consistent result.
+    pub fn verify(&self, db: &RocksDB) -> Result<Verification, db::Error> {
+        let mut verification = Verification::default();
+        let name = |id: &[u8; 8]| match db.get_document(&self.collection_hash, id) {
+            Ok(Some(document)) => document.id_user,
+            _ => utils::to_hex(id),
+        };
+
+        // Neighbor lists by layer and node
+        let prefix = Prefix::new()
+            .prefix_type(NEIGHBORS)
+            .collection(&self.collection_hash)
+            .field(&self.graph_hash)
+            .finish();
+        let mut layers: Vec<HashMap<[u8; 8], Vec<[u8; 8]>>> = Vec::new();
+        for (key, neighbors) in db.get_by_prefix_key_value("neighbors", &prefix)? {
+            let layer = key.get_layer() as usize;
+            if layers.len() <= layer {
+                layers.resize_with(layer + 1, HashMap::new);
+            }
+            // A corrupted list still counts as a node, without edges
+            let neighbors = match SortedList::<f64, [u8; 8]>::from_binary(&neighbors) {
+                Ok(neighbors) => neighbors.get_data().iter().map(|n| n.1).collect(),
+                Err(_) => {
+                    verification.report(Violation::Corrupted {
+                        key: utils::to_hex(&key.to_vec()),
+                    });
+                    Vec::new()
+                }
+            };
+            layers[layer].insert(key.get_document_id(), neighbors);
+        }
+        let empty = HashMap::new();
+        let bottom_layer = layers.first().unwrap_or(&empty);
+        verification.n_nodes = bottom_layer.len() as u64;
+
+        // Every document with the field is part of the graph
+        let prefix = Prefix::new().prefix_type(DOCUMENT).collection(&self.collection_hash).finish();
+        for (key, document) in db.get_by_prefix_key_value("documents", &prefix)? {
+            let document = match Document::from_binary(document) {
+                Some(document) => document,
+                None => {
+                    verification.report(Violation::Corrupted {
+                        key: utils::to_hex(&key.to_vec()),
+                    });
+                    continue;
+                }
+            };
+            if document.data.get(&self.field_id).is_none() {
+                continue;
+            }
+            match db.get_value(&self.collection_hash, &self.field_hash, &document.id_hash) {
+                Ok(Some(_)) => {}
+                Ok(None) => verification.report(Violation::MissingValue {
+                    document_id: document.id_user.clone(),
+                }),
+                Err(db::Error::Corrupted { key, .. }) => verification.report(Violation::Corrupted { key: utils::to_hex(&key) }),
+                Err(error) => return Err(error),
+            }
+            if !bottom_layer.contains_key(&document.id_hash) {
+                verification.report(Violation::MissingNeighbors {
+                    document_id: document.id_user,
+                });
+            }
+        }
+
+        // Edges stay within their layer and respect the degree
+        for (layer_idx, layer) in layers.iter().enumerate() {
+            for (id, neighbors) in layer.iter() {
+                if neighbors.len() > self.k {
+                    verification.report(Violation::DegreeExceeded {
+                        layer: layer_idx as u8,
+                        document_id: name(id),
+                        degree: neighbors.len(),
+                        max_degree: self.k,
+                    });
+                }
+                for neighbor in neighbors.iter().filter(|neighbor| !layer.contains_key(*neighbor)) {
+                    verification.report(Violation::DanglingNeighbor {
+                        layer: layer_idx as u8,
+                        document_id: name(id),
+                        neighbor_id: name(neighbor),
+                    });
+                }
+            }
+        }
+
+        // The entry point is on the top layer, which is the last one of the index
+        let top_layer = layers.len().checked_sub(1);
+        let entry_point_is_valid = match (self.entry_point, top_layer) {
+            (Some(entry_point), Some(top_layer)) => top_layer + 1 == self.n_layers as usize && layers[top_layer].contains_key(&entry_point),
+            (None, None) => true,
+            _ => false,
+        };
+        if !entry_point_is_valid {
+            verification.report(Violation::InvalidEntryPoint {
+                entry_point: self.entry_point.as_ref().map(name),
+                top_layer: top_layer.map(|top_layer| top_layer as u8),
+            });
+        }
+
+        if self.n_elements != verification.n_nodes {
+            verification.report(Violation::ElementCountMismatch {
+                n_elements: self.n_elements,
+                counted: verification.n_nodes,
+            });
+        }
+
+        // Every node of the bottom layer can be found from the entry point
+        let mut visited: HashSet<[u8; 8]> = HashSet::new();
+        let mut queue: VecDeque<[u8; 8]> = VecDeque::new();
+        if let Some(entry_point) = self.entry_point.filter(|entry_point| bottom_layer.contains_key(entry_point)) {
+            visited.insert(entry_point);
+            queue.push_back(entry_point);
+        }
+        while let Some(id) = queue.pop_front() {
+            for neighbor in bottom_layer[&id].iter() {
+                if bottom_layer.contains_key(neighbor) && visited.insert(*neighbor) {
+                    queue.push_back(*neighbor);
+                }
+            }
+        }
+        verification.n_reachable = visited.len() as u64;
+        for id in bottom_layer.keys().filter(|id| !visited.contains(*id)) {
+            verification.report(Violation::Unreachable { document_id: name(id) });
+        }
+
+        Ok(verification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IndexConfig;
+    use crate::hiddb;
+    use crate::hnsw::index::get_index_hash;
+    use crate::index_store;
+
+    use seahash::hash;
+    use serde_json::json;
+
+    #[test]
+    fn test_verify() {
+        let db_name = "./build/verify.rdb";
+        let db_options;
+        {
+            let db = &RocksDB::init(db_name);
+            db_options = db.options.clone();
+
+            let index_store = index_store::init(db).unwrap();
+            hiddb::collection::create(db, "movies").unwrap();
+            hiddb::index::create(db, &index_store, &IndexConfig::default(), "movies", "vector", 2).unwrap();
+            let documents = (0..20).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 0.0]})).collect();
+            hiddb::document::insert(db, &index_store, "movies", &documents).unwrap();
+
+            let verification = hiddb::index::verify(db, &index_store, "movies", "vector").unwrap();
+            assert!(verification.is_healthy(), "{:?}", verification.violations);
+            assert_eq!(verification.n_nodes, 20);
+            assert_eq!(verification.n_reachable, 20);
+
+            // Point "0" to a node that doesn't exist, drop "1" from the graph and corrupt the neighbors of "2"
+            let collection_hash = hash(b"movies").to_be_bytes();
+            let field_hash = hash(b"vector").to_be_bytes();
+            let missing = hash(b"missing").to_be_bytes();
+            let mut neighbors = db
+                .get_neighbors(&collection_hash, &field_hash, 0, &hash(b"0").to_be_bytes())
+                .unwrap()
+                .unwrap();
+            neighbors.insert((0.5, missing));
+            db.insert_neighbors(&collection_hash, &field_hash, 0, &hash(b"0").to_be_bytes(), &neighbors)
+                .unwrap();
+            let mut key = Key::new();
+            key.set_type(NEIGHBORS);
+            key.set_collection_id(&collection_hash);
+            key.set_field_id(&field_hash);
+            key.set_document_id(&hash(b"1").to_be_bytes());
+            db.delete("neighbors", &key).unwrap();
+            key.set_document_id(&hash(b"2").to_be_bytes());
+            db.put("neighbors", &key, &vec![255]).unwrap();
+
+            let index_store = index_store.read().unwrap();
+            let index = index_store[&get_index_hash(collection_hash, field_hash)].lock().unwrap();
+            let verification = index.verify(db).unwrap();
+            assert!(verification
+                .violations
+                .contains(&Violation::MissingNeighbors { document_id: "1".to_owned() }));
+            assert!(verification.violations.contains(&Violation::DanglingNeighbor {
+                layer: 0,
+                document_id: "0".to_owned(),
+                neighbor_id: utils::to_hex(&missing)
+            }));
+            assert!(verification
+                .violations
+                .contains(&Violation::ElementCountMismatch { n_elements: 20, counted: 19 }));
+            assert!(verification.violations.contains(&Violation::Corrupted {
+                key: utils::to_hex(&key.to_vec())
+            }));
+        }
+        RocksDB::destroy(&db_options, db_name);
+    }
+}
@@ -2,28 +2,24 @@ use crate::api::handlers::*;
 use crate::api::types::*;
 
 use crate::api::types::*;
-use crate::db::dbtypes::*;
 use crate::db::*;
-use crate::hnsw::index::get_index_hash;
-use crate::hnsw::key::*;
 use crate::hnsw::Index;
 
 use std::collections::HashMap;
 use std::sync::Mutex;
 use std::sync::RwLock;
 
-pub fn init(db: &RocksDB) -> IndexStore {
-    // Get indices from database
-    let indices = db.get_by_prefix_key_value("default", &Prefix::new().prefix_type(INDEX).finish()).unwrap();
+/// Loads all indices from the database. Corrupted indices are logged and left out.
+pub fn init(db: &RocksDB) -> Result<IndexStore, Error> {
     let mut index_hashmap: HashMap<[u8; 16], Mutex<Index>> = HashMap::new();
-    for (key, index) in indices.iter() {
-        let key = get_index_hash(key.get_collection_id(), key.get_field_id());
-        let index = IndexDB::from_binary(index).to_hnsw_type();
-        match index_hashmap.insert(key, Mutex::new(index)) {
-            // index_hashmap should initially be empty!
-            Some(_) => panic!(),
-            _ => {}
+    for index in db.get_indices()? {
+        let index = index.to_hnsw_type();
+        // Keys are unique, so a second index with the same hash can only come from a corrupted entry
+        if index_hashmap.contains_key(&index.index_hash) {
+            log::error!("Skipping duplicate index '{}' of collection '{}'", index.field_id, index.collection_id);
+            continue;
         }
+        index_hashmap.insert(index.index_hash, Mutex::new(index));
     }
-    return RwLock::new(index_hashmap);
+    Ok(RwLock::new(index_hashmap))
 }
@@ -9,6 +9,10 @@ pub mod reverse_sorted_list;
 pub mod sorted_list;
 
 pub mod api;
+pub mod auth;
+pub mod config;
+pub mod grpc;
 pub mod hiddb;
 pub mod index_store;
 pub mod metrics;
+pub mod workers;
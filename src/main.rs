@@ -1,6 +1,9 @@
 mod api;
+mod auth;
+mod config;
 mod db;
 mod distance;
+mod grpc;
 mod hiddb;
 mod hnsw;
 mod index_store;
@@ -8,77 +11,212 @@ mod metrics;
 mod reverse_sorted_list;
 mod sorted_list;
 mod utils;
+mod workers;
 
 use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
 
+use api::error::json_error;
 use api::handlers::*;
+use api::middleware::AssignRequestId;
 use api::types::*;
 
-use std::env;
+use clap::Parser;
+use futures::channel::oneshot;
+use serde_json::json;
 
+use std::io::{self, Write};
+use std::time::Duration;
+
+use auth::middleware::Auth;
+use config::Config;
 use metrics::middleware::Metrics;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
+    let args = config::Args::parse();
+    let config = config::load(&args).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    if args.print_config {
+        print!("{}", toml::to_string(&config).unwrap());
+        return Ok(());
+    }
 
-    let db_path = match env::var("HIDDB_PATH") {
-        Ok(path) => path,
-        Err(_) => "HIDDBrocksdb".to_owned(),
-    };
+    init_logger(&config);
 
-    let bind: String = match env::var("HIDDB_LISTEN") {
-        Ok(b) => b,
-        Err(_) => "127.0.0.1:8080".to_owned(),
-    };
+    let grpc_addr = config
+        .server
+        .grpc_listen
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid gRPC listen address: {}", e)))?;
 
-    let db = db::RocksDB::init(&db_path);
+    let db = db::RocksDB::try_open(&config.storage.path, &config.storage)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("could not open database: {}", e)))?;
+    let clean_start = hiddb::recovery::start(&db).map_err(|_| io::Error::new(io::ErrorKind::Other, "could not write run state"))?;
 
-    let state = web::Data::new(State {
-        index_store: index_store::init(&db),
-        db,
-    });
+    let state = State::from_config(db, &config).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("could not load indices: {}", e)))?;
+    let state = web::Data::new(state);
+    if !state.auth.is_enabled() {
+        log::warn!("HIDDB_ADMIN_KEY is not set, API key authentication is disabled");
+    }
+
+    if !clean_start {
+        log::warn!("Previous shutdown was not clean, checking consistency");
+        match hiddb::recovery::check(&state.db, &state.index_store, true) {
+            Ok(report) => log::warn!(
+                "Checked {} collections and {} indices, repaired collections {:?} and indices {:?}",
+                report.checked_collections,
+                report.checked_indices,
+                report.stale_collections,
+                report.stale_indices
+            ),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "consistency check failed")),
+        }
+    }
+
+    if config.storage.gc_on_start {
+        match hiddb::gc::collect(&state.db, &state.index_store, false) {
+            Ok(report) => log::info!(
+                "Checked {} keys, deleted {} orphaned keys and reclaimed {} bytes",
+                report.checked_keys,
+                report.orphaned_keys,
+                report.reclaimed_bytes
+            ),
+            // Already logged, the data is left as it is
+            Err(hiddb::gc::Error::CorruptedMetadata) => log::warn!("Skipped garbage collection on start"),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "garbage collection failed")),
+        }
+    }
+
+    let (stop_grpc, grpc_shutdown) = oneshot::channel();
+    let grpc = grpc::spawn(state.clone().into_inner(), grpc_addr, grpc_shutdown)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not serve gRPC on {}: {}", grpc_addr, e)))?;
 
-    HttpServer::new(move || {
+    match hiddb::job::resume(&state.clone().into_inner()) {
+        Ok(0) => {}
+        Ok(n_jobs) => log::info!("Resumed {} background jobs", n_jobs),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "could not read background jobs")),
+    }
+
+    let server_config = config.server.clone();
+    let app_state = state.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(Auth::new())
             .wrap(middleware::Logger::default())
             .wrap(middleware::Compress::default())
             .wrap(Metrics::new())
-            .wrap(Cors::permissive()) // TODO: use default() and make allow stuff
-            .data(web::JsonConfig::default().limit(131072))
-            .app_data(state.clone())
+            .wrap(cors(&server_config.cors_origins))
+            .wrap(AssignRequestId::new())
+            .data(web::JsonConfig::default().limit(server_config.json_limit).error_handler(json_error))
+            .app_data(web::PayloadConfig::new(server_config.payload_limit))
+            .app_data(app_state.clone())
             .route("/health", web::get().to(check_health))
             .route("/metrics", web::get().to(get_metrics))
             //
-            // /collection
-            .route("/collection", web::get().to(get_collections)) // Get information about collections
-            .route("/collection", web::post().to(create_collection)) // Create new collection
-            //
-            // /collection/{collection_id}
-            .route("/collection/{collection_name}", web::get().to(get_collection)) // Get information about collection
-            .route("/collection/{collection_name}", web::delete().to(delete_collection)) // Delete collection
-            //
-            // /collection/{collection_id}/index
-            .route("/collection/{collection_name}/index", web::get().to(get_indices)) // Get information about existing indices
-            .route("/collection/{collection_name}/index", web::post().to(create_index)) // Create new index in {collection_id}
-            //
-            // /collection/{collection_id}/index/{index_id}
-            .route("/collection/{collection_name}/index/{field_name}", web::get().to(get_index)) // Get information about specific index
-            .route("/collection/{collection_name}/index/{field_name}", web::delete().to(delete_index)) // Delete index
+            // /admin/keys
+            .route("/admin/keys", web::get().to(get_api_keys)) // List API keys without their secrets
+            .route("/admin/keys", web::post().to(create_api_key)) // Create API key with per-collection scopes
+            .route("/admin/keys/{key_id}", web::delete().to(revoke_api_key)) // Revoke API key
+            .route("/admin/gc", web::post().to(collect_garbage)) // Delete keys of collections and indices that no longer exist
             //
-            // /collection/{collection_id}/document
-            .route("/collection/{collection_name}/document/search", web::post().to(search_documents)) // Search for document. Supply at least "field" and "document_id"
-            .route("/collection/{collection_name}/document", web::post().to(insert_documents))
-            // Insert documents. The field "field_id" will be indexed by all existing indices.
-            .route("/collection/{collection_name}/document/{document_id}", web::delete().to(not_implemented)) // Remove document. Indices will be updated
-            .route("/collection/{collection_name}/document/{document_id}", web::get().to(get_document_by_id))
-        // Get document by ID
-    })
-    .bind(&bind)?
-    .run()
-    .await
+            // /collection for the default tenant and /ns/{tenant}/collection for all others
+            .configure(collection_routes)
+            .service(web::scope("/ns/{tenant}").configure(collection_routes))
+            .default_service(web::route().to(not_found))
+    });
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+    // Stops accepting connections on SIGINT and SIGTERM and returns once in-flight requests finished
+    server
+        .shutdown_timeout(config.server.shutdown_timeout)
+        .bind(&config.server.listen)?
+        .run()
+        .await?;
+
+    log::info!("Shutting down");
+    let _ = stop_grpc.send(());
+    let _ = grpc.join();
+
+    // Inserts of cancelled requests may still be running. Interrupted jobs are resumed by the next start.
+    state.jobs.stop();
+    if !state.workers.drain(Duration::from_secs(config.server.shutdown_timeout)) {
+        log::warn!("Inserts and jobs did not finish in time, the next start will check consistency");
+        return Ok(());
+    }
+    hiddb::recovery::shutdown(&state.db).map_err(|_| io::Error::new(io::ErrorKind::Other, "could not flush the database"))?;
+    log::info!("Clean shutdown");
+    Ok(())
+}
+
+/// Logs to stderr. RUST_LOG is respected unless `--log-level` is given.
+fn init_logger(config: &Config) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&config.log.level);
+    if config.log.format == "json" {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+fn cors(origins: &[String]) -> Cors {
+    if origins.iter().any(|origin| origin == "*") {
+        return Cors::permissive();
+    }
+    origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allow_any_method()
+        .allow_any_header()
+}
+
+/// Routes of a tenant's collections, mounted at the root for the default tenant and below `/ns/{tenant}`
+fn collection_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        // /collection
+        .route("/collection", web::get().to(get_collections)) // Get information about collections
+        .route("/collection", web::post().to(create_collection)) // Create new collection
+        //
+        // /collection/{collection_id}
+        .route("/collection/{collection_name}", web::get().to(get_collection)) // Get information about collection
+        .route("/collection/{collection_name}", web::delete().to(delete_collection)) // Delete collection
+        //
+        // /collection/{collection_id}/index
+        .route("/collection/{collection_name}/index", web::get().to(get_indices)) // Get information about existing indices
+        .route("/collection/{collection_name}/index", web::post().to(create_index)) // Create new index in {collection_id}
+        //
+        // /collection/{collection_id}/index/{index_id}
+        .route("/collection/{collection_name}/index/{field_name}", web::get().to(get_index)) // Get information about specific index
+        .route("/collection/{collection_name}/index/{field_name}", web::delete().to(delete_index)) // Delete index
+        .route("/collection/{collection_name}/index/{field_name}/verify", web::get().to(verify_index)) // Check the graph for broken invariants
+        .route("/collection/{collection_name}/index/{field_name}/rebuild", web::post().to(rebuild_index)) // Queue a job that builds a new graph and swaps it in
+        //
+        // /collection/{collection_id}/text_index
+        .route("/collection/{collection_name}/text_index", web::get().to(get_text_indices)) // Get information about existing text indices
+        .route("/collection/{collection_name}/text_index", web::post().to(create_text_index)) // Create BM25 index on a string field
+        .route("/collection/{collection_name}/text_index/{field_name}", web::get().to(get_text_index)) // Get information about specific text index
+        .route("/collection/{collection_name}/text_index/{field_name}", web::delete().to(delete_text_index)) // Delete text index
+        //
+        // /collection/{collection_id}/document
+        .route("/collection/{collection_name}/document/search", web::post().to(search_documents)) // Search for document. Supply at least "field" and "document_id"
+        .route("/collection/{collection_name}/document/recommend", web::post().to(recommend_documents)) // Recommend documents from positive and negative example ids
+        .route("/collection/{collection_name}/document", web::post().to(insert_documents))
+        // Insert documents. The field "field_id" will be indexed by all existing indices.
+        .route("/collection/{collection_name}/document/{document_id}", web::delete().to(not_implemented)) // Remove document. Indices will be updated
+        .route("/collection/{collection_name}/document/{document_id}", web::get().to(get_document_by_id)) // Get document by ID
+        //
+        // /jobs
+        .route("/jobs", web::get().to(get_jobs)) // List background jobs with their progress
+        .route("/jobs/{job_id}", web::get().to(get_job)) // Get status and progress of a job
+        .route("/jobs/{job_id}", web::delete().to(cancel_job)); // Cancel a queued or running job
 }
 
 #[cfg(test)]
@@ -6,12 +6,18 @@ use actix_web::dev::{Service, Transform};
 use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error};
 use futures::future::{ok, Ready};
 
+use percent_encoding::percent_decode_str;
+
 use std::time::Instant;
 
 pub struct Metrics;
 
+use crate::hiddb::tenant::{self, DEFAULT_TENANT};
 use crate::metrics;
 
+/// Label of requests whose tenant isn't known, so clients can't create a series per made up tenant
+const UNKNOWN_TENANT: &str = "unknown";
+
 impl Metrics {
     pub fn new() -> Self {
         Self {}
@@ -57,38 +63,62 @@ where
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
         let timer = Instant::now();
-        let route = req.match_pattern().unwrap_or(req.uri().path().to_owned()).to_owned();
+        let pattern = req.match_pattern();
+        let route = pattern.clone().unwrap_or(req.uri().path().to_owned());
         let method = req.method().as_str().to_owned();
+        let tenant = tenant_from_path(pattern.as_deref(), req.path());
 
         let fut = self.service.call(req);
 
         Box::pin(async move {
             let res = fut.await?;
             let status = res.status().as_u16();
+            // Rejected credentials don't show that the tenant exists
+            let tenant = if status == 401 || status == 403 {
+                UNKNOWN_TENANT.to_owned()
+            } else {
+                tenant
+            };
 
             metrics::N_REQUESTS
-                .with_label_values(&[
-                    &method,
-                    &route,
-                    &status.to_string(),
-                    &metrics::INSTANCE_ID,
-                    &metrics::INDEX_ID,
-                    &metrics::ORGANIZATION_ID,
-                ])
+                .with_label_values(&[&method, &route, &status.to_string(), &metrics::INSTANCE_ID, &metrics::INDEX_ID, &tenant])
                 .inc();
 
             metrics::REQUEST_TIME_HISTOGRAM
-                .with_label_values(&[
-                    &method,
-                    &route,
-                    &status.to_string(),
-                    &metrics::INSTANCE_ID,
-                    &metrics::INDEX_ID,
-                    &metrics::ORGANIZATION_ID,
-                ])
+                .with_label_values(&[&method, &route, &status.to_string(), &metrics::INSTANCE_ID, &metrics::INDEX_ID, &tenant])
                 .observe(timer.elapsed().as_secs_f64());
 
             Ok(res)
         })
     }
 }
+
+/// Tenant of `/ns/{tenant}/...` routes, other routes are labeled with the default tenant.
+/// Paths matching no route and invalid tenant names are labeled as unknown.
+fn tenant_from_path(pattern: Option<&str>, path: &str) -> String {
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        _ => return UNKNOWN_TENANT.to_owned(),
+    };
+    if !pattern.starts_with("/ns/{tenant}") {
+        return DEFAULT_TENANT.to_owned();
+    }
+    let tenant = path.trim_start_matches('/').split('/').nth(1).unwrap_or("");
+    match percent_decode_str(tenant).decode_utf8() {
+        Ok(tenant) if tenant::is_valid_name(&tenant) => tenant.into_owned(),
+        _ => UNKNOWN_TENANT.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_from_path() {
+        assert_eq!(tenant_from_path(Some("/collection"), "/collection"), DEFAULT_TENANT);
+        assert_eq!(tenant_from_path(Some("/ns/{tenant}/collection"), "/ns/team_a/collection"), "team_a");
+        assert_eq!(tenant_from_path(Some("/ns/{tenant}/collection"), "/ns/%2A/collection"), UNKNOWN_TENANT);
+        assert_eq!(tenant_from_path(None, "/ns/random/unknown"), UNKNOWN_TENANT);
+    }
+}
@@ -23,13 +23,13 @@ lazy_static! {
 lazy_static! {
     pub static ref N_REQUESTS: IntCounterVec = register_int_counter_vec!(
         opts!("number_requests", "Number of HTTP requests"),
-        &["method", "route", "status", "instance_id", "index_id", "organization_id"]
+        &["method", "route", "status", "instance_id", "index_id", "tenant"]
     )
     .unwrap();
     pub static ref REQUEST_TIME_HISTOGRAM: HistogramVec = register_histogram_vec!(
         "request_time_histogram",
         "Request time histogram : description",
-        &["method", "route", "status", "instance_id", "index_id", "organization_id"],
+        &["method", "route", "status", "instance_id", "index_id", "tenant"],
         DEFAULT_BUCKETS.to_vec()
     )
     .unwrap();
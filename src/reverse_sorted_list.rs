@@ -2,6 +2,8 @@ use std::iter::FromIterator;
 
 use serde::{Deserialize, Serialize};
 
+use crate::sorted_list::total_cmp;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ReverseSortedList<K: PartialOrd, V> {
     data: Vec<(K, V)>,
@@ -33,9 +35,7 @@ where
     }
 
     pub fn insert(&mut self, key_value_pair: (K, V)) {
-        let idx = match self.data.binary_search_by(|entry| {
-            key_value_pair.0.partial_cmp(&entry.0).unwrap() // unwrap for NaN
-        }) {
+        let idx = match self.data.binary_search_by(|entry| total_cmp(&key_value_pair.0, &entry.0)) {
             Ok(idx) => idx,  // insertion next to element with same distance
             Err(idx) => idx, // insertion next to element with different distance
         };
@@ -1,7 +1,17 @@
+use std::cmp::Ordering;
 use std::iter::FromIterator;
 
 use serde::{Deserialize, Serialize};
 
+/// Orders keys which are not comparable, i.e. NaN, after all others, so that lists stay sorted.
+pub fn total_cmp<K: PartialOrd>(a: &K, b: &K) -> Ordering {
+    match a.partial_cmp(b) {
+        Some(ordering) => ordering,
+        #[allow(clippy::eq_op)]
+        None => (a != a).cmp(&(b != b)),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SortedList<K: PartialOrd, V> {
     data: Vec<(K, V)>,
@@ -33,9 +43,7 @@ where
     }
 
     pub fn insert(&mut self, key_value_pair: (K, V)) {
-        let idx = match self.data.binary_search_by(|entry| {
-            entry.0.partial_cmp(&key_value_pair.0).unwrap() // unwrap for NaN
-        }) {
+        let idx = match self.data.binary_search_by(|entry| total_cmp(&entry.0, &key_value_pair.0)) {
             Ok(idx) => idx,  // insertion next to element with same distance
             Err(idx) => idx, // insertion next to element with different distance
         };
@@ -132,4 +140,19 @@ mod tests {
         assert_eq!(sorted_list.len(), 0);
         assert_eq!(sorted_list.pop(), None);
     }
+
+    #[test]
+    fn test_nan() {
+        let mut sorted_list = SortedList::<f64, u64>::new();
+        sorted_list.insert((0.5, 0));
+        sorted_list.insert((f64::NAN, 1));
+        sorted_list.insert((f64::INFINITY, 2));
+        sorted_list.insert((0.1, 3));
+        sorted_list.insert((f64::NAN, 4));
+        sorted_list.insert((0.7, 5));
+
+        assert_eq!(sorted_list.get_data().iter().map(|x| x.1).take(4).collect::<Vec<u64>>(), vec![3, 0, 5, 2]);
+        assert!(sorted_list[4].0.is_nan());
+        assert!(sorted_list[5].0.is_nan());
+    }
 }
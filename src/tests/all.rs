@@ -1,9 +1,5 @@
 use actix_web::{web, App};
 
-use std::sync::RwLock;
-
-use std::collections::HashMap;
-
 use crate::api::handlers::*;
 use crate::api::types::*;
 use crate::db::RocksDB;
@@ -22,10 +18,7 @@ async fn test_index() -> Result<(), Error> {
     {
         let db = RocksDB::init("./build/test_index.rdb");
         db_options = db.options.clone();
-        let state = web::Data::new(State {
-            index_store: RwLock::new(HashMap::new()),
-            db,
-        });
+        let state = web::Data::new(State::new(db).unwrap());
         let mut app = test::init_service(
             App::new()
                 .data(web::JsonConfig::default().limit(1024 * 1024))
@@ -204,6 +197,25 @@ async fn test_index() -> Result<(), Error> {
         let index_info_json: IndexResponse = serde_json::from_str(std::str::from_utf8(response_body).unwrap()).unwrap();
         assert_eq!(index_info_json.n_documents, 100);
 
+        // Get statistics of "collection1", sizes are taken from the SST files
+        state.db.flush().unwrap();
+        let req = test::TestRequest::get().uri("/collection/collection1").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+        let collection_info_json: CollectionResponse = serde_json::from_str(std::str::from_utf8(response_body).unwrap()).unwrap();
+        assert_eq!(collection_info_json.n_documents, 100);
+        assert_eq!(collection_info_json.n_indices, Some(2));
+        assert!(collection_info_json.created_at <= collection_info_json.updated_at);
+        let indices = collection_info_json.indices.unwrap();
+        let index_vector = indices.iter().find(|i| i.field_name == "vector").unwrap();
+        assert_eq!(index_vector.n_documents, 100);
+        assert!(index_vector.approximate_bytes > 0);
+        assert!(collection_info_json.approximate_bytes.unwrap() > index_vector.approximate_bytes);
+
         // Insert to index "vector" and "vector2"
         for id in 0..10 {
             let vector: Vec<f64> = (0..50).map(|idx| (idx * id) as f64).collect();
@@ -278,6 +290,29 @@ async fn test_index() -> Result<(), Error> {
         let index_info_json: SearchANNResponse = serde_json::from_str(std::str::from_utf8(response_body).unwrap()).unwrap();
         assert_eq!(index_info_json.data[0].len(), 10);
 
+        // Multi-field search with score fusion
+        let req = test::TestRequest::post()
+            .uri("/collection/collection1/document/search")
+            .set_json(&serde_json::json!({
+                "fields": [
+                    {"field_name": "vector", "vector": (0..50).map(|idx| (idx * 3) as f64).collect::<Vec<f64>>(), "weight": 1.0},
+                    {"field_name": "vector2", "vector": (0..5).map(|idx| 2.0 * (idx as f64) * 3f64.sqrt()).collect::<Vec<f64>>(), "weight": 0.5},
+                ],
+                "fusion": "rrf",
+                "max_neighbors": 5,
+            }))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let response_body = match resp.response().body().as_ref() {
+            Some(actix_web::body::Body::Bytes(bytes)) => bytes,
+            _ => panic!("Response error"),
+        };
+        let index_info_json: SearchANNResponse = serde_json::from_str(std::str::from_utf8(response_body).unwrap()).unwrap();
+        assert_eq!(index_info_json.data.len(), 1);
+        assert_eq!(index_info_json.data[0].len(), 5);
+        assert_eq!(index_info_json.data[0][0], "multiple_vector_doc_3");
+
         let req = test::TestRequest::get()
             .uri("/collection/collection1/document/multiple_vector_doc_0")
             .to_request();
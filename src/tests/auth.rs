@@ -0,0 +1,126 @@
+use actix_web::{web, App};
+
+use crate::api::handlers::*;
+use crate::api::types::*;
+use crate::auth::middleware::Auth;
+use crate::auth::Authenticator;
+use crate::db::dbtypes::{Permission, Scope};
+use crate::db::RocksDB;
+
+use actix_web::{http, test, Error};
+
+use actix_web::dev::Service;
+
+#[actix_rt::test]
+async fn test_api_keys() -> Result<(), Error> {
+    let db_name = "./build/auth_test.rdb";
+    let db_options;
+    {
+        let db = RocksDB::init(db_name);
+        db_options = db.options.clone();
+        let mut state = State::new(db).unwrap();
+        state.auth = Authenticator::new(Some("admin-secret"));
+        let state = web::Data::new(state);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Auth::new())
+                .app_data(state.clone())
+                .route("/health", web::get().to(check_health))
+                .route("/admin/keys", web::get().to(get_api_keys))
+                .route("/admin/keys", web::post().to(create_api_key))
+                .route("/admin/keys/{key_id}", web::delete().to(revoke_api_key))
+                .route("/collection", web::post().to(create_collection))
+                .route("/collection/{collection_name}", web::get().to(get_collection))
+                .route("/collection/{collection_name}", web::delete().to(delete_collection)),
+        )
+        .await;
+
+        // Public routes don't need a key
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CollectionRequest {
+                collection_name: "movies".to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await;
+        assert_eq!(resp.err().unwrap().as_response_error().status_code(), http::StatusCode::UNAUTHORIZED);
+
+        // The bootstrap admin key can create collections and keys
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .header("Authorization", "Bearer admin-secret")
+            .set_json(&CollectionRequest {
+                collection_name: "movies".to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/admin/keys")
+            .header("Authorization", "Bearer admin-secret")
+            .set_json(&CreateApiKeyRequest {
+                name: "reader".to_owned(),
+                scopes: vec![Scope {
+                    tenant: "default".to_owned(),
+                    collection: "movies".to_owned(),
+                    permission: Permission::Read,
+                }],
+            })
+            .to_request();
+        let api_key: ApiKeyResponse = test::read_response_json(&mut app, req).await;
+        let authorization = format!("Bearer {}", api_key.key.unwrap());
+
+        // Read access to its collection only
+        let req = test::TestRequest::get()
+            .uri("/collection/movies")
+            .header("Authorization", authorization.as_str())
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::delete()
+            .uri("/collection/movies")
+            .header("Authorization", authorization.as_str())
+            .to_request();
+        let resp = app.call(req).await;
+        assert_eq!(resp.err().unwrap().as_response_error().status_code(), http::StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/keys")
+            .header("Authorization", authorization.as_str())
+            .to_request();
+        let resp = app.call(req).await;
+        assert_eq!(resp.err().unwrap().as_response_error().status_code(), http::StatusCode::FORBIDDEN);
+
+        // Secrets are never listed
+        let req = test::TestRequest::get()
+            .uri("/admin/keys")
+            .header("Authorization", "Bearer admin-secret")
+            .to_request();
+        let api_keys: ApiKeysResponse = test::read_response_json(&mut app, req).await;
+        assert_eq!(api_keys.keys.len(), 1);
+        assert_eq!(api_keys.keys[0].key, None);
+
+        // Revoked keys are rejected
+        let req = test::TestRequest::delete()
+            .uri(&format!("/admin/keys/{}", api_key.key_id))
+            .header("Authorization", "Bearer admin-secret")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/movies")
+            .header("Authorization", authorization.as_str())
+            .to_request();
+        let resp = app.call(req).await;
+        assert_eq!(resp.err().unwrap().as_response_error().status_code(), http::StatusCode::UNAUTHORIZED);
+    }
+    RocksDB::destroy(&db_options, db_name);
+    Ok(())
+}
@@ -1,14 +1,13 @@
 use actix_web::{web, App};
 
-use std::sync::RwLock;
-
-use std::collections::HashMap;
-
+use crate::api::error::ErrorCode;
 use crate::api::handlers::*;
 use crate::api::types::*;
 use crate::db::RocksDB;
 
-use actix_web::{test, Error};
+use actix_web::dev::Service;
+use actix_web::{http, test, Error};
+use serde_json::json;
 
 use crate::tests::helpers;
 
@@ -19,10 +18,7 @@ pub async fn test_collection() -> Result<(), Error> {
     let result = {
         let db = RocksDB::init(db_name);
         db_options = db.options.clone();
-        let state = web::Data::new(State {
-            index_store: RwLock::new(HashMap::new()),
-            db,
-        });
+        let state = web::Data::new(State::new(db).unwrap());
         let mut app = test::init_service(
             App::new()
                 .data(web::JsonConfig::default().limit(1024 * 1024))
@@ -48,9 +44,27 @@ pub async fn test_collection() -> Result<(), Error> {
         assert_eq!(collections_response.collections.len(), 1);
         assert_eq!(collections_response.collections[0].collection_name, collection_name);
 
-        // TODO: Create -> should not work
-        // let collection_response: CollectionResponse = helpers::create_collection(&mut app, collection_name).await;
-        // assert_eq!(collection_response.collection_name, collection_name);
+        // Create again -> conflict with a machine-readable error
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .header("x-request-id", "req-42")
+            .set_json(&CollectionRequest {
+                collection_name: collection_name.to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::CONFLICT);
+        let error: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(error.code, ErrorCode::CollectionAlreadyExists);
+        assert_eq!(error.details, json!({ "collection": collection_name }));
+        assert_eq!(error.request_id, "req-42");
+
+        let req = test::TestRequest::get().uri("/collection/unknown").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        let error: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(error.code, ErrorCode::CollectionNotFound);
+        assert!(!error.request_id.is_empty());
     };
     RocksDB::destroy(&db_options, db_name);
     Ok(())
@@ -1 +1,171 @@
+use actix_web::{web, App};
 
+use crate::api::handlers::*;
+use crate::api::types::*;
+use crate::db::dbtypes::JobStatus;
+use crate::db::RocksDB;
+
+use actix_web::{http, test, Error};
+use serde_json::json;
+
+use actix_web::dev::Service;
+
+#[actix_rt::test]
+async fn test_verify_index() -> Result<(), Error> {
+    let db_name = "./build/index_test.rdb";
+    let db_options;
+    {
+        let db = RocksDB::init(db_name);
+        db_options = db.options.clone();
+        let state = web::Data::new(State::new(db).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/collection", web::post().to(create_collection))
+                .route("/collection/{collection_name}/index", web::post().to(create_index))
+                .route("/collection/{collection_name}/index/{field_name}/verify", web::get().to(verify_index))
+                .route("/collection/{collection_name}/document", web::post().to(insert_documents)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CollectionRequest {
+                collection_name: "movies".to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/movies/index")
+            .set_json(&CreateIndexRequest {
+                field_name: "vector".to_owned(),
+                dimension: 2,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let documents: Vec<_> = (0..10).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 1.0]})).collect();
+        let req = test::TestRequest::post()
+            .uri("/collection/movies/document")
+            .set_json(&json!({ "documents": documents }))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/collection/movies/index/vector/verify").to_request();
+        let verification: IndexVerificationResponse = test::read_response_json(&mut app, req).await;
+        assert!(verification.healthy);
+        assert_eq!(verification.n_nodes, 10);
+        assert_eq!(verification.n_reachable, 10);
+        assert!(verification.violations.is_empty());
+
+        let req = test::TestRequest::get().uri("/collection/movies/index/title/verify").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+    }
+    RocksDB::destroy(&db_options, db_name);
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn test_rebuild_index_job() -> Result<(), Error> {
+    let db_name = "./build/rebuild_job_test.rdb";
+    let db_options;
+    {
+        let db = RocksDB::init(db_name);
+        db_options = db.options.clone();
+        let state = web::Data::new(State::new(db).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/collection", web::post().to(create_collection))
+                .route("/collection/{collection_name}/index", web::post().to(create_index))
+                .route("/collection/{collection_name}/index/{field_name}/rebuild", web::post().to(rebuild_index))
+                .route("/collection/{collection_name}/document", web::post().to(insert_documents))
+                .route("/jobs", web::get().to(get_jobs))
+                .route("/jobs/{job_id}", web::get().to(get_job))
+                .route("/jobs/{job_id}", web::delete().to(cancel_job)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CollectionRequest {
+                collection_name: "movies".to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/movies/index")
+            .set_json(&CreateIndexRequest {
+                field_name: "vector".to_owned(),
+                dimension: 2,
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let documents: Vec<_> = (0..10).map(|i| json!({"id": i.to_string(), "vector": [i as f64, 1.0]})).collect();
+        let req = test::TestRequest::post()
+            .uri("/collection/movies/document")
+            .set_json(&json!({ "documents": documents }))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/movies/index/vector/rebuild")
+            .set_json(&json!({"k": 0}))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post().uri("/collection/movies/index/title/rebuild").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/movies/index/vector/rebuild")
+            .set_json(&json!({"k": 4}))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::ACCEPTED);
+        let job: JobResponse = test::read_body_json(resp).await;
+        assert_eq!(job.kind, "rebuild_index");
+        assert_eq!(job.collection_name, "movies");
+
+        // The job runs in the background
+        let mut status = job.status;
+        for _ in 0..100 {
+            let req = test::TestRequest::get().uri(&format!("/jobs/{}", job.job_id)).to_request();
+            let job: JobResponse = test::read_response_json(&mut app, req).await;
+            status = job.status;
+            if status == JobStatus::Succeeded {
+                assert_eq!((job.progress, job.total), (10, 10));
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(status, JobStatus::Succeeded);
+
+        let req = test::TestRequest::get().uri("/jobs").to_request();
+        let jobs: JobsResponse = test::read_response_json(&mut app, req).await;
+        assert_eq!(jobs.jobs.len(), 1);
+
+        let req = test::TestRequest::delete().uri(&format!("/jobs/{}", job.job_id)).to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::CONFLICT);
+
+        let req = test::TestRequest::get().uri("/jobs/0123456789abcdef").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        assert!(state.workers.drain(std::time::Duration::from_secs(5)));
+    }
+    RocksDB::destroy(&db_options, db_name);
+    Ok(())
+}
@@ -1,3 +1,6 @@
 mod all;
+mod auth;
 mod collection;
 mod helpers;
+mod index;
+mod tenant;
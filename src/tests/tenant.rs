@@ -0,0 +1,90 @@
+use actix_web::{web, App};
+
+use crate::api::handlers::*;
+use crate::api::types::*;
+use crate::db::RocksDB;
+use crate::hiddb::tenant::Quota;
+
+use actix_web::{http, test, Error};
+
+use actix_web::dev::Service;
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/collection", web::get().to(get_collections))
+        .route("/collection", web::post().to(create_collection))
+        .route("/collection/{collection_name}", web::get().to(get_collection))
+        .route("/collection/{collection_name}", web::delete().to(delete_collection));
+}
+
+#[actix_rt::test]
+async fn test_tenants() -> Result<(), Error> {
+    let db_name = "./build/tenant_test.rdb";
+    let db_options;
+    {
+        let db = RocksDB::init(db_name);
+        db_options = db.options.clone();
+        let mut state = State::new(db).unwrap();
+        state.quota = Quota {
+            max_collections: Some(1),
+            max_documents: None,
+        };
+        let state = web::Data::new(state);
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(routes)
+                .service(web::scope("/ns/{tenant}").configure(routes)),
+        )
+        .await;
+
+        // The same name can be used by every tenant
+        for uri in ["/collection", "/ns/team_a/collection", "/ns/team_b/collection"].iter() {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .set_json(&CollectionRequest {
+                    collection_name: "movies".to_owned(),
+                })
+                .to_request();
+            let collection: CollectionResponse = test::read_response_json(&mut app, req).await;
+            assert_eq!(collection.collection_name, "movies");
+        }
+
+        let req = test::TestRequest::get().uri("/ns/team_a/collection").to_request();
+        let collections: CollectionsResponse = test::read_response_json(&mut app, req).await;
+        assert_eq!(collections.collections.len(), 1);
+        let req = test::TestRequest::get().uri("/collection").to_request();
+        let collections: CollectionsResponse = test::read_response_json(&mut app, req).await;
+        assert_eq!(collections.collections.len(), 1);
+
+        // Deleting in one tenant leaves the others untouched
+        let req = test::TestRequest::delete().uri("/ns/team_a/collection/movies").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let req = test::TestRequest::get().uri("/ns/team_a/collection/movies").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        let req = test::TestRequest::get().uri("/ns/team_b/collection/movies").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        // Quotas count per tenant
+        let req = test::TestRequest::post()
+            .uri("/ns/team_b/collection")
+            .set_json(&CollectionRequest {
+                collection_name: "books".to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri("/ns/team_a/collection")
+            .set_json(&CollectionRequest {
+                collection_name: "books".to_owned(),
+            })
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+    RocksDB::destroy(&db_options, db_name);
+    Ok(())
+}
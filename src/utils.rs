@@ -1,5 +1,7 @@
 use num_traits::Float;
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 const NUMERICAL_ACCURACY: f64 = 1e-8;
 
 pub fn float_vector_comp<T: Float>(arr_a: &[T], arr_b: &[T]) -> bool {
@@ -11,6 +13,26 @@ pub fn float_vector_comp<T: Float>(arr_a: &[T], arr_b: &[T]) -> bool {
     true
 }
 
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Inverse of `to_hex`, `None` for odd lengths and non-hex characters
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Milliseconds since the unix epoch
+pub fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
 mod tests {
     #[test]
     fn test_float_vector_comp() {
@@ -19,4 +41,14 @@ mod tests {
         assert!(float_vector_comp(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]));
         assert!(!float_vector_comp(&[1.0, 2.0, 3.0], &[1.0, 2.0, 2.9999]));
     }
+
+    #[test]
+    fn test_hex() {
+        use super::{from_hex, to_hex};
+
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fFF"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("0f0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
 }
@@ -0,0 +1,230 @@
+use futures::channel::oneshot;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use std::cell::RefCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Cancellation>> = RefCell::new(None);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Cancelled,
+    Panicked,
+}
+
+/// Flag shared between a request and the job doing its work
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Cancellation of the job running on the current thread.
+    /// Outside of a worker job this is never cancelled.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.borrow().clone().unwrap_or_default())
+    }
+}
+
+/// Cancels the job once the future waiting for it is dropped, e.g. because the client disconnected
+struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Counts an ingest job from submission until it finished or was skipped
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Blocking thread pools for storage and graph work, so the actix event loop is never blocked.
+/// Ingest and queries use separate pools so bulk inserts can't starve searches.
+/// Background jobs run one at a time on a pool of their own.
+/// Parallel iterators inside a job run on the pool of the job, so batched searches share the query threads.
+pub struct Workers {
+    ingest: ThreadPool,
+    query: ThreadPool,
+    background: ThreadPool,
+    pending_ingest: Arc<AtomicUsize>,
+}
+
+impl Workers {
+    pub fn new(ingest_threads: usize, query_threads: usize) -> Self {
+        Self {
+            ingest: build_pool("hiddb-ingest", ingest_threads),
+            query: build_pool("hiddb-query", query_threads),
+            background: build_pool("hiddb-background", 1),
+            pending_ingest: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Defaults to half of the cores for ingest and all cores for queries
+    pub fn with_defaults(ingest_threads: Option<usize>, query_threads: Option<usize>) -> Self {
+        let cpus = num_cpus::get();
+        Self::new(
+            ingest_threads.unwrap_or(std::cmp::max(1, cpus / 2)),
+            query_threads.unwrap_or(cpus),
+        )
+    }
+
+    pub async fn ingest<F, T>(&self, work: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let pending = Pending::new(&self.pending_ingest);
+        run(&self.ingest, move || {
+            let _pending = pending;
+            work()
+        })
+        .await
+    }
+
+    pub async fn query<F, T>(&self, work: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        run(&self.query, work).await
+    }
+
+    /// Queues `work` on the background pool without waiting for it. It is skipped if `cancellation` is cancelled before it starts.
+    /// Counts as an ingest job for `drain`.
+    pub fn spawn<F>(&self, cancellation: Cancellation, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let pending = Pending::new(&self.pending_ingest);
+        self.background.spawn(move || {
+            let _pending = pending;
+            if cancellation.is_cancelled() {
+                return;
+            }
+            CURRENT.with(|current| *current.borrow_mut() = Some(cancellation));
+            let _ = catch_unwind(AssertUnwindSafe(work));
+            CURRENT.with(|current| *current.borrow_mut() = None);
+        });
+    }
+
+    /// Blocks until all submitted ingest jobs have finished, or at most `timeout`.
+    /// Jobs keep running after their request was cancelled, so this is needed before the database is closed.
+    /// Returns whether the ingest pool is idle.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.pending_ingest.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+fn build_pool(name: &'static str, threads: usize) -> ThreadPool {
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |i| format!("{}-{}", name, i))
+        .build()
+        .unwrap()
+}
+
+/// Runs `work` on `pool` and waits for its result without blocking the caller.
+/// Jobs that are cancelled while queued are skipped; running jobs can poll `Cancellation::current()`.
+async fn run<F, T>(pool: &ThreadPool, work: F) -> Result<T, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let cancellation = Cancellation::new();
+    let _guard = CancelOnDrop(cancellation.clone());
+
+    pool.spawn(move || {
+        if cancellation.is_cancelled() {
+            return;
+        }
+        CURRENT.with(|current| *current.borrow_mut() = Some(cancellation));
+        let result = catch_unwind(AssertUnwindSafe(work)).map_err(|_| Error::Panicked);
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        let _ = sender.send(result);
+    });
+
+    receiver.await.unwrap_or(Err(Error::Cancelled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    #[actix_rt::test]
+    async fn test_workers() {
+        let workers = Workers::new(1, 2);
+        assert_eq!(workers.query(|| 1 + 1).await, Ok(2));
+        assert_eq!(workers.ingest(|| Cancellation::current().is_cancelled()).await, Ok(false));
+        assert_eq!(workers.query(|| panic!("worker panicked")).await, Err::<(), Error>(Error::Panicked));
+        assert!(!Cancellation::current().is_cancelled());
+
+        // Parallel iterators of a query stay on the query pool
+        let threads = workers
+            .query(|| {
+                (0..8)
+                    .into_par_iter()
+                    .map(|_| thread::current().name().map(str::to_owned))
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap();
+        assert!(threads.iter().all(|name| name.as_deref().unwrap_or("").starts_with("hiddb-query")));
+
+        let mut job = Box::pin(workers.ingest(|| thread::sleep(Duration::from_millis(200))));
+        assert!(futures::poll!(job.as_mut()).is_pending());
+        assert!(!workers.drain(Duration::from_millis(10)));
+        assert_eq!(job.await, Ok(()));
+        assert!(workers.drain(Duration::from_secs(5)));
+
+        let (sender, receiver) = oneshot::channel();
+        workers.spawn(Cancellation::new(), move || {
+            let _ = sender.send(Cancellation::current().is_cancelled());
+        });
+        assert_eq!(receiver.await, Ok(false));
+        let started = Arc::new(AtomicBool::new(false));
+        let cancellation = Cancellation::new();
+        cancellation.cancel();
+        let flag = started.clone();
+        workers.spawn(cancellation, move || flag.store(true, Ordering::SeqCst));
+        assert!(workers.drain(Duration::from_secs(5)));
+        assert!(!started.load(Ordering::SeqCst));
+    }
+}